@@ -0,0 +1,844 @@
+use std::collections::{BTreeMap, HashMap};
+
+use actix_web::{get, post};
+use actix_web::{web, HttpResponse, Responder};
+
+use chrono::{DateTime, FixedOffset, TimeZone, Utc};
+
+use firestore_grpc_cloudrun::{
+    precondition::ConditionType, value::ValueType, write::Operation, CommitRequest, Document,
+    DocumentMask, GetDocumentRequest, ListDocumentsRequest, Precondition, Value, Write,
+};
+
+use futures::lock::Mutex;
+
+use serde::Deserialize;
+
+use tonic::{Code, Status};
+
+use crate::auth_middleware::{Identity, Role, ROLE_ERROR};
+use crate::handlers::{balance_field, balance_update, credit, log_store_error};
+use crate::logging::RequestId;
+use crate::models::Error;
+use crate::models::ErrorMessage;
+use crate::models::{Adjustment, Balance, TransactionRecord};
+use crate::money::Money;
+use crate::outbox::{self, Event};
+use crate::MyData;
+
+const USER_ERROR: ErrorMessage = ErrorMessage {
+    error: Error {
+        code: "INVALID_USER",
+        message: "Invalid user",
+    },
+};
+
+const TRANSACTION_ERROR: ErrorMessage = ErrorMessage {
+    error: Error {
+        code: "INVALID_TRANSACTION",
+        message: "Invalid transaction",
+    },
+};
+
+const REASON_ERROR: ErrorMessage = ErrorMessage {
+    error: Error {
+        code: "INVALID_PARAMETER",
+        message: "Reason is required and the idempotency key 1 to 128 of [A-Za-z0-9_-]",
+    },
+};
+
+const KEY_REUSED_ERROR: ErrorMessage = ErrorMessage {
+    error: Error {
+        code: "IDEMPOTENCY_KEY_REUSED",
+        message: "Idempotency key already used for a different adjustment",
+    },
+};
+
+const CONFLICT_ERROR: ErrorMessage = ErrorMessage {
+    error: Error {
+        code: "BALANCE_CHANGED",
+        message: "The balance changed during the adjustment, retry with the same key",
+    },
+};
+
+const BALANCE_ERROR: ErrorMessage = ErrorMessage {
+    error: Error {
+        code: "INSUFFICIENT_CREDITS",
+        message: "Adjustment would make the balance negative",
+    },
+};
+
+const CURRENCY_ERROR: ErrorMessage = ErrorMessage {
+    error: Error {
+        code: "INVALID_PARAMETER",
+        message: "Unknown currency",
+    },
+};
+
+//Game servers only spend, they never read other users' history
+const READERS: [Role; 3] = [Role::ReadOnly, Role::Support, Role::Finance];
+
+//Adjustments share the transact collection, Xsolla transaction IDs are numeric.
+const ADJUSTMENT_PREFIX: &str = "adj_";
+
+const DATE_ERROR: ErrorMessage = ErrorMessage {
+    error: Error {
+        code: "INVALID_PARAMETER",
+        message: "Dates must be RFC 3339",
+    },
+};
+
+pub(crate) fn to_date(timestamp: &prost_types::Timestamp) -> DateTime<Utc> {
+    Utc.timestamp(timestamp.seconds, timestamp.nanos as u32)
+}
+
+pub(crate) fn integer(doc: &Document, field: &str) -> Option<i64> {
+    match doc.fields.get(field).and_then(|v| v.value_type.as_ref()) {
+        Some(ValueType::IntegerValue(value)) => Some(*value),
+        _ => None,
+    }
+}
+
+pub(crate) fn string(doc: &Document, field: &str) -> Option<String> {
+    match doc.fields.get(field).and_then(|v| v.value_type.as_ref()) {
+        Some(ValueType::StringValue(value)) => Some(value.clone()),
+        _ => None,
+    }
+}
+
+//Internal currencies other than Credits, see Config::virtual_currency_skus and MyData::sku_currencies
+pub(crate) fn balances(doc: &Document) -> BTreeMap<String, i64> {
+    let fields = match doc
+        .fields
+        .get("Balances")
+        .and_then(|v| v.value_type.as_ref())
+    {
+        Some(ValueType::MapValue(map)) => &map.fields,
+        _ => return BTreeMap::new(),
+    };
+
+    fields
+        .iter()
+        .filter_map(|(currency, value)| match value.value_type {
+            Some(ValueType::IntegerValue(amount)) => Some((currency.clone(), amount)),
+            _ => None,
+        })
+        .collect()
+}
+
+//Decimal string, whole units as an integer in documents stored before Money
+pub(crate) fn cost(doc: &Document) -> Option<Money> {
+    let currency = string(doc, "Currency")?;
+
+    match doc.fields.get("Cost").and_then(|v| v.value_type.as_ref()) {
+        Some(ValueType::StringValue(cost)) => Money::parse(cost, &currency).ok(),
+        Some(ValueType::IntegerValue(cost)) => Money::parse(&cost.to_string(), &currency).ok(),
+        _ => None,
+    }
+}
+
+pub(crate) fn timestamp(doc: &Document, field: &str) -> Option<DateTime<Utc>> {
+    match doc.fields.get(field).and_then(|v| v.value_type.as_ref()) {
+        Some(ValueType::TimestampValue(value)) => Some(to_date(value)),
+        _ => None,
+    }
+}
+
+pub(crate) fn record(user_id: &str, doc: &Document) -> TransactionRecord {
+    let refund_date = timestamp(doc, "RefundDate");
+
+    TransactionRecord {
+        id: doc.name.rsplit('/').next().unwrap_or_default().to_owned(),
+        user_id: user_id.to_owned(),
+        kind: string(doc, "Type").unwrap_or_else(|| "payment".to_owned()),
+        currency: string(doc, "Currency"),
+        cost: cost(doc).map(|cost| cost.to_decimal()),
+        quantity: integer(doc, "Quantity"),
+        virtual_currency: string(doc, "VirtualCurrency"),
+        created_date: doc.create_time.as_ref().map(|t| to_date(t).to_rfc3339()),
+        refunded: refund_date.is_some(),
+        refund_date: refund_date.map(|date| date.to_rfc3339()),
+        refund_code: integer(doc, "RefundCode"),
+        reason: string(doc, "Reason"),
+        operator: string(doc, "Operator"),
+    }
+}
+
+//Idempotency keys end up in document IDs
+pub(crate) fn valid_key(key: &str) -> bool {
+    !key.is_empty()
+        && key.len() <= 128
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+//Credits, or one of the internal currencies SKUs are mapped to
+pub(crate) fn known_currency(data: &MyData, currency: &Option<String>) -> bool {
+    match currency {
+        Some(currency) => data.sku_currencies.values().any(|known| known == currency),
+        None => true,
+    }
+}
+
+pub(crate) fn user_name(project_id: &str, user_id: &str) -> String {
+    format!(
+        "projects/{}/databases/(default)/documents/users/{}",
+        project_id, user_id
+    )
+}
+
+#[get("/users/{user_id}/balance")]
+async fn balance(
+    firestore: web::Data<Mutex<MyData>>,
+    user_id: web::Path<String>,
+    identity: Identity,
+    request_id: RequestId,
+) -> impl Responder {
+    if !identity.role.allows(&READERS) {
+        return HttpResponse::Forbidden().json(ROLE_ERROR);
+    }
+
+    let mut firestore = firestore.lock().await;
+
+    let req = GetDocumentRequest {
+        name: user_name(&firestore.project_id, &user_id),
+        mask: Some(DocumentMask {
+            field_paths: vec!["Credits".to_owned(), "Balances".to_owned()],
+        }),
+        consistency_selector: None,
+    };
+
+    let user_doc = match firestore.client.get_document(req).await {
+        Ok(user_doc) => user_doc,
+        Err(error) => {
+            if let Code::NotFound = error.code() {
+                return HttpResponse::NotFound().json(USER_ERROR);
+            } else {
+                return log_store_error(&request_id, &error);
+            }
+        }
+    };
+
+    HttpResponse::Ok().json(Balance {
+        user_id: user_id.into_inner(),
+        credits: integer(&user_doc, "Credits").unwrap_or_default(),
+        balances: balances(&user_doc),
+    })
+}
+
+#[derive(Deserialize)]
+pub struct DateRange {
+    from: Option<String>,
+    to: Option<String>,
+}
+
+fn parse_date(date: &Option<String>) -> Result<Option<DateTime<FixedOffset>>, ()> {
+    match date {
+        Some(date) => DateTime::parse_from_rfc3339(date).map(Some).map_err(|_| ()),
+        None => Ok(None),
+    }
+}
+
+//A user's transactions, optionally created between from and to (inclusive).
+#[get("/users/{user_id}/transactions")]
+async fn transactions(
+    firestore: web::Data<Mutex<MyData>>,
+    user_id: web::Path<String>,
+    range: web::Query<DateRange>,
+    identity: Identity,
+    request_id: RequestId,
+) -> impl Responder {
+    if !identity.role.allows(&READERS) {
+        return HttpResponse::Forbidden().json(ROLE_ERROR);
+    }
+
+    let (from, to) = match (parse_date(&range.from), parse_date(&range.to)) {
+        (Ok(from), Ok(to)) => (from, to),
+        _ => return HttpResponse::BadRequest().json(DATE_ERROR),
+    };
+
+    let mut firestore = firestore.lock().await;
+
+    let parent = user_name(&firestore.project_id, &user_id);
+
+    let req = GetDocumentRequest {
+        name: parent.clone(),
+        mask: Some(DocumentMask {
+            field_paths: Vec::new(),
+        }),
+        consistency_selector: None,
+    };
+
+    if let Err(error) = firestore.client.get_document(req).await {
+        if let Code::NotFound = error.code() {
+            return HttpResponse::NotFound().json(USER_ERROR);
+        } else {
+            return log_store_error(&request_id, &error);
+        }
+    }
+
+    let mut records = Vec::new();
+    let mut page_token = String::new();
+
+    loop {
+        let req = ListDocumentsRequest {
+            parent: parent.clone(),
+            collection_id: "transact".to_owned(),
+            page_size: 300,
+            page_token,
+            ..ListDocumentsRequest::default()
+        };
+
+        let res = match firestore.client.list_documents(req).await {
+            Ok(res) => res,
+            Err(error) => return log_store_error(&request_id, &error),
+        };
+
+        for doc in &res.documents {
+            let created = doc.create_time.as_ref().map(to_date);
+
+            let after_from = match (from, created) {
+                (Some(from), Some(created)) => created >= from,
+                (Some(_), None) => false,
+                (None, _) => true,
+            };
+
+            let before_to = match (to, created) {
+                (Some(to), Some(created)) => created <= to,
+                (Some(_), None) => false,
+                (None, _) => true,
+            };
+
+            if after_from && before_to {
+                records.push(record(&user_id, doc));
+            }
+        }
+
+        if res.next_page_token.is_empty() {
+            break;
+        }
+
+        page_token = res.next_page_token;
+    }
+
+    HttpResponse::Ok().json(records)
+}
+
+#[get("/users/{user_id}/transactions/{transaction_id}")]
+async fn transaction(
+    firestore: web::Data<Mutex<MyData>>,
+    path: web::Path<(String, String)>,
+    identity: Identity,
+    request_id: RequestId,
+) -> impl Responder {
+    if !identity.role.allows(&READERS) {
+        return HttpResponse::Forbidden().json(ROLE_ERROR);
+    }
+
+    let (user_id, transaction_id) = path.into_inner();
+
+    let mut firestore = firestore.lock().await;
+
+    let req = GetDocumentRequest {
+        name: format!(
+            "{}/transact/{}",
+            user_name(&firestore.project_id, &user_id),
+            transaction_id
+        ),
+        mask: None,
+        consistency_selector: None,
+    };
+
+    match firestore.client.get_document(req).await {
+        Ok(doc) => HttpResponse::Ok().json(record(&user_id, &doc)),
+        Err(error) => {
+            if let Code::NotFound = error.code() {
+                HttpResponse::NotFound().json(TRANSACTION_ERROR)
+            } else {
+                log_store_error(&request_id, &error)
+            }
+        }
+    }
+}
+
+pub(crate) fn string_value(value: String) -> Value {
+    Value {
+        value_type: Some(ValueType::StringValue(value)),
+    }
+}
+
+//The adjustment or spend stored under the key, Err if it was for another amount or currency.
+pub(crate) async fn applied(
+    firestore: &mut MyData,
+    name: &str,
+    amount: i64,
+    currency: &Option<String>,
+) -> Result<Option<Document>, Status> {
+    let req = GetDocumentRequest {
+        name: name.to_owned(),
+        mask: None,
+        consistency_selector: None,
+    };
+
+    match firestore.client.get_document(req).await {
+        Ok(doc)
+            if integer(&doc, "Quantity") == Some(amount)
+                && string(&doc, "VirtualCurrency") == *currency =>
+        {
+            Ok(Some(doc))
+        }
+        Ok(_) => Err(Status::already_exists(name)),
+        Err(error) if error.code() == Code::NotFound => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+#[post("/users/{user_id}/adjustments")]
+async fn adjust(
+    firestore: web::Data<Mutex<MyData>>,
+    user_id: web::Path<String>,
+    adjustment: web::Json<Adjustment>,
+    identity: Identity,
+    request_id: RequestId,
+) -> impl Responder {
+    if !identity.role.allows(&[Role::Support]) {
+        return HttpResponse::Forbidden().json(ROLE_ERROR);
+    }
+
+    let user_id = user_id.into_inner();
+    let adjustment = adjustment.into_inner();
+
+    if adjustment.reason.trim().is_empty() || !valid_key(&adjustment.idempotency_key) {
+        return HttpResponse::BadRequest().json(REASON_ERROR);
+    }
+
+    let mut firestore = firestore.lock().await;
+
+    let currency = adjustment.currency;
+
+    if !known_currency(&firestore, &currency) {
+        return HttpResponse::BadRequest().json(CURRENCY_ERROR);
+    }
+
+    let adjustment_id = format!("{}{}", ADJUSTMENT_PREFIX, adjustment.idempotency_key);
+    let name = format!(
+        "{}/transact/{}",
+        user_name(&firestore.project_id, &user_id),
+        adjustment_id
+    );
+
+    //Adjustment already applied answer like the first time
+    match applied(&mut firestore, &name, adjustment.amount, &currency).await {
+        Ok(Some(doc)) => return HttpResponse::Created().json(record(&user_id, &doc)),
+        Ok(None) => {}
+        Err(error) if error.code() == Code::AlreadyExists => {
+            return HttpResponse::Conflict().json(KEY_REUSED_ERROR)
+        }
+        Err(error) => return log_store_error(&request_id, &error),
+    }
+
+    let req = GetDocumentRequest {
+        name: user_name(&firestore.project_id, &user_id),
+        mask: Some(DocumentMask {
+            field_paths: vec![balance_field(&currency)],
+        }),
+        consistency_selector: None,
+    };
+
+    let mut user_doc = match firestore.client.get_document(req).await {
+        Ok(user_doc) => user_doc,
+        Err(error) => {
+            if let Code::NotFound = error.code() {
+                return HttpResponse::NotFound().json(USER_ERROR);
+            } else {
+                return log_store_error(&request_id, &error);
+            }
+        }
+    };
+
+    //credit only changes Credits once the field exists
+    if currency.is_none() {
+        user_doc
+            .fields
+            .entry("Credits".to_owned())
+            .or_insert(Value {
+                value_type: Some(ValueType::IntegerValue(0)),
+            });
+    }
+
+    let new_balance = credit(&mut user_doc, &currency, adjustment.amount);
+
+    if new_balance < 0 {
+        return HttpResponse::BadRequest().json(BALANCE_ERROR);
+    }
+
+    let mut data: HashMap<String, Value> = HashMap::with_capacity(4);
+
+    data.insert("Type".to_owned(), string_value("adjustment".to_owned()));
+    data.insert(
+        "Quantity".to_owned(),
+        Value {
+            value_type: Some(ValueType::IntegerValue(adjustment.amount)),
+        },
+    );
+    data.insert("Reason".to_owned(), string_value(adjustment.reason));
+
+    if let Some(currency) = currency.clone() {
+        data.insert("VirtualCurrency".to_owned(), string_value(currency));
+    }
+
+    //The authenticated caller, not something the request can claim
+    data.insert("Operator".to_owned(), string_value(identity.subject));
+
+    let event = Event::CreditsAdjusted {
+        user_id: user_id.clone(),
+        adjustment_id,
+        currency: currency.clone(),
+        quantity: adjustment.amount,
+    };
+
+    let mut written = Document {
+        name: name.clone(),
+        fields: data,
+        create_time: None,
+        update_time: None,
+    };
+
+    let writes = vec![
+        Write {
+            update_mask: None,
+            update_transforms: Vec::new(),
+            current_document: Some(Precondition {
+                condition_type: Some(ConditionType::Exists(false)),
+            }),
+            operation: Some(Operation::Update(written.clone())),
+        },
+        Write {
+            update_mask: Some(DocumentMask {
+                field_paths: vec![balance_field(&currency)],
+            }),
+            update_transforms: Vec::new(),
+            //Fails if a webhook changed the balance since it was read
+            current_document: Some(Precondition {
+                condition_type: user_doc.update_time.clone().map(ConditionType::UpdateTime),
+            }),
+            operation: Some(Operation::Update(user_doc)),
+        },
+        outbox::write(&firestore.project_id, &event),
+    ];
+
+    let req = CommitRequest {
+        database: format!("projects/{}/databases/(default)", firestore.project_id),
+        writes,
+        transaction: Vec::new(),
+    };
+
+    match firestore.client.commit(req).await {
+        Ok(res) => written.create_time = res.commit_time,
+        //A retried commit fails its preconditions once the first attempt went through
+        Err(error) => {
+            return match applied(&mut firestore, &name, adjustment.amount, &currency).await {
+                Ok(Some(doc)) => HttpResponse::Created().json(record(&user_id, &doc)),
+                _ if error.code() == Code::FailedPrecondition => {
+                    HttpResponse::Conflict().json(CONFLICT_ERROR)
+                }
+                _ => log_store_error(&request_id, &error),
+            };
+        }
+    }
+
+    firestore
+        .hub
+        .publish(&user_id, &balance_update(currency, new_balance));
+
+    HttpResponse::Created().json(record(&user_id, &written))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth_middleware::{Authenticate, Keys};
+    use crate::handlers;
+    use crate::simulator;
+    use crate::test_support::{self, create_user};
+    use actix_web::http::{header, StatusCode};
+    use actix_web::test;
+    use actix_web::test::TestRequest;
+    use actix_web::App;
+    use std::sync::Arc;
+
+    async fn test_data() -> web::Data<Mutex<MyData>> {
+        let data = test_support::test_data();
+
+        //The second user gets adjustments with the same tickets
+        for user_id in &["1234567", "7654321"] {
+            create_user(&data, user_id, 0, &[]).await;
+        }
+
+        data
+    }
+
+    #[actix_rt::test]
+    async fn transaction_history() {
+        let data = test_data().await;
+
+        let keys = Keys::default()
+            .with_api_key("finance-key", "finance@example.com", Role::Finance)
+            .with_api_key("game-key", "game-server-1", Role::GameServer);
+
+        let app = App::new()
+            .app_data(data)
+            .service(handlers::notifications)
+            .service(
+                web::scope("/admin")
+                    .wrap(Authenticate::new(Arc::new(keys)))
+                    .service(balance)
+                    .service(transactions)
+                    .service(transaction),
+            );
+        let mut app = test::init_service(app).await;
+
+        for payload in &[
+            simulator::payment("1234567", 1, 100),
+            simulator::payment("1234567", 2, 50),
+            simulator::refund("1234567", 1, 100),
+        ] {
+            let req = TestRequest::post()
+                .uri("/webhook")
+                .header(header::CONTENT_TYPE, "application/json")
+                .set_payload(payload.clone())
+                .to_request();
+
+            let resp = test::call_service(&mut app, req).await;
+
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+
+        let req = TestRequest::get()
+            .uri("/admin/users/1234567/balance")
+            .header(header::AUTHORIZATION, "Bearer finance-key")
+            .to_request();
+        let resp: serde_json::Value = test::read_response_json(&mut app, req).await;
+
+        assert_eq!(resp["credits"], 50);
+
+        let req = TestRequest::get()
+            .uri("/admin/users/1234567/transactions")
+            .header(header::AUTHORIZATION, "Bearer finance-key")
+            .to_request();
+        let resp: serde_json::Value = test::read_response_json(&mut app, req).await;
+
+        assert_eq!(resp.as_array().unwrap().len(), 2);
+        assert_eq!(resp[0]["id"], "1");
+        assert_eq!(resp[0]["refunded"], true);
+        assert_eq!(resp[1]["refunded"], false);
+
+        let req = TestRequest::get()
+            .uri("/admin/users/1234567/transactions?from=2000-01-01T00:00:00Z&to=2001-01-01T00:00:00Z")
+            .header(header::AUTHORIZATION, "Bearer finance-key")
+            .to_request();
+        let resp: serde_json::Value = test::read_response_json(&mut app, req).await;
+
+        assert!(resp.as_array().unwrap().is_empty());
+
+        let req = TestRequest::get()
+            .uri("/admin/users/1234567/transactions/2")
+            .header(header::AUTHORIZATION, "Bearer finance-key")
+            .to_request();
+        let resp: serde_json::Value = test::read_response_json(&mut app, req).await;
+
+        assert_eq!(resp["quantity"], 50);
+
+        let req = TestRequest::get()
+            .uri("/admin/users/1234567/transactions/3")
+            .header(header::AUTHORIZATION, "Bearer finance-key")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let req = TestRequest::get()
+            .uri("/admin/users/1234567/balance")
+            .header(header::AUTHORIZATION, "Bearer game-key")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_rt::test]
+    async fn adjustment_recorded() {
+        let keys = Keys::default()
+            .with_api_key("support-key", "support@example.com", Role::Support)
+            .with_api_key("read-key", "viewer@example.com", Role::ReadOnly);
+
+        let app = App::new().app_data(test_data().await).service(
+            web::scope("/admin")
+                .wrap(Authenticate::new(Arc::new(keys)))
+                .service(adjust)
+                .service(balance)
+                .service(transactions),
+        );
+        let mut app = test::init_service(app).await;
+
+        let req = TestRequest::post()
+            .uri("/admin/users/1234567/adjustments")
+            .header(header::AUTHORIZATION, "Bearer support-key")
+            .set_json(&serde_json::json!({
+                "amount": 25,
+                "idempotency_key": "ticket-1",
+                "reason": "Compensation for outage"
+            }))
+            .to_request();
+        let resp: serde_json::Value = test::read_response_json(&mut app, req).await;
+
+        assert_eq!(resp["kind"], "adjustment");
+        assert_eq!(resp["quantity"], 25);
+        assert_eq!(resp["id"], "adj_ticket-1");
+
+        //Retried after a timeout, nothing is credited twice
+        let req = TestRequest::post()
+            .uri("/admin/users/1234567/adjustments")
+            .header(header::AUTHORIZATION, "Bearer support-key")
+            .set_json(&serde_json::json!({
+                "amount": 25,
+                "idempotency_key": "ticket-1",
+                "reason": "Compensation for outage"
+            }))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let req = TestRequest::post()
+            .uri("/admin/users/7654321/adjustments")
+            .header(header::AUTHORIZATION, "Bearer support-key")
+            .set_json(&serde_json::json!({
+                "amount": 25,
+                "idempotency_key": "ticket-1",
+                "reason": "Compensation for outage"
+            }))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let req = TestRequest::post()
+            .uri("/admin/users/1234567/adjustments")
+            .header(header::AUTHORIZATION, "Bearer support-key")
+            .set_json(&serde_json::json!({
+                "amount": 30,
+                "idempotency_key": "ticket-1",
+                "reason": "Compensation for outage"
+            }))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+        let req = TestRequest::post()
+            .uri("/admin/users/1234567/adjustments")
+            .header(header::AUTHORIZATION, "Bearer support-key")
+            .set_json(&serde_json::json!({
+                "amount": -10,
+                "idempotency_key": "ticket-2",
+                "reason": " "
+            }))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let req = TestRequest::post()
+            .uri("/admin/users/1234567/adjustments")
+            .header(header::AUTHORIZATION, "Bearer support-key")
+            .set_json(&serde_json::json!({
+                "amount": -30,
+                "idempotency_key": "ticket-3",
+                "reason": "Duplicate grant"
+            }))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let req = TestRequest::post()
+            .uri("/admin/users/1234567/adjustments")
+            .header(header::AUTHORIZATION, "Bearer read-key")
+            .set_json(&serde_json::json!({
+                "amount": 5,
+                "idempotency_key": "ticket-4",
+                "reason": "Goodwill"
+            }))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let req = TestRequest::post()
+            .uri("/admin/users/1234567/adjustments")
+            .header(header::AUTHORIZATION, "Bearer support-key")
+            .set_json(&serde_json::json!({
+                "amount": 5,
+                "idempotency_key": "ticket-5",
+                "reason": "Event reward",
+                "currency": "gems"
+            }))
+            .to_request();
+        let resp: serde_json::Value = test::read_response_json(&mut app, req).await;
+
+        assert_eq!(resp["virtual_currency"], "gems");
+
+        let req = TestRequest::post()
+            .uri("/admin/users/1234567/adjustments")
+            .header(header::AUTHORIZATION, "Bearer support-key")
+            .set_json(&serde_json::json!({
+                "amount": 5,
+                "idempotency_key": "ticket-6",
+                "reason": "Event reward",
+                "currency": "gold"
+            }))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let req = TestRequest::get()
+            .uri("/admin/users/1234567/balance")
+            .header(header::AUTHORIZATION, "Bearer read-key")
+            .to_request();
+        let resp: serde_json::Value = test::read_response_json(&mut app, req).await;
+
+        assert_eq!(resp["credits"], 25);
+        assert_eq!(resp["balances"]["gems"], 5);
+
+        let req = TestRequest::get()
+            .uri("/admin/users/1234567/transactions")
+            .header(header::AUTHORIZATION, "Bearer read-key")
+            .to_request();
+        let resp: serde_json::Value = test::read_response_json(&mut app, req).await;
+
+        assert_eq!(resp[0]["reason"], "Compensation for outage");
+        assert_eq!(resp[0]["operator"], "support@example.com");
+    }
+
+    #[actix_rt::test]
+    async fn invalid_api_key() {
+        let app = App::new().app_data(test_data().await).service(
+            web::scope("/admin")
+                .wrap(Authenticate::new(Arc::new(Keys::default())))
+                .service(balance),
+        );
+        let mut app = test::init_service(app).await;
+
+        let req = TestRequest::get()
+            .uri("/admin/users/1234567/balance")
+            .header(header::AUTHORIZATION, "Bearer not-the-key")
+            .to_request();
+
+        let resp = test::call_service(&mut app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,392 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+
+use failure::Error;
+
+use firestore_grpc_cloudrun::{value::ValueType, CreateDocumentRequest, Document, MapValue, Value};
+
+use serde::{Deserialize, Serialize};
+
+use crate::logging::Line;
+use crate::signature_middleware::SignatureVerdict;
+use crate::store::Store;
+
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+pub struct ArchiveRecord {
+    pub received_at: u64, //unix time in milliseconds
+    #[serde(default)]
+    pub request_id: String,
+    pub transaction_id: Option<i64>,
+    pub notification_type: Option<String>,
+    pub source_ip: Option<String>,
+    pub headers: BTreeMap<String, String>,
+    pub body: String,
+    pub signature: Option<SignatureVerdict>, //None if rejected before the signature check
+    pub status: u16,
+    pub latency_ms: u64,
+}
+
+impl ArchiveRecord {
+    //Record id, prefixed with the transaction id so all deliveries of a transaction sort together.
+    //The request id keeps deliveries received within the same millisecond apart.
+    pub fn key(&self) -> String {
+        match self.transaction_id {
+            Some(id) => format!("{}_{}_{}", id, self.received_at, self.request_id),
+            None => format!("none_{}_{}", self.received_at, self.request_id),
+        }
+    }
+
+    fn expired(&self, retention: Duration, now: SystemTime) -> bool {
+        let received = UNIX_EPOCH + Duration::from_millis(self.received_at);
+
+        received + retention < now
+    }
+}
+
+pub fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+//Notification bodies from a single notification or from archived records, one per line,
+//and how many records were skipped. The archive also keeps rejected traffic,
+//only bodies that came with a valid signature are returned unless include_unverified.
+pub fn payloads(content: &str, include_unverified: bool) -> Result<(Vec<String>, usize), Error> {
+    let json = serde_json::from_str::<serde_json::Value>(content);
+
+    if let Ok(json) = json {
+        //A one line archive has a notification_type too
+        let record = serde_json::from_value::<ArchiveRecord>(json.clone()).is_ok();
+
+        if json.get("notification_type").is_some() && !record {
+            return Ok((vec![content.to_owned()], 0));
+        }
+    }
+
+    let mut payloads = Vec::new();
+    let mut skipped = 0;
+
+    for line in content.lines().filter(|line| !line.trim().is_empty()) {
+        let record: ArchiveRecord = serde_json::from_str(line)?;
+
+        if include_unverified || record.signature == Some(SignatureVerdict::Valid) {
+            payloads.push(record.body);
+        } else {
+            skipped += 1;
+        }
+    }
+
+    Ok((payloads, skipped))
+}
+
+//Append only, records are never updated once written.
+#[async_trait(?Send)]
+pub trait Archive {
+    async fn append(&mut self, record: ArchiveRecord) -> Result<(), Error>;
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(suffix);
+
+    PathBuf::from(name)
+}
+
+//One JSON record per line, used for local runs and tests.
+pub struct JsonlArchive {
+    file: File,
+}
+
+impl JsonlArchive {
+    //Expired records are dropped when the file is opened. Lines that do not parse,
+    //such as one cut short by a crash, are moved to <path>.unreadable.
+    //The kept records are written to <path>.tmp then renamed over the archive,
+    //a crash in between leaves the archive as it was.
+    pub fn open(path: PathBuf, retention: Duration) -> Result<Self, Error> {
+        if path.exists() {
+            let now = SystemTime::now();
+
+            let tmp_path = with_suffix(&path, "tmp");
+            let unreadable_path = with_suffix(&path, "unreadable");
+
+            let mut tmp = File::create(&tmp_path)?;
+            let mut unreadable = None;
+
+            for (number, line) in BufReader::new(File::open(&path)?).lines().enumerate() {
+                let line = line?;
+
+                match serde_json::from_str::<ArchiveRecord>(&line) {
+                    Ok(record) if !record.expired(retention, now) => writeln!(tmp, "{}", line)?,
+                    Ok(_) => {}
+                    Err(e) => {
+                        let mut log = Line::background("archive line unreadable", "WARNING");
+                        log.detail = Some(format!(
+                            "line {} of {} moved to {}",
+                            number + 1,
+                            path.display(),
+                            unreadable_path.display()
+                        ));
+                        log.error = Some(e.to_string());
+                        log.write();
+
+                        if unreadable.is_none() {
+                            unreadable = Some(
+                                OpenOptions::new()
+                                    .create(true)
+                                    .append(true)
+                                    .open(&unreadable_path)?,
+                            );
+                        }
+
+                        if let Some(unreadable) = &mut unreadable {
+                            writeln!(unreadable, "{}", line)?;
+                            unreadable.sync_all()?;
+                        }
+                    }
+                }
+            }
+
+            tmp.sync_all()?;
+            fs::rename(&tmp_path, &path)?;
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(Self { file })
+    }
+}
+
+#[async_trait(?Send)]
+impl Archive for JsonlArchive {
+    async fn append(&mut self, record: ArchiveRecord) -> Result<(), Error> {
+        let line = serde_json::to_string(&record)?;
+
+        writeln!(self.file, "{}", line)?;
+
+        Ok(())
+    }
+}
+
+//Writes to the archive collection, ExpireAt is meant for a Firestore TTL policy.
+pub struct StoreArchive {
+    project_id: String,
+    client: Box<dyn Store + Send>,
+    retention: Duration,
+}
+
+impl StoreArchive {
+    pub fn new(project_id: String, client: Box<dyn Store + Send>, retention: Duration) -> Self {
+        Self {
+            project_id,
+            client,
+            retention,
+        }
+    }
+}
+
+fn string_value(value: String) -> Value {
+    Value {
+        value_type: Some(ValueType::StringValue(value)),
+    }
+}
+
+fn integer_value(value: i64) -> Value {
+    Value {
+        value_type: Some(ValueType::IntegerValue(value)),
+    }
+}
+
+fn timestamp_value(time: SystemTime) -> Value {
+    Value {
+        value_type: Some(ValueType::TimestampValue(prost_types::Timestamp::from(
+            time,
+        ))),
+    }
+}
+
+#[async_trait(?Send)]
+impl Archive for StoreArchive {
+    async fn append(&mut self, record: ArchiveRecord) -> Result<(), Error> {
+        let received = UNIX_EPOCH + Duration::from_millis(record.received_at);
+
+        let document_id = record.key();
+
+        let mut data: HashMap<String, Value> = HashMap::with_capacity(11);
+
+        if let Some(id) = record.transaction_id {
+            data.insert("TransactionId".to_owned(), integer_value(id));
+        }
+
+        if let Some(notification_type) = record.notification_type {
+            data.insert(
+                "NotificationType".to_owned(),
+                string_value(notification_type),
+            );
+        }
+
+        if let Some(ip) = record.source_ip {
+            data.insert("SourceIp".to_owned(), string_value(ip));
+        }
+
+        if let Some(signature) = record.signature {
+            data.insert(
+                "Signature".to_owned(),
+                string_value(signature.as_str().to_owned()),
+            );
+        }
+
+        let headers = record
+            .headers
+            .into_iter()
+            .map(|(name, value)| (name, string_value(value)))
+            .collect();
+
+        data.insert(
+            "Headers".to_owned(),
+            Value {
+                value_type: Some(ValueType::MapValue(MapValue { fields: headers })),
+            },
+        );
+
+        data.insert("Body".to_owned(), string_value(record.body));
+        data.insert("Status".to_owned(), integer_value(record.status as i64));
+        data.insert(
+            "LatencyMs".to_owned(),
+            integer_value(record.latency_ms as i64),
+        );
+        data.insert("ReceivedDate".to_owned(), timestamp_value(received));
+        data.insert(
+            "ExpireAt".to_owned(),
+            timestamp_value(received + self.retention),
+        );
+
+        let doc = Document {
+            name: String::new(),
+            fields: data,
+            create_time: None,
+            update_time: None,
+        };
+
+        let req = CreateDocumentRequest {
+            parent: format!("projects/{}/databases/(default)/documents", self.project_id),
+            collection_id: "archive".to_owned(),
+            document_id,
+            document: Some(doc),
+            mask: None,
+        };
+
+        self.client.create_document(req).await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn record(received_at: u64) -> ArchiveRecord {
+        ArchiveRecord {
+            received_at,
+            request_id: format!("request{}", received_at),
+            transaction_id: Some(1),
+            notification_type: Some("payment".to_owned()),
+            source_ip: Some("185.30.21.1".to_owned()),
+            headers: BTreeMap::new(),
+            body: "{}".to_owned(),
+            signature: Some(SignatureVerdict::Valid),
+            status: 200,
+            latency_ms: 3,
+        }
+    }
+
+    #[test]
+    fn payloads_from_notification_or_records() {
+        let notification = r#"{"notification_type": "user_validation", "user": {"id": "1"}}"#;
+
+        assert_eq!(
+            payloads(notification, false).unwrap(),
+            (vec![notification.to_owned()], 0)
+        );
+
+        let forged = ArchiveRecord {
+            signature: Some(SignatureVerdict::Invalid),
+            ..record(3)
+        };
+
+        let records = [record(1), record(2), forged]
+            .iter()
+            .map(|record| serde_json::to_string(record).unwrap())
+            .collect::<Vec<String>>()
+            .join("\n");
+
+        assert_eq!(
+            payloads(&records, false).unwrap(),
+            (vec!["{}".to_owned(), "{}".to_owned()], 1)
+        );
+        assert_eq!(payloads(&records, true).unwrap().0.len(), 3);
+
+        let one_line = serde_json::to_string(&ArchiveRecord {
+            signature: Some(SignatureVerdict::Invalid),
+            ..record(4)
+        })
+        .unwrap();
+
+        assert_eq!(payloads(&one_line, false).unwrap(), (Vec::new(), 1));
+        assert_eq!(payloads(&one_line, true).unwrap().0, vec!["{}".to_owned()]);
+
+        assert!(payloads("not json", false).is_err());
+    }
+
+    #[actix_rt::test]
+    async fn jsonl_retention() {
+        let path = std::env::temp_dir().join("archive_jsonl_retention.jsonl");
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(with_suffix(&path, "unreadable"));
+
+        let retention = Duration::from_secs(60 * 60 * 24);
+        let now = unix_millis(SystemTime::now());
+
+        let mut archive = JsonlArchive::open(path.clone(), retention).unwrap();
+        archive.append(record(1000)).await.unwrap();
+        archive.append(record(now)).await.unwrap();
+        drop(archive);
+
+        //Cut short by a crash
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"{\"received_at\":")
+            .unwrap();
+
+        JsonlArchive::open(path.clone(), retention).unwrap();
+
+        let content = fs::read_to_string(&path).unwrap();
+        let records: Vec<ArchiveRecord> = content
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+
+        assert_eq!(records, vec![record(now)]);
+
+        //Kept as evidence
+        let unreadable = with_suffix(&path, "unreadable");
+
+        assert_eq!(
+            fs::read_to_string(&unreadable).unwrap(),
+            "{\"received_at\":\n"
+        );
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&unreadable).unwrap();
+    }
+}
@@ -0,0 +1,302 @@
+use actix_http::Payload;
+use actix_service::{Service, Transform};
+use actix_web::{dev::ServiceRequest, dev::ServiceResponse, Error, HttpMessage};
+use bytes::BytesMut;
+use futures::future::{self, ok, Future, Ready};
+use futures::lock::Mutex;
+use futures::stream::{self, StreamExt};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Instant, SystemTime};
+
+use crate::archive::{unix_millis, Archive, ArchiveRecord};
+use crate::logging::{Line, RequestId};
+use crate::metrics;
+use crate::signature_middleware::SignatureVerdict;
+
+pub type SharedArchive = Arc<Mutex<Box<dyn Archive + Send>>>;
+
+//Must be the outermost middleware so rejected requests are archived too.
+pub struct ArchiveNotifications {
+    archive: SharedArchive,
+    max_body_size: usize,
+}
+
+impl ArchiveNotifications {
+    pub fn new(archive: SharedArchive, max_body_size: usize) -> Self {
+        Self {
+            archive,
+            max_body_size,
+        }
+    }
+}
+
+impl<S: 'static, B> Transform<S> for ArchiveNotifications
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = ArchiveNotificationsMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(ArchiveNotificationsMiddleware {
+            service: Rc::new(RefCell::new(service)),
+            archive: self.archive.clone(),
+            max_body_size: self.max_body_size,
+        })
+    }
+}
+
+pub struct ArchiveNotificationsMiddleware<S> {
+    service: Rc<RefCell<S>>,
+    archive: SharedArchive,
+    max_body_size: usize,
+}
+
+fn transaction_id(json: &serde_json::Value) -> Option<i64> {
+    json.get("transaction")?.get("id")?.as_i64()
+}
+
+fn notification_type(json: &serde_json::Value) -> Option<String> {
+    Some(json.get("notification_type")?.as_str()?.to_owned())
+}
+
+impl<S, B> Service for ArchiveNotificationsMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: ServiceRequest) -> Self::Future {
+        let mut svc = self.service.clone();
+        let archive = self.archive.clone();
+        let max_body_size = self.max_body_size;
+
+        Box::pin(async move {
+            let start = Instant::now();
+            let received_at = unix_millis(SystemTime::now());
+
+            let mut body = BytesMut::new();
+            let mut stream = req.take_payload();
+
+            //Only the start of an oversized body is kept, VerifySignature rejects it
+            while body.len() <= max_body_size {
+                match stream.next().await {
+                    Some(chunk) => body.extend_from_slice(&chunk?),
+                    None => break,
+                }
+            }
+
+            let body = body.freeze();
+
+            //Put the body back for the next middlewares, followed by whatever was not read
+            let read = stream::once(future::ok(body.clone()));
+            req.set_payload(Payload::Stream(Box::pin(read.chain(stream))));
+
+            let source_ip = req.peer_addr().map(|socket| socket.ip().to_string());
+
+            let headers = req
+                .headers()
+                .iter()
+                .map(|(name, value)| {
+                    (
+                        name.as_str().to_owned(),
+                        String::from_utf8_lossy(value.as_bytes()).into_owned(),
+                    )
+                })
+                .collect::<BTreeMap<String, String>>();
+
+            let request_id = req.extensions().get::<RequestId>().cloned();
+            let request_id = request_id.unwrap_or_else(RequestId::generate);
+
+            let res = svc.call(req).await;
+
+            //An inner error is still answered, with the status of its response
+            let (status, signature) = match &res {
+                Ok(res) => (
+                    res.status(),
+                    res.request()
+                        .extensions()
+                        .get::<SignatureVerdict>()
+                        .copied(),
+                ),
+                Err(error) => (error.as_response_error().status_code(), None),
+            };
+
+            let json = serde_json::from_slice::<serde_json::Value>(&body).ok();
+
+            let record = ArchiveRecord {
+                received_at,
+                request_id: request_id.0.clone(),
+                transaction_id: json.as_ref().and_then(transaction_id),
+                notification_type: json.as_ref().and_then(notification_type),
+                source_ip,
+                headers,
+                body: String::from_utf8_lossy(&body).into_owned(),
+                signature,
+                status: status.as_u16(),
+                latency_ms: start.elapsed().as_millis() as u64,
+            };
+
+            //Archiving must never change the response sent to Xsolla
+            if let Err(e) = archive.lock().await.append(record).await {
+                metrics::ARCHIVE_FAILURES.inc();
+
+                let mut line = Line::new("archive failed", &request_id);
+                line.severity = "ERROR";
+                line.transaction_id = json.as_ref().and_then(transaction_id);
+                line.error = Some(e.to_string());
+                line.write();
+            }
+
+            res
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers;
+    use crate::signature_middleware::VerifySignature;
+    use actix_web::http::header;
+    use actix_web::http::StatusCode;
+    use actix_web::test;
+    use actix_web::test::TestRequest;
+    use actix_web::App;
+    use async_trait::async_trait;
+
+    struct VecArchive(Arc<std::sync::Mutex<Vec<ArchiveRecord>>>);
+
+    #[async_trait(?Send)]
+    impl Archive for VecArchive {
+        async fn append(&mut self, record: ArchiveRecord) -> Result<(), failure::Error> {
+            self.0.lock().unwrap().push(record);
+
+            Ok(())
+        }
+    }
+
+    #[actix_rt::test]
+    async fn rejected_signature_archived() {
+        let records = Arc::new(std::sync::Mutex::new(Vec::new()));
+
+        let archive: Box<dyn Archive + Send> = Box::new(VecArchive(records.clone()));
+        let archive = Arc::new(Mutex::new(archive));
+
+        let app = App::new()
+            .wrap(VerifySignature::new(
+                "Ultra1Top2Secret3Key".to_owned(),
+                64 * 1024,
+            ))
+            .wrap(ArchiveNotifications::new(archive, 64 * 1024))
+            .service(handlers::notifications);
+        let mut app = test::init_service(app).await;
+
+        let data = r#"{"notification_type":"payment","transaction":{"id":42}}"#;
+
+        let req = TestRequest::post()
+            .uri("/webhook")
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(
+                header::AUTHORIZATION,
+                "Bearer bd31a2212735b01bc15e8350a6d27003a2b63d26",
+            )
+            .set_payload(data)
+            .to_request();
+
+        let resp = test::call_service(&mut app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let records = records.lock().unwrap();
+
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].transaction_id, Some(42));
+        assert_eq!(records[0].notification_type.as_deref(), Some("payment"));
+        assert_eq!(records[0].signature, Some(SignatureVerdict::Invalid));
+        assert_eq!(records[0].status, 401);
+        assert_eq!(records[0].body, data);
+    }
+
+    struct FailingArchive;
+
+    #[async_trait(?Send)]
+    impl Archive for FailingArchive {
+        async fn append(&mut self, _record: ArchiveRecord) -> Result<(), failure::Error> {
+            Err(failure::err_msg("disk full"))
+        }
+    }
+
+    #[actix_rt::test]
+    async fn inner_error_archived() {
+        let records = Arc::new(std::sync::Mutex::new(Vec::new()));
+
+        let archive: Box<dyn Archive + Send> = Box::new(VecArchive(records.clone()));
+        let archive = Arc::new(Mutex::new(archive));
+
+        let app = App::new()
+            .wrap_fn(|_, _| {
+                future::err::<ServiceResponse, _>(actix_web::error::ErrorBadGateway("down"))
+            })
+            .wrap(ArchiveNotifications::new(archive, 64 * 1024))
+            .service(handlers::notifications);
+        let mut app = test::init_service(app).await;
+
+        let req = TestRequest::post().uri("/webhook").to_request();
+        let error = app.call(req).await.err().unwrap();
+
+        assert_eq!(
+            error.as_response_error().status_code(),
+            StatusCode::BAD_GATEWAY
+        );
+
+        {
+            let records = records.lock().unwrap();
+
+            assert_eq!(records.len(), 1);
+            assert_eq!(records[0].status, 502);
+        }
+
+        //Failures are counted, the response is left alone
+        let archive: Box<dyn Archive + Send> = Box::new(FailingArchive);
+        let archive = Arc::new(Mutex::new(archive));
+
+        let app = App::new()
+            .wrap(ArchiveNotifications::new(archive, 64 * 1024))
+            .route(
+                "/webhook",
+                actix_web::web::post().to(actix_web::HttpResponse::Ok),
+            );
+        let mut app = test::init_service(app).await;
+
+        let failures = metrics::ARCHIVE_FAILURES.get();
+
+        let req = TestRequest::post().uri("/webhook").to_request();
+
+        assert_eq!(
+            test::call_service(&mut app, req).await.status(),
+            StatusCode::OK
+        );
+        assert_eq!(metrics::ARCHIVE_FAILURES.get(), failures + 1);
+    }
+}
@@ -0,0 +1,349 @@
+use actix_http::Payload;
+use actix_service::{Service, Transform};
+use actix_web::{
+    dev::ServiceRequest, dev::ServiceResponse, error::InternalError, http::header, Error,
+    FromRequest, HttpMessage, HttpRequest, HttpResponse,
+};
+use futures::future::{err, ok, Ready};
+use futures::Future;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use crate::config::Config;
+use crate::models::Error as JsonError;
+use crate::models::ErrorMessage;
+
+#[derive(PartialEq, Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    ReadOnly,
+    Support,
+    Finance,
+    GameServer,
+    Admin,
+}
+
+impl Role {
+    //One of the listed roles, admin may do anything
+    pub fn allows(self, roles: &[Role]) -> bool {
+        self == Role::Admin || roles.contains(&self)
+    }
+}
+
+//Who called, left in the request extensions by Authenticate.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Identity {
+    pub subject: String,
+    pub role: Role,
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+    role: Role,
+}
+
+#[derive(Default)]
+pub struct Keys {
+    api_keys: HashMap<String, Identity>,
+    hs256: Option<DecodingKey<'static>>,
+    rs256: Option<DecodingKey<'static>>,
+    audience: Option<String>,
+    issuer: Option<String>,
+}
+
+impl Keys {
+    pub fn with_api_key(mut self, key: &str, subject: &str, role: Role) -> Self {
+        let identity = Identity {
+            subject: subject.to_owned(),
+            role,
+        };
+
+        self.api_keys.insert(key.to_owned(), identity);
+        self
+    }
+
+    pub fn with_hs256_secret(mut self, secret: &[u8]) -> Self {
+        self.hs256 = Some(DecodingKey::from_secret(secret).into_static());
+        self
+    }
+
+    //Parsed and checked by Config
+    pub fn with_rs256_public_key(mut self, key: DecodingKey<'static>) -> Self {
+        self.rs256 = Some(key);
+        self
+    }
+
+    pub fn with_audience_and_issuer(mut self, audience: &str, issuer: &str) -> Self {
+        self.audience = Some(audience.to_owned());
+        self.issuer = Some(issuer.to_owned());
+        self
+    }
+
+    fn identify(&self, token: &str) -> Option<Identity> {
+        if let Some(identity) = self.api_keys.get(token) {
+            return Some(identity.clone());
+        }
+
+        let header = jsonwebtoken::decode_header(token).ok()?;
+
+        let key = match header.alg {
+            Algorithm::HS256 => self.hs256.as_ref()?,
+            Algorithm::RS256 => self.rs256.as_ref()?,
+            _ => return None,
+        };
+
+        let mut validation = Validation::new(header.alg);
+        validation.iss = self.issuer.clone();
+
+        if let Some(audience) = &self.audience {
+            validation.set_audience(&[audience]);
+        }
+
+        let data = jsonwebtoken::decode::<Claims>(token, key, &validation).ok()?;
+
+        Some(Identity {
+            subject: data.claims.sub,
+            role: data.claims.role,
+        })
+    }
+}
+
+pub fn get_keys(config: &Config) -> Keys {
+    let mut keys = Keys::default();
+
+    for api_key in &config.admin_api_keys {
+        keys = keys.with_api_key(&api_key.key, &api_key.subject, api_key.role);
+    }
+
+    if let Some(secret) = &config.jwt_hs256_secret {
+        keys = keys.with_hs256_secret(secret.as_bytes());
+    }
+
+    if let Some(key) = &config.jwt_rs256_public_key {
+        keys = keys.with_rs256_public_key(key.clone());
+    }
+
+    if let (Some(audience), Some(issuer)) = (&config.jwt_audience, &config.jwt_issuer) {
+        keys = keys.with_audience_and_issuer(audience, issuer);
+    }
+
+    keys
+}
+
+const AUTH_ERROR: ErrorMessage = ErrorMessage {
+    error: JsonError {
+        code: "INVALID_CREDENTIALS",
+        message: "Invalid API key or token",
+    },
+};
+
+pub const ROLE_ERROR: ErrorMessage = ErrorMessage {
+    error: JsonError {
+        code: "FORBIDDEN",
+        message: "Role not allowed",
+    },
+};
+
+impl FromRequest for Identity {
+    type Config = ();
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        match req.extensions().get::<Identity>() {
+            Some(identity) => ok(identity.clone()),
+            None => err(InternalError::from_response(
+                "Missing identity",
+                HttpResponse::Unauthorized().json(AUTH_ERROR),
+            )
+            .into()),
+        }
+    }
+}
+
+//Bearer API key or JWT for staff and game servers, never applied to the webhook.
+pub struct Authenticate {
+    keys: Arc<Keys>,
+}
+
+impl Authenticate {
+    pub fn new(keys: Arc<Keys>) -> Self {
+        Authenticate { keys }
+    }
+}
+
+impl<S, B> Transform<S> for Authenticate
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = AuthenticateMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(AuthenticateMiddleware {
+            service,
+            keys: self.keys.clone(),
+        })
+    }
+}
+
+pub struct AuthenticateMiddleware<S> {
+    service: S,
+    keys: Arc<Keys>,
+}
+
+impl<S, B> Service for AuthenticateMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        //"Bearer <key or token>"
+        let identity = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .and_then(|token| self.keys.identify(token));
+
+        if let Some(identity) = identity {
+            req.extensions_mut().insert(identity);
+
+            return Box::pin(self.service.call(req));
+        }
+
+        Box::pin(ok(req.into_response(
+            HttpResponse::Unauthorized().json(AUTH_ERROR).into_body(),
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::test;
+    use actix_web::test::TestRequest;
+    use actix_web::{web, App};
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde::Serialize;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    const SECRET: &[u8] = b"Jwt1Secret2Key3Test";
+
+    #[derive(Serialize)]
+    struct TestClaims<'a> {
+        sub: &'a str,
+        role: &'a str,
+        exp: u64,
+        aud: &'a str,
+        iss: &'a str,
+    }
+
+    fn jwt(role: &str, exp: u64, aud: &str) -> String {
+        let claims = TestClaims {
+            sub: "finance@example.com",
+            role,
+            exp,
+            aud,
+            iss: "https://auth.example.com",
+        };
+
+        jsonwebtoken::encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(SECRET),
+        )
+        .unwrap()
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    async fn whoami(identity: Identity) -> HttpResponse {
+        HttpResponse::Ok().body(identity.subject)
+    }
+
+    #[test]
+    fn role_permissions() {
+        let readers = [Role::ReadOnly, Role::Support, Role::Finance];
+
+        assert!(Role::Support.allows(&readers));
+        assert!(Role::Admin.allows(&[Role::Finance]));
+        assert!(!Role::GameServer.allows(&readers));
+        assert!(!Role::ReadOnly.allows(&[Role::Support]));
+        assert!(!Role::Finance.allows(&[Role::Support]));
+        assert!(!Role::Support.allows(&[Role::GameServer]));
+    }
+
+    #[actix_rt::test]
+    async fn api_key_or_jwt() {
+        let keys = Keys::default()
+            .with_api_key("support-key", "support-bot", Role::Support)
+            .with_hs256_secret(SECRET)
+            .with_audience_and_issuer("xsolla-admin", "https://auth.example.com");
+
+        let app = App::new().service(
+            web::scope("/admin")
+                .wrap(Authenticate::new(Arc::new(keys)))
+                .route("/whoami", web::get().to(whoami)),
+        );
+        let mut app = test::init_service(app).await;
+
+        for (authorization, status) in &[
+            ("Bearer support-key".to_owned(), StatusCode::OK),
+            ("Basic support-key".to_owned(), StatusCode::UNAUTHORIZED),
+            ("Bearer wrong-key".to_owned(), StatusCode::UNAUTHORIZED),
+            (
+                format!("Bearer {}", jwt("finance", now() + 60, "xsolla-admin")),
+                StatusCode::OK,
+            ),
+            (
+                format!("Bearer {}", jwt("finance", now() - 600, "xsolla-admin")),
+                StatusCode::UNAUTHORIZED,
+            ),
+            (
+                format!("Bearer {}", jwt("superuser", now() + 60, "xsolla-admin")),
+                StatusCode::UNAUTHORIZED,
+            ),
+            (
+                format!("Bearer {}", jwt("finance", now() + 60, "other-service")),
+                StatusCode::UNAUTHORIZED,
+            ),
+        ] {
+            let req = TestRequest::get()
+                .uri("/admin/whoami")
+                .header(header::AUTHORIZATION, authorization.as_str())
+                .to_request();
+
+            let resp = test::call_service(&mut app, req).await;
+
+            assert_eq!(resp.status(), *status, "{}", authorization);
+        }
+    }
+}
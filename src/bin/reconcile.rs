@@ -0,0 +1,126 @@
+use std::env;
+use std::fs::File;
+use std::process;
+
+use actix_web::web;
+
+use futures::lock::Mutex;
+
+use actix_test::config::Config;
+use actix_test::{push, reconcile, store, MyData};
+
+const USAGE: &str = "Usage: reconcile <CSV> [--replay]
+
+CSV is an Xsolla transactions report export. Every transaction in it is
+compared with the users/*/transact/* documents in Firestore and a JSON
+report of missing, duplicated, extra and mismatched transactions is printed.
+With --replay, missing transactions are processed as the notifications
+Xsolla should have sent, then compared again.";
+
+fn exit_with_usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2)
+}
+
+fn parse_args() -> (String, bool) {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    let (path, mut options) = match args.split_first() {
+        Some((path, options)) if !path.starts_with("--") => (path.clone(), options),
+        _ => exit_with_usage(),
+    };
+
+    let mut replay = false;
+
+    loop {
+        options = match options {
+            [] => break,
+            [flag, rest @ ..] if flag == "--replay" => {
+                replay = true;
+                rest
+            }
+            _ => exit_with_usage(),
+        };
+    }
+
+    (path, replay)
+}
+
+async fn report(
+    data: &web::Data<Mutex<MyData>>,
+    export: &[reconcile::ExportRow],
+) -> reconcile::Report {
+    let mut data = data.lock().await;
+    let project_id = data.project_id.clone();
+
+    let stored = reconcile::load_store(data.client.as_mut(), &project_id)
+        .await
+        .unwrap_or_else(|e| {
+            eprintln!("Trying to list transactions Error: {}", e);
+            process::exit(1)
+        });
+
+    reconcile::compare(export, &stored)
+}
+
+#[actix_rt::main]
+async fn main() {
+    let (path, replay) = parse_args();
+
+    let file = File::open(&path).unwrap_or_else(|e| {
+        eprintln!("Trying to read {} Error: {}", path, e);
+        process::exit(1)
+    });
+
+    let export = reconcile::parse_export(file).unwrap_or_else(|e| {
+        eprintln!("Trying to parse {} Error: {}", path, e);
+        process::exit(1)
+    });
+
+    let config = Config::load().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1)
+    });
+
+    let project_id = store::get_project_id(&config).await.unwrap_or_else(|e| {
+        eprintln!("Trying to get the project ID Error: {}", e);
+        process::exit(1)
+    });
+
+    let client = store::get_client(&config).await.unwrap_or_else(|e| {
+        eprintln!("Trying to connect to Firestore Error: {}", e);
+        process::exit(1)
+    });
+
+    let data = MyData {
+        project_id,
+        client: Box::new(client),
+        reject_unknown: config.reject_unknown_notifications,
+        hub: push::Hub::default(),
+        sku_currencies: config.virtual_currency_skus,
+    };
+
+    let data = web::Data::new(Mutex::new(data));
+
+    let mut result = report(&data, &export).await;
+
+    if replay && !result.missing.is_empty() {
+        for row in &result.missing {
+            match reconcile::replay(data.clone(), row).await {
+                Ok(statuses) => eprintln!("Replayed {} {:?}", row.transaction_id, statuses),
+                Err(error) => eprintln!("Skipped {} Error: {}", row.transaction_id, error),
+            }
+        }
+
+        result = report(&data, &export).await;
+    }
+
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&result).expect("Trying to serialize report Error: ")
+    );
+
+    if !result.is_clean() {
+        process::exit(1);
+    }
+}
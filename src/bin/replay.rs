@@ -0,0 +1,193 @@
+use std::env;
+use std::fs;
+use std::process;
+
+use actix_web::client::Client;
+use actix_web::http::header;
+use actix_web::{test, web, App};
+
+use futures::lock::Mutex;
+
+use actix_test::config::Config;
+use actix_test::{archive, handlers, push, signature_middleware, store, MyData};
+
+const USAGE: &str = "Usage: replay <FILE> (--url <URL> | --store firestore) [--include-unverified]
+
+FILE is a notification JSON file or archived records, one per line.
+Each notification is signed with WEBHOOK_SECRET_KEY then either posted to
+a running instance at URL or processed in-process against Firestore.
+Archived records are only replayed if they came with a valid signature,
+--include-unverified also replays rejected and unsigned ones.";
+
+enum Target {
+    Url(String),
+    Firestore,
+}
+
+fn exit_with_usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2)
+}
+
+fn parse_args() -> (String, Target, bool) {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+
+    let include_unverified = args.iter().any(|arg| arg == "--include-unverified");
+    args.retain(|arg| arg != "--include-unverified");
+
+    let (path, options) = match args.split_first() {
+        Some((path, options)) if !path.starts_with("--") => (path.clone(), options),
+        _ => exit_with_usage(),
+    };
+
+    let target = match options {
+        [flag, url] if flag == "--url" => Target::Url(url.clone()),
+        [flag, store] if flag == "--store" && store == "firestore" => Target::Firestore,
+        _ => exit_with_usage(),
+    };
+
+    (path, target, include_unverified)
+}
+
+async fn post(url: &str, payload: String, signature: &str) -> Result<(u16, String), String> {
+    let mut res = Client::default()
+        .post(url)
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::AUTHORIZATION, format!("Bearer {}", signature))
+        .send_body(payload)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let body = res.body().await.map_err(|e| e.to_string())?;
+
+    Ok((
+        res.status().as_u16(),
+        String::from_utf8_lossy(&body).into_owned(),
+    ))
+}
+
+async fn in_process(
+    data: web::Data<Mutex<MyData>>,
+    payload: String,
+    secret: &str,
+    signature: &str,
+    max_body_size: usize,
+) -> (u16, String) {
+    let app = App::new()
+        .app_data(data)
+        .wrap(signature_middleware::VerifySignature::new(
+            secret.to_owned(),
+            max_body_size,
+        ))
+        .service(handlers::notifications);
+    let mut app = test::init_service(app).await;
+
+    let req = test::TestRequest::post()
+        .uri("/webhook")
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::AUTHORIZATION, format!("Bearer {}", signature))
+        .set_payload(payload)
+        .to_request();
+
+    let res = test::call_service(&mut app, req).await;
+    let status = res.status().as_u16();
+    let body = test::read_body(res).await;
+
+    (status, String::from_utf8_lossy(&body).into_owned())
+}
+
+#[actix_rt::main]
+async fn main() {
+    let (path, target, include_unverified) = parse_args();
+
+    let content = fs::read_to_string(&path).unwrap_or_else(|e| {
+        eprintln!("Trying to read {} Error: {}", path, e);
+        process::exit(1)
+    });
+
+    let (payloads, skipped) = archive::payloads(&content, include_unverified).unwrap_or_else(|e| {
+        eprintln!("Trying to parse {} Error: {}", path, e);
+        process::exit(1)
+    });
+
+    if skipped > 0 {
+        eprintln!(
+            "Skipped {} records without a valid signature, see --include-unverified",
+            skipped
+        );
+    }
+
+    let config = Config::load().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1)
+    });
+
+    let secret = config.webhook_secret_key.clone().unwrap_or_else(|| {
+        eprintln!("WEBHOOK_SECRET_KEY is required to sign the notifications");
+        process::exit(1)
+    });
+
+    let data = match target {
+        Target::Url(_) => None,
+        Target::Firestore => {
+            let project_id = store::get_project_id(&config).await.unwrap_or_else(|e| {
+                eprintln!("Trying to get the project ID Error: {}", e);
+                process::exit(1)
+            });
+
+            let client = store::get_client(&config).await.unwrap_or_else(|e| {
+                eprintln!("Trying to connect to Firestore Error: {}", e);
+                process::exit(1)
+            });
+
+            Some(MyData {
+                project_id,
+                client: Box::new(client),
+                reject_unknown: config.reject_unknown_notifications,
+                hub: push::Hub::default(),
+                sku_currencies: config.virtual_currency_skus.clone(),
+            })
+        }
+    };
+
+    let data = data.map(|data| web::Data::new(Mutex::new(data)));
+
+    let url = match target {
+        Target::Url(url) => Some(url),
+        _ => None,
+    };
+
+    let mut failed = false;
+
+    for payload in payloads {
+        let signature = signature_middleware::sign(payload.as_bytes(), &secret);
+
+        let result = match (&url, &data) {
+            (Some(url), _) => post(url, payload, &signature).await,
+            (None, Some(data)) => Ok(in_process(
+                data.clone(),
+                payload,
+                &secret,
+                &signature,
+                config.max_body_size,
+            )
+            .await),
+            (None, None) => Err("No target".to_owned()),
+        };
+
+        match result {
+            Ok((status, body)) => {
+                failed |= status >= 400;
+                println!("{} {}", status, body);
+            }
+            Err(error) => {
+                failed = true;
+                println!("Error: {}", error);
+            }
+        }
+    }
+
+    if failed {
+        process::exit(1);
+    }
+}
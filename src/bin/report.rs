@@ -0,0 +1,113 @@
+use std::env;
+use std::fs::File;
+use std::path::Path;
+use std::process;
+
+use chrono::NaiveDate;
+
+use actix_test::config::Config;
+use actix_test::{push, report, store, MyData};
+
+const USAGE: &str = "Usage: report <FROM> <TO> [--out <DIR>]
+
+FROM and TO are YYYY-MM-DD UTC dates, both inclusive. Gross, VAT, fees,
+payout and refunds of the users/*/transact/* documents in Firestore are
+summed by day, country and currency, then written to
+revenue_<FROM>_<TO>.csv and revenue_<FROM>_<TO>.json in DIR (the current
+directory by default).";
+
+fn exit_with_usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2)
+}
+
+fn parse_date(date: &str) -> NaiveDate {
+    NaiveDate::parse_from_str(date, "%Y-%m-%d").unwrap_or_else(|_| exit_with_usage())
+}
+
+fn parse_args() -> (NaiveDate, NaiveDate, String) {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    let (from, to, mut options) = match args.as_slice() {
+        [from, to, options @ ..] => (parse_date(from), parse_date(to), options),
+        _ => exit_with_usage(),
+    };
+
+    if to < from {
+        exit_with_usage();
+    }
+
+    let mut out = ".".to_owned();
+
+    loop {
+        options = match options {
+            [] => break,
+            [flag, dir, rest @ ..] if flag == "--out" => {
+                out = dir.clone();
+                rest
+            }
+            _ => exit_with_usage(),
+        };
+    }
+
+    (from, to, out)
+}
+
+fn create(path: &Path) -> File {
+    File::create(path).unwrap_or_else(|e| {
+        eprintln!("Trying to write {} Error: {}", path.display(), e);
+        process::exit(1)
+    })
+}
+
+#[actix_rt::main]
+async fn main() {
+    let (from, to, out) = parse_args();
+
+    let config = Config::load().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1)
+    });
+
+    let project_id = store::get_project_id(&config).await.unwrap_or_else(|e| {
+        eprintln!("Trying to get the project ID Error: {}", e);
+        process::exit(1)
+    });
+
+    let client = store::get_client(&config).await.unwrap_or_else(|e| {
+        eprintln!("Trying to connect to Firestore Error: {}", e);
+        process::exit(1)
+    });
+
+    let mut data = MyData {
+        project_id,
+        client: Box::new(client),
+        reject_unknown: config.reject_unknown_notifications,
+        hub: push::Hub::default(),
+        sku_currencies: config.virtual_currency_skus,
+    };
+
+    let rows = report::load_report(data.client.as_mut(), &data.project_id.clone(), from, to)
+        .await
+        .unwrap_or_else(|e| {
+            eprintln!("Trying to list transactions Error: {}", e);
+            process::exit(1)
+        });
+
+    let name = format!("revenue_{}_{}", from, to);
+
+    let csv = Path::new(&out).join(format!("{}.csv", name));
+    report::write_csv(&rows, create(&csv)).unwrap_or_else(|e| {
+        eprintln!("Trying to write {} Error: {}", csv.display(), e);
+        process::exit(1)
+    });
+
+    let json = Path::new(&out).join(format!("{}.json", name));
+    serde_json::to_writer_pretty(create(&json), &rows).unwrap_or_else(|e| {
+        eprintln!("Trying to write {} Error: {}", json.display(), e);
+        process::exit(1)
+    });
+
+    println!("{}", csv.display());
+    println!("{}", json.display());
+}
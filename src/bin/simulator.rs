@@ -0,0 +1,72 @@
+use std::env;
+use std::process;
+
+use actix_test::config::Config;
+use actix_test::simulator;
+
+const USAGE: &str = "Usage: simulator [--time-scale <FACTOR>]
+
+Plays a purchase lifecycle (user validation, payments, refunds, subscriptions)
+against an in-process server backed by a memory store. Notifications are signed
+with WEBHOOK_SECRET_KEY and resent on 5xx like Xsolla, with the real retry
+delays multiplied by FACTOR (0 by default).";
+
+const SIMULATOR_SECRET_KEY: &str = "Simulator1Secret2Key";
+
+fn parse_args() -> f64 {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    match args.as_slice() {
+        [] => 0.0,
+        [flag, factor] if flag == "--time-scale" => match factor.parse() {
+            Ok(factor) if factor >= 0.0 => factor,
+            _ => {
+                eprintln!("{}", USAGE);
+                process::exit(2)
+            }
+        },
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2)
+        }
+    }
+}
+
+#[actix_rt::main]
+async fn main() {
+    let time_scale = parse_args();
+
+    let config = Config::load().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1)
+    });
+
+    //Runs offline, no need for the real secret
+    let secret = config
+        .webhook_secret_key
+        .unwrap_or_else(|| SIMULATOR_SECRET_KEY.to_owned());
+
+    let outcomes = simulator::run(time_scale, &secret).await;
+
+    let mut failed = false;
+
+    for outcome in outcomes {
+        failed |= !outcome.passed;
+
+        println!(
+            "{} {} (attempts: {}, status: {}, credits: {})",
+            if outcome.passed { "PASS" } else { "FAIL" },
+            outcome.name,
+            outcome.attempts,
+            outcome.status,
+            outcome
+                .credits
+                .map(|credits| credits.to_string())
+                .unwrap_or_else(|| "-".to_owned()),
+        );
+    }
+
+    if failed {
+        process::exit(1);
+    }
+}
@@ -0,0 +1,903 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use ipnet::IpNet;
+
+use jsonwebtoken::DecodingKey;
+
+use crate::auth_middleware::Role;
+
+//Settings of the server and the CLIs, read once at startup.
+//Defaults, then the TOML file named by CONFIG_FILE, then environment variables.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub port: u16,
+    //HTTPS when both are set, reread every tls_reload_interval
+    pub tls_cert_file: Option<PathBuf>,
+    pub tls_key_file: Option<PathBuf>,
+    //Client certificates signed by these CAs are required on every route, not only /webhook:
+    //game clients on /balance, the game server on /spend, staff on /admin and probes on /healthz
+    //all need one too. The handshake is checked before the route is known, so only set this
+    //on a deployment that Xsolla alone reaches, IpWhiteList still guards /webhook without it.
+    pub tls_client_ca_file: Option<PathBuf>,
+    pub tls_reload_interval: Duration,
+    //How long notifications still running at SIGTERM get before they are aborted
+    pub shutdown_timeout: Duration,
+    //Local runs without Firestore, nothing is persisted
+    pub in_memory_store: bool,
+    //Asked to the metadata server when missing, which only answers on GCP
+    pub project_id: Option<String>,
+    //host:port of the Firestore emulator, plaintext and without credentials
+    pub firestore_emulator_host: Option<String>,
+    //Deadline for each attempt of a store call
+    pub store_timeout: Duration,
+    pub store_retries: u32,
+    pub store_retry_backoff: Duration,
+    pub store_breaker_threshold: u32,
+    pub store_breaker_cooldown: Duration,
+    pub webhook_secret_key: Option<String>,
+    pub ip_white_list: Vec<IpNet>,
+    //Notifications are a few KB, larger bodies are rejected before being hashed
+    pub max_body_size: usize,
+    pub reject_unknown_notifications: bool,
+    //Signed bodies seen within replay_ttl and payments dated outside replay_clock_skew are rejected
+    pub replay_protection: bool,
+    pub replay_ttl: Duration,
+    pub replay_clock_skew: Duration,
+    //Any other SKU credits Credits
+    pub virtual_currency_skus: HashMap<String, String>,
+    pub archive_file: Option<PathBuf>,
+    pub archive_retention: Duration,
+    pub outbox_endpoint: Option<String>,
+    pub outbox_poll_interval: Duration,
+    pub outbox_backoff: Duration,
+    pub otlp_endpoint: Option<String>,
+    pub otlp_schedule_delay: Duration,
+    pub service_name: String,
+    pub push_secret_key: Option<String>,
+    pub admin_api_keys: Vec<ApiKey>,
+    pub jwt_hs256_secret: Option<String>,
+    pub jwt_rs256_public_key: Option<DecodingKey<'static>>,
+    //Required with either JWT key, tokens minted for another service are refused
+    pub jwt_audience: Option<String>,
+    pub jwt_issuer: Option<String>,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct ApiKey {
+    pub subject: String,
+    pub role: Role,
+    pub key: String,
+}
+
+//Every problem found, not only the first one.
+#[derive(PartialEq, Debug)]
+pub struct ConfigError(pub Vec<String>);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Invalid configuration:")?;
+
+        for error in &self.0 {
+            writeln!(f, "  - {}", error)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[derive(PartialEq, Debug, Clone)]
+enum TomlValue {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<TomlValue>),
+}
+
+impl TomlValue {
+    fn type_name(&self) -> &'static str {
+        match self {
+            TomlValue::String(_) => "a string",
+            TomlValue::Integer(_) => "an integer",
+            TomlValue::Boolean(_) => "a boolean",
+            TomlValue::Array(_) => "an array",
+        }
+    }
+}
+
+//End of a value or header, only a comment may follow.
+fn line_end(rest: &str) -> Result<(), String> {
+    let rest = rest.trim_start();
+
+    if rest.is_empty() || rest.starts_with('#') {
+        Ok(())
+    } else {
+        Err(format!("unexpected {}", rest))
+    }
+}
+
+fn parse_string(s: &str) -> Result<(String, &str), String> {
+    let mut value = String::new();
+    let mut chars = s.char_indices().skip(1);
+
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((value, &s[i + 1..])),
+            '\\' => match chars.next() {
+                Some((_, 'n')) => value.push('\n'),
+                Some((_, 't')) => value.push('\t'),
+                Some((_, '"')) => value.push('"'),
+                Some((_, '\\')) => value.push('\\'),
+                _ => return Err("unsupported escape in string".to_owned()),
+            },
+            c => value.push(c),
+        }
+    }
+
+    Err("unterminated string".to_owned())
+}
+
+fn parse_literal_string(s: &str) -> Result<(String, &str), String> {
+    match s[1..].find('\'') {
+        Some(end) => Ok((s[1..end + 1].to_owned(), &s[end + 2..])),
+        None => Err("unterminated string".to_owned()),
+    }
+}
+
+//Bare or quoted segments joined by dots, as they are looked up.
+fn parse_key(s: &str) -> Result<(String, &str), String> {
+    let mut segments = Vec::new();
+    let mut rest = s.trim_start();
+
+    loop {
+        let (segment, after) = if rest.starts_with('"') {
+            parse_string(rest)?
+        } else if rest.starts_with('\'') {
+            parse_literal_string(rest)?
+        } else {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '-'))
+                .unwrap_or(rest.len());
+
+            if end == 0 {
+                return Err("missing key".to_owned());
+            }
+
+            (rest[..end].to_owned(), &rest[end..])
+        };
+
+        segments.push(segment);
+        rest = after.trim_start();
+
+        match rest.strip_prefix('.') {
+            Some(after) => rest = after.trim_start(),
+            None => return Ok((segments.join("."), rest)),
+        }
+    }
+}
+
+fn parse_value(s: &str) -> Result<(TomlValue, &str), String> {
+    if s.starts_with('"') {
+        let (value, rest) = parse_string(s)?;
+        return Ok((TomlValue::String(value), rest));
+    }
+
+    if s.starts_with('\'') {
+        let (value, rest) = parse_literal_string(s)?;
+        return Ok((TomlValue::String(value), rest));
+    }
+
+    if let Some(mut rest) = s.strip_prefix('[') {
+        let mut values = Vec::new();
+
+        loop {
+            rest = rest.trim_start();
+
+            if let Some(after) = rest.strip_prefix(']') {
+                return Ok((TomlValue::Array(values), after));
+            }
+
+            let (value, after) = parse_value(rest)?;
+            values.push(value);
+            rest = after.trim_start();
+
+            if let Some(after) = rest.strip_prefix(',') {
+                rest = after;
+            } else if !rest.starts_with(']') {
+                return Err("arrays must be on a single line".to_owned());
+            }
+        }
+    }
+
+    let end = s
+        .find(|c: char| c.is_whitespace() || c == ',' || c == ']' || c == '#')
+        .unwrap_or(s.len());
+
+    let (token, rest) = s.split_at(end);
+
+    match token {
+        "true" => Ok((TomlValue::Boolean(true), rest)),
+        "false" => Ok((TomlValue::Boolean(false), rest)),
+        _ => match token.replace('_', "").parse() {
+            Ok(integer) => Ok((TomlValue::Integer(integer), rest)),
+            Err(_) => Err(format!("unsupported value {}", token)),
+        },
+    }
+}
+
+//The subset of TOML the settings need: tables, strings, integers, booleans and single line arrays.
+fn parse_toml(text: &str) -> Result<BTreeMap<String, TomlValue>, String> {
+    let mut values = BTreeMap::new();
+    let mut table = String::new();
+
+    for (number, line) in text.lines().enumerate() {
+        let error = |message: String| format!("line {}: {}", number + 1, message);
+
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if line.starts_with("[[") {
+            return Err(error("arrays of tables are not supported".to_owned()));
+        }
+
+        if let Some(header) = line.strip_prefix('[') {
+            let (key, rest) = parse_key(header).map_err(error)?;
+
+            let rest = rest
+                .strip_prefix(']')
+                .ok_or_else(|| error("unterminated table header".to_owned()))?;
+
+            line_end(rest).map_err(error)?;
+            table = key;
+            continue;
+        }
+
+        let (key, rest) = parse_key(line).map_err(error)?;
+
+        let rest = rest
+            .strip_prefix('=')
+            .ok_or_else(|| error(format!("missing = after {}", key)))?;
+
+        let (value, rest) = parse_value(rest.trim_start()).map_err(error)?;
+        line_end(rest).map_err(error)?;
+
+        let key = if table.is_empty() {
+            key
+        } else {
+            format!("{}.{}", table, key)
+        };
+
+        if values.insert(key.clone(), value).is_some() {
+            return Err(error(format!("{} is set twice", key)));
+        }
+    }
+
+    Ok(values)
+}
+
+//Reads each setting from the environment first, then the file, and keeps every error.
+struct Loader<'a> {
+    file: BTreeMap<String, TomlValue>,
+    env: &'a HashMap<String, String>,
+    used: HashSet<String>,
+    errors: Vec<String>,
+}
+
+enum Raw {
+    Env(String),
+    File(TomlValue),
+}
+
+impl<'a> Loader<'a> {
+    fn raw(&mut self, key: &str, var: &str) -> Option<Raw> {
+        self.used.insert(key.to_owned());
+
+        if let Some(value) = self.env.get(var) {
+            return Some(Raw::Env(value.clone()));
+        }
+
+        self.file.get(key).cloned().map(Raw::File)
+    }
+
+    fn error(&mut self, key: &str, var: &str, message: impl fmt::Display) {
+        self.errors.push(format!("{} ({}): {}", key, var, message));
+    }
+
+    fn string(&mut self, key: &str, var: &str) -> Option<String> {
+        match self.raw(key, var)? {
+            Raw::Env(value) | Raw::File(TomlValue::String(value)) => Some(value),
+            Raw::File(value) => {
+                let message = format!("expected a string, found {}", value.type_name());
+                self.error(key, var, message);
+                None
+            }
+        }
+    }
+
+    fn boolean(&mut self, key: &str, var: &str, default: bool) -> bool {
+        match self.raw(key, var) {
+            None => default,
+            Some(Raw::File(TomlValue::Boolean(value))) => value,
+            Some(Raw::Env(value)) => match value.parse() {
+                Ok(value) => value,
+                Err(_) => {
+                    self.error(key, var, format!("{} is not true or false", value));
+                    default
+                }
+            },
+            Some(Raw::File(value)) => {
+                let message = format!("expected a boolean, found {}", value.type_name());
+                self.error(key, var, message);
+                default
+            }
+        }
+    }
+
+    fn integer(&mut self, key: &str, var: &str, default: u64) -> u64 {
+        let value = match self.raw(key, var) {
+            None => return default,
+            Some(Raw::File(TomlValue::Integer(value))) => value.to_string(),
+            Some(Raw::Env(value)) => value,
+            Some(Raw::File(value)) => {
+                let message = format!("expected an integer, found {}", value.type_name());
+                self.error(key, var, message);
+                return default;
+            }
+        };
+
+        match value.parse() {
+            Ok(value) => value,
+            Err(_) => {
+                self.error(key, var, format!("{} is not a positive integer", value));
+                default
+            }
+        }
+    }
+
+    fn millis(&mut self, key: &str, var: &str, default: u64) -> Duration {
+        Duration::from_millis(self.integer(key, var, default))
+    }
+
+    //a;b;c in the environment, an array of strings in the file
+    fn list(&mut self, key: &str, var: &str) -> Vec<String> {
+        match self.raw(key, var) {
+            None => Vec::new(),
+            Some(Raw::Env(value)) => value.split(';').map(str::to_owned).collect(),
+            Some(Raw::File(TomlValue::Array(values))) => {
+                let mut strings = Vec::with_capacity(values.len());
+
+                for value in values {
+                    match value {
+                        TomlValue::String(value) => strings.push(value),
+                        value => {
+                            let message = format!("expected strings, found {}", value.type_name());
+                            self.error(key, var, message);
+                        }
+                    }
+                }
+
+                strings
+            }
+            Some(Raw::File(value)) => {
+                let message = format!("expected an array, found {}", value.type_name());
+                self.error(key, var, message);
+                Vec::new()
+            }
+        }
+    }
+
+    //name:value;name:value in the environment, a [key] table of strings in the file
+    fn table(&mut self, key: &str, var: &str) -> HashMap<String, String> {
+        let mut table = HashMap::new();
+
+        let prefix = format!("{}.", key);
+
+        let entries: Vec<(String, TomlValue)> = self
+            .file
+            .range(prefix.clone()..)
+            .take_while(|(name, _)| name.starts_with(&prefix))
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+
+        for (name, _) in &entries {
+            self.used.insert(name.clone());
+        }
+
+        if let Some(value) = self.env.get(var).cloned() {
+            for entry in value.split(';') {
+                let mut parts = entry.splitn(2, ':');
+
+                match (parts.next(), parts.next()) {
+                    (Some(name), Some(value)) => {
+                        table.insert(name.to_owned(), value.to_owned());
+                    }
+                    _ => self.error(key, var, format!("{} is not name:value", entry)),
+                }
+            }
+
+            return table;
+        }
+
+        for (name, value) in entries {
+            match value {
+                TomlValue::String(value) => {
+                    table.insert(name[prefix.len()..].to_owned(), value);
+                }
+                value => {
+                    let message = format!("expected a string, found {}", value.type_name());
+                    self.error(&name, var, message);
+                }
+            }
+        }
+
+        table
+    }
+}
+
+fn parse_api_key(entry: &str) -> Result<ApiKey, String> {
+    let mut parts = entry.splitn(3, ':');
+
+    let (subject, role, key) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(subject), Some(role), Some(key)) => (subject, role, key),
+        _ => return Err("entries must be name:role:key".to_owned()),
+    };
+
+    let role = serde_json::from_value(serde_json::Value::String(role.to_owned()))
+        .map_err(|_| format!("{} is not a role", role))?;
+
+    Ok(ApiKey {
+        subject: subject.to_owned(),
+        role,
+        key: key.to_owned(),
+    })
+}
+
+impl Config {
+    //Xsolla settings may be missing, they are checked by load_server.
+    fn from_sources(
+        toml: &str,
+        env: &HashMap<String, String>,
+        server: bool,
+    ) -> Result<Config, ConfigError> {
+        let file = parse_toml(toml).map_err(|e| ConfigError(vec![format!("CONFIG_FILE {}", e)]))?;
+
+        let mut loader = Loader {
+            file,
+            env,
+            used: HashSet::new(),
+            errors: Vec::new(),
+        };
+
+        let port = loader.integer("port", "PORT", 8080);
+
+        let port = if port > u64::from(u16::MAX) {
+            loader.error("port", "PORT", format!("{} is not a port", port));
+            8080
+        } else {
+            port as u16
+        };
+
+        let shutdown_timeout = Duration::from_secs(loader.integer(
+            "shutdown_timeout_secs",
+            "SHUTDOWN_TIMEOUT_SECS",
+            //Cloud Run kills the instance 10 seconds after SIGTERM
+            8,
+        ));
+
+        let tls_cert_file = loader
+            .string("tls.cert_file", "TLS_CERT_FILE")
+            .map(PathBuf::from);
+        let tls_key_file = loader
+            .string("tls.key_file", "TLS_KEY_FILE")
+            .map(PathBuf::from);
+        let tls_client_ca_file = loader
+            .string("tls.client_ca_file", "TLS_CLIENT_CA_FILE")
+            .map(PathBuf::from);
+        let tls_reload_interval = Duration::from_secs(loader.integer(
+            "tls.reload_interval_secs",
+            "TLS_RELOAD_INTERVAL_SECS",
+            60,
+        ));
+
+        if tls_cert_file.is_some() != tls_key_file.is_some() {
+            loader.error(
+                "tls.cert_file",
+                "TLS_CERT_FILE",
+                "and tls.key_file (TLS_KEY_FILE) must be set together",
+            );
+        }
+
+        if tls_client_ca_file.is_some() && tls_cert_file.is_none() {
+            loader.error(
+                "tls.client_ca_file",
+                "TLS_CLIENT_CA_FILE",
+                "requires tls.cert_file (TLS_CERT_FILE)",
+            );
+        }
+
+        let in_memory_store = loader.boolean("in_memory_store", "IN_MEMORY_STORE", false);
+
+        let project_id = loader.string("firestore.project_id", "GOOGLE_CLOUD_PROJECT");
+
+        let firestore_emulator_host =
+            loader.string("firestore.emulator_host", "FIRESTORE_EMULATOR_HOST");
+
+        if let Some(host) = &firestore_emulator_host {
+            if host.contains("://") || !host.contains(':') {
+                loader.error(
+                    "firestore.emulator_host",
+                    "FIRESTORE_EMULATOR_HOST",
+                    format!("{} is not host:port", host),
+                );
+            }
+        }
+
+        let store_timeout = loader.millis("store.timeout_ms", "STORE_TIMEOUT_MS", 5000);
+        let store_retries = loader.integer("store.retries", "STORE_RETRIES", 2) as u32;
+        let store_retry_backoff =
+            loader.millis("store.retry_backoff_ms", "STORE_RETRY_BACKOFF_MS", 100);
+
+        //Failed calls in a row, retries included, before calls fail fast
+        let store_breaker_threshold =
+            loader.integer("store.breaker_threshold", "STORE_BREAKER_THRESHOLD", 5) as u32;
+        let store_breaker_cooldown = loader.millis(
+            "store.breaker_cooldown_ms",
+            "STORE_BREAKER_COOLDOWN_MS",
+            30000,
+        );
+
+        if store_breaker_threshold == 0 {
+            loader.error(
+                "store.breaker_threshold",
+                "STORE_BREAKER_THRESHOLD",
+                "must be at least 1",
+            );
+        }
+
+        let webhook_secret_key = loader.string("xsolla.webhook_secret_key", "WEBHOOK_SECRET_KEY");
+
+        match &webhook_secret_key {
+            Some(secret) if secret.len() != 20 => loader.error(
+                "xsolla.webhook_secret_key",
+                "WEBHOOK_SECRET_KEY",
+                "must be 20 characters long",
+            ),
+            None if server => loader.error(
+                "xsolla.webhook_secret_key",
+                "WEBHOOK_SECRET_KEY",
+                "is required",
+            ),
+            _ => {}
+        }
+
+        //185.30.20.0/24;185.30.21.0/24;...etc
+        let mut ip_white_list = Vec::new();
+
+        for network in loader.list("xsolla.ip_white_list", "IP_WHITE_LIST") {
+            match network.parse() {
+                Ok(network) => ip_white_list.push(network),
+                Err(_) => loader.error(
+                    "xsolla.ip_white_list",
+                    "IP_WHITE_LIST",
+                    format!("{} is not a network", network),
+                ),
+            }
+        }
+
+        if server && ip_white_list.is_empty() {
+            loader.error(
+                "xsolla.ip_white_list",
+                "IP_WHITE_LIST",
+                "is required, no notification would be accepted",
+            );
+        }
+
+        let max_body_size =
+            loader.integer("xsolla.max_body_bytes", "MAX_BODY_BYTES", 64 * 1024) as usize;
+
+        if max_body_size == 0 {
+            loader.error(
+                "xsolla.max_body_bytes",
+                "MAX_BODY_BYTES",
+                "must be at least 1",
+            );
+        }
+
+        //Unknown notifications are acknowledged by default, Xsolla would retry them forever otherwise
+        let reject_unknown_notifications = loader.boolean(
+            "xsolla.reject_unknown_notifications",
+            "REJECT_UNKNOWN_NOTIFICATIONS",
+            false,
+        );
+
+        let replay_protection =
+            loader.boolean("replay_protection.enabled", "REPLAY_PROTECTION", false);
+        let replay_ttl = Duration::from_secs(loader.integer(
+            "replay_protection.ttl_secs",
+            "REPLAY_TTL_SECS",
+            600,
+        ));
+        //Xsolla keeps resending a failed payment for hours, with its original payment_date
+        let replay_clock_skew = Duration::from_secs(loader.integer(
+            "replay_protection.clock_skew_secs",
+            "REPLAY_CLOCK_SKEW_SECS",
+            24 * 60 * 60,
+        ));
+
+        let virtual_currency_skus =
+            loader.table("xsolla.virtual_currency_skus", "VIRTUAL_CURRENCY_SKUS");
+
+        let archive_file = loader
+            .string("archive.file", "ARCHIVE_FILE")
+            .map(PathBuf::from);
+
+        let days = loader.integer("archive.retention_days", "ARCHIVE_RETENTION_DAYS", 365);
+        let archive_retention = Duration::from_secs(days * 24 * 60 * 60);
+
+        //Events are always written, they are only delivered once the endpoint is set
+        let outbox_endpoint = loader.string("outbox.endpoint", "OUTBOX_ENDPOINT");
+        let outbox_poll_interval =
+            loader.millis("outbox.poll_interval_ms", "OUTBOX_POLL_INTERVAL_MS", 1000);
+        let outbox_backoff = loader.millis("outbox.backoff_ms", "OUTBOX_BACKOFF_MS", 1000);
+
+        //Spans are only recorded and sent once the endpoint is set
+        let otlp_endpoint = loader.string("telemetry.otlp_endpoint", "OTEL_EXPORTER_OTLP_ENDPOINT");
+        let otlp_schedule_delay = loader.millis(
+            "telemetry.schedule_delay_ms",
+            "OTEL_BSP_SCHEDULE_DELAY",
+            5000,
+        );
+        let service_name = loader
+            .string("telemetry.service_name", "OTEL_SERVICE_NAME")
+            .unwrap_or_else(|| env!("CARGO_PKG_NAME").to_owned());
+
+        //Without a key no client can subscribe
+        let push_secret_key = loader.string("push.secret_key", "PUSH_SECRET_KEY");
+
+        let mut admin_api_keys = Vec::new();
+
+        for entry in loader.list("admin.api_keys", "ADMIN_API_KEYS") {
+            match parse_api_key(&entry) {
+                Ok(key) => admin_api_keys.push(key),
+                Err(e) => loader.error("admin.api_keys", "ADMIN_API_KEYS", e),
+            }
+        }
+
+        let jwt_hs256_secret = loader.string("admin.jwt_hs256_secret", "JWT_HS256_SECRET");
+
+        let jwt_rs256_public_key = match loader.string(
+            "admin.jwt_rs256_public_key_file",
+            "JWT_RS256_PUBLIC_KEY_FILE",
+        ) {
+            Some(path) => match fs::read(&path) {
+                Ok(pem) => match DecodingKey::from_rsa_pem(&pem) {
+                    Ok(key) => Some(key.into_static()),
+                    Err(_) => {
+                        loader.error(
+                            "admin.jwt_rs256_public_key_file",
+                            "JWT_RS256_PUBLIC_KEY_FILE",
+                            format!("{} is not an RSA public key", path),
+                        );
+                        None
+                    }
+                },
+                Err(e) => {
+                    loader.error(
+                        "admin.jwt_rs256_public_key_file",
+                        "JWT_RS256_PUBLIC_KEY_FILE",
+                        format!("{} {}", path, e),
+                    );
+                    None
+                }
+            },
+            None => None,
+        };
+
+        let jwt_audience = loader.string("admin.jwt_audience", "JWT_AUDIENCE");
+        let jwt_issuer = loader.string("admin.jwt_issuer", "JWT_ISSUER");
+
+        if jwt_hs256_secret.is_some() || jwt_rs256_public_key.is_some() {
+            if jwt_audience.is_none() {
+                loader.error(
+                    "admin.jwt_audience",
+                    "JWT_AUDIENCE",
+                    "required with a JWT key",
+                );
+            }
+
+            if jwt_issuer.is_none() {
+                loader.error("admin.jwt_issuer", "JWT_ISSUER", "required with a JWT key");
+            }
+        }
+
+        //A typo would otherwise silently leave the default in place
+        let unknown: Vec<String> = loader
+            .file
+            .keys()
+            .filter(|key| !loader.used.contains(*key))
+            .map(|key| format!("{}: unknown setting", key))
+            .collect();
+
+        loader.errors.extend(unknown);
+
+        if !loader.errors.is_empty() {
+            return Err(ConfigError(loader.errors));
+        }
+
+        Ok(Config {
+            port,
+            tls_cert_file,
+            tls_key_file,
+            tls_client_ca_file,
+            tls_reload_interval,
+            shutdown_timeout,
+            in_memory_store,
+            project_id,
+            firestore_emulator_host,
+            store_timeout,
+            store_retries,
+            store_retry_backoff,
+            store_breaker_threshold,
+            store_breaker_cooldown,
+            webhook_secret_key,
+            ip_white_list,
+            max_body_size,
+            reject_unknown_notifications,
+            replay_protection,
+            replay_ttl,
+            replay_clock_skew,
+            virtual_currency_skus,
+            archive_file,
+            archive_retention,
+            outbox_endpoint,
+            outbox_poll_interval,
+            outbox_backoff,
+            otlp_endpoint,
+            otlp_schedule_delay,
+            service_name,
+            push_secret_key,
+            admin_api_keys,
+            jwt_hs256_secret,
+            jwt_rs256_public_key,
+            jwt_audience,
+            jwt_issuer,
+        })
+    }
+
+    fn load_with(server: bool) -> Result<Config, ConfigError> {
+        let env: HashMap<String, String> = env::vars().collect();
+
+        let toml = match env.get("CONFIG_FILE") {
+            Some(path) => fs::read_to_string(path)
+                .map_err(|e| ConfigError(vec![format!("CONFIG_FILE {} {}", path, e)]))?,
+            None => String::new(),
+        };
+
+        Config::from_sources(&toml, &env, server)
+    }
+
+    //For the CLIs, the Xsolla secret and white list are optional.
+    pub fn load() -> Result<Config, ConfigError> {
+        Config::load_with(false)
+    }
+
+    //The webhook server cannot accept anything without the Xsolla secret and white list.
+    pub fn load_server() -> Result<Config, ConfigError> {
+        Config::load_with(true)
+    }
+}
+
+//Every setting at its default, for tests and local runs.
+impl Default for Config {
+    fn default() -> Self {
+        Config::from_sources("", &HashMap::new(), false).expect("Trying to build defaults Error: ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(vars: &[(&str, &str)]) -> HashMap<String, String> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    const TOML: &str = r#"
+port = 9000 # local
+
+[xsolla]
+webhook_secret_key = "Ultra1Top2Secret3Key"
+ip_white_list = ["185.30.20.0/24", "185.30.21.0/24"]
+
+[xsolla.virtual_currency_skus]
+gems_pack = "GEMS"
+"gold.pack" = 'GOLD'
+
+[admin]
+api_keys = ["ops:admin:k1"]
+"#;
+
+    #[test]
+    fn file_then_env() {
+        let config = Config::from_sources(TOML, &env(&[("PORT", "8081")]), true).unwrap();
+
+        assert_eq!(config.port, 8081);
+        assert_eq!(
+            config.webhook_secret_key.as_deref(),
+            Some("Ultra1Top2Secret3Key")
+        );
+        assert_eq!(config.ip_white_list.len(), 2);
+        assert_eq!(config.virtual_currency_skus["gems_pack"], "GEMS");
+        assert_eq!(config.virtual_currency_skus["gold.pack"], "GOLD");
+        assert_eq!(config.admin_api_keys[0].role, Role::Admin);
+        assert_eq!(
+            config.archive_retention,
+            Duration::from_secs(365 * 24 * 60 * 60)
+        );
+
+        //The environment replaces a table as a whole
+        let config = Config::from_sources(
+            TOML,
+            &env(&[
+                ("VIRTUAL_CURRENCY_SKUS", "coins:COINS"),
+                ("IP_WHITE_LIST", "10.0.0.0/8"),
+            ]),
+            true,
+        )
+        .unwrap();
+
+        assert_eq!(config.virtual_currency_skus.len(), 1);
+        assert_eq!(
+            config.ip_white_list,
+            vec!["10.0.0.0/8".parse::<IpNet>().unwrap()]
+        );
+    }
+
+    #[test]
+    fn errors_aggregated() {
+        let toml = "port = \"80\"\n[xsolla]\nip_whitelist = []\n";
+
+        let error = Config::from_sources(
+            toml,
+            &env(&[
+                ("IP_WHITE_LIST", "185.30.20.0/24;nope"),
+                ("ADMIN_API_KEYS", "ops:root:k1"),
+                ("JWT_HS256_SECRET", "Jwt1Secret2Key3Test"),
+                ("JWT_ISSUER", "https://auth.example.com"),
+            ]),
+            true,
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            error.0,
+            vec![
+                "port (PORT): expected an integer, found a string",
+                "xsolla.webhook_secret_key (WEBHOOK_SECRET_KEY): is required",
+                "xsolla.ip_white_list (IP_WHITE_LIST): nope is not a network",
+                "admin.api_keys (ADMIN_API_KEYS): root is not a role",
+                "admin.jwt_audience (JWT_AUDIENCE): required with a JWT key",
+                "xsolla.ip_whitelist: unknown setting",
+            ]
+        );
+
+        assert_eq!(
+            Config::from_sources("port 80", &HashMap::new(), false).unwrap_err(),
+            ConfigError(vec!["CONFIG_FILE line 1: missing = after port".to_owned()])
+        );
+
+        //The CLIs run without the Xsolla settings
+        assert!(Config::from_sources("", &HashMap::new(), false).is_ok());
+    }
+}
@@ -1,20 +1,174 @@
-use std::collections::HashMap;
-use std::sync::Mutex;
+use std::collections::{BTreeMap, HashMap};
+use std::time::Instant;
 
+use actix_web::http::StatusCode;
 use actix_web::post;
 use actix_web::{web, HttpResponse, Responder};
 
+use crate::logging::{Line, RequestId};
+use crate::metrics;
 use crate::models::Error;
 use crate::models::ErrorMessage;
 use crate::models::Message;
+use crate::models::{Item, PaymentDetails, VirtualItems};
+use crate::money::Money;
+use crate::outbox::{self, Event};
+use crate::push::Update;
+use crate::shutdown::{self, InFlight};
+use crate::telemetry::{Span, SpanContext, SpanKind};
 use crate::MyData;
 
+use chrono::DateTime;
+
 use firestore_grpc_cloudrun::{
-    value::ValueType, CreateDocumentRequest, Document, DocumentMask, GetDocumentRequest,
-    UpdateDocumentRequest, Value,
+    precondition::ConditionType, value::ValueType, write::Operation, CommitRequest,
+    CreateDocumentRequest, Document, DocumentMask, GetDocumentRequest, MapValue, Precondition,
+    Value, Write,
 };
 
-use tonic::Code;
+use futures::lock::Mutex;
+
+use tonic::{Code, Status};
+
+fn map_value(fields: HashMap<String, Value>) -> Value {
+    Value {
+        value_type: Some(ValueType::MapValue(MapValue { fields })),
+    }
+}
+
+//Decimal strings, a double would round 9.99
+fn money(money: Money) -> Value {
+    let mut fields = HashMap::with_capacity(2);
+
+    fields.insert(
+        "Amount".to_owned(),
+        Value {
+            value_type: Some(ValueType::StringValue(money.to_decimal())),
+        },
+    );
+
+    fields.insert(
+        "Currency".to_owned(),
+        Value {
+            value_type: Some(ValueType::StringValue(money.currency)),
+        },
+    );
+
+    map_value(fields)
+}
+
+//Kept for the revenue report, names follow the Xsolla fields.
+fn payment_details(details: PaymentDetails) -> Value {
+    let mut fields = HashMap::with_capacity(7);
+
+    let amounts = vec![
+        ("Payment", details.payment),
+        ("Vat", details.vat),
+        ("Payout", details.payout),
+        ("XsollaFee", details.xsolla_fee),
+        ("PaymentMethodFee", details.payment_method_fee),
+        ("RepatriationCommission", details.repatriation_commission),
+    ];
+
+    for (name, amount) in amounts {
+        if let Some(amount) = amount {
+            fields.insert(name.to_owned(), money(amount));
+        }
+    }
+
+    if let Some(rate) = details.payout_currency_rate {
+        fields.insert(
+            "PayoutCurrencyRate".to_owned(),
+            Value {
+                value_type: Some(ValueType::DoubleValue(rate)),
+            },
+        );
+    }
+
+    //Kept as sent for a later look, the report skips them
+    if !details.invalid.is_empty() {
+        let invalid = details
+            .invalid
+            .into_iter()
+            .map(|(field, value)| {
+                let value = Value {
+                    value_type: Some(ValueType::StringValue(value.to_string())),
+                };
+
+                (field, value)
+            })
+            .collect();
+
+        fields.insert("Invalid".to_owned(), map_value(invalid));
+    }
+
+    map_value(fields)
+}
+
+//Xsolla sends RFC 3339 dates, the processing time is used if it is missing
+fn payment_date(date: &Option<String>) -> prost_types::Timestamp {
+    match date.as_ref().map(|date| DateTime::parse_from_rfc3339(date)) {
+        Some(Ok(date)) => prost_types::Timestamp {
+            seconds: date.timestamp(),
+            nanos: date.timestamp_subsec_nanos() as i32,
+        },
+        _ => prost_types::Timestamp::from(std::time::SystemTime::now()),
+    }
+}
+
+pub(crate) fn balance_field(currency: &Option<String>) -> String {
+    match currency {
+        Some(_) => "Balances".to_owned(),
+        None => "Credits".to_owned(),
+    }
+}
+
+//Adds quantity to the currency balance or to Credits, returns the new balance
+pub(crate) fn credit(user_doc: &mut Document, currency: &Option<String>, quantity: i64) -> i64 {
+    let currency = match currency {
+        Some(currency) => currency,
+        None => {
+            let mut balance = 0;
+
+            if let Some(credits) = user_doc.fields.get_mut("Credits") {
+                if let Some(ValueType::IntegerValue(credits)) = credits.value_type.as_mut() {
+                    *credits += quantity;
+                    balance = *credits;
+                }
+            }
+
+            return balance;
+        }
+    };
+
+    let balances = user_doc
+        .fields
+        .entry("Balances".to_owned())
+        .or_insert_with(|| map_value(HashMap::new()));
+
+    if let Some(ValueType::MapValue(map)) = balances.value_type.as_mut() {
+        let balance = map.fields.entry(currency.clone()).or_insert(Value {
+            value_type: Some(ValueType::IntegerValue(0)),
+        });
+
+        if let Some(ValueType::IntegerValue(balance)) = balance.value_type.as_mut() {
+            *balance += quantity;
+            return *balance;
+        }
+    }
+
+    0
+}
+
+pub(crate) fn balance_update(currency: Option<String>, balance: i64) -> Update {
+    match currency {
+        Some(currency) => Update::CurrencyBalance {
+            currency,
+            amount: balance,
+        },
+        None => Update::Balance { credits: balance },
+    }
+}
 
 const USER_ERROR: ErrorMessage = ErrorMessage {
     error: Error {
@@ -30,19 +184,147 @@ const INCORRECT_INVOICE: ErrorMessage = ErrorMessage {
     },
 };
 
+const UNKNOWN_NOTIFICATION: ErrorMessage = ErrorMessage {
+    error: Error {
+        code: "INVALID_PARAMETER",
+        message: "Unknown notification type",
+    },
+};
+
+const CONFLICT_ERROR: ErrorMessage = ErrorMessage {
+    error: Error {
+        code: "BALANCE_CHANGED",
+        message: "The balance changed during the notification, retry later",
+    },
+};
+
+const STORE_UNAVAILABLE: ErrorMessage = ErrorMessage {
+    error: Error {
+        code: "SERVICE_UNAVAILABLE",
+        message: "Store unavailable, retry later",
+    },
+};
+
+//Xsolla resends on any 5xx, a 503 also tells whoever reads the logs that the store is down.
+fn store_error(line: &mut Line, status: &Status) -> HttpResponse {
+    line.error = Some(status.to_string());
+
+    match status.code() {
+        Code::Unavailable | Code::DeadlineExceeded => {
+            HttpResponse::ServiceUnavailable().json(STORE_UNAVAILABLE)
+        }
+        //Only commits have preconditions, the notification is handled again from a new read
+        Code::FailedPrecondition => HttpResponse::Conflict().json(CONFLICT_ERROR),
+        _ => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+//For handlers without a line of their own
+pub(crate) fn log_store_error(request_id: &RequestId, status: &Status) -> HttpResponse {
+    let mut line = Line::new("store error", request_id);
+
+    let resp = store_error(&mut line, status);
+
+    line.finish(resp.status());
+    line.write();
+
+    resp
+}
+
 #[post("/webhook")]
 async fn notifications(
     firestore: web::Data<Mutex<MyData>>,
     notif: web::Json<Message>,
+    request_id: RequestId,
+    trace: SpanContext,
 ) -> impl Responder {
-    let firestore = firestore.lock();
-    let mut firestore = match firestore {
-        Ok(firestore) => firestore,
-        Err(_) => return HttpResponse::InternalServerError().finish(),
+    let start = Instant::now();
+
+    let mut span = Span::start("notifications", SpanKind::Server, &trace);
+
+    let message = notif.into_inner();
+
+    //Unknown types are not used as labels, anyone can send any type
+    let kind = match &message {
+        Message::UserValidation { .. } => "user_validation",
+        Message::Payment { .. } => "payment",
+        Message::Refund { .. } => "refund",
+        Message::Unknown { .. } => "unknown",
+    };
+
+    let (user_id, transaction_id) = match &message {
+        Message::UserValidation { user } => (Some(user.id.clone()), None),
+        Message::Payment {
+            user, transaction, ..
+        }
+        | Message::Refund {
+            user, transaction, ..
+        } => (Some(user.id.clone()), Some(transaction.id)),
+        Message::Unknown { .. } => (None, None),
     };
 
-    match notif.into_inner() {
+    let guard = shutdown::track(InFlight {
+        request_id: request_id.clone(),
+        notification_type: kind,
+        user_id,
+        transaction_id,
+        started: start,
+    });
+
+    //Every notification waits here for the one before it
+    let lock = Span::start("lock", SpanKind::Internal, &span.context());
+    let mut firestore = firestore.lock().await;
+    lock.end();
+
+    let mut line = Line::new("notification", &request_id);
+
+    firestore.client.set_trace_parent(Some(span.context()));
+    let mut resp = handle(&mut firestore, message.clone(), &mut line).await;
+
+    //Another instance changed the balance between the read and the commit
+    if resp.status() == StatusCode::CONFLICT {
+        line.error = None;
+        resp = handle(&mut firestore, message, &mut line).await;
+    }
+    firestore.client.set_trace_parent(None);
+
+    line.finish(resp.status());
+    line.write();
+
+    span.set_attribute("notification_type", kind);
+    span.set_attribute("http.status_code", resp.status().as_u16());
+
+    if let Some(user_id) = &line.user_id {
+        span.set_attribute("user_id", user_id);
+    }
+    if let Some(transaction_id) = line.transaction_id {
+        span.set_attribute("transaction_id", transaction_id);
+    }
+    if let Some(error) = &line.error {
+        span.set_error(error);
+    }
+
+    span.end();
+
+    metrics::NOTIFICATIONS
+        .with_label_values(&[kind, line.outcome.unwrap_or_default()])
+        .inc();
+    metrics::NOTIFICATION_DURATION
+        .with_label_values(&[kind])
+        .observe(start.elapsed().as_secs_f64());
+
+    guard.finish();
+
+    resp
+}
+
+//Fills in what the log line needs along the way, the tonic Status included.
+async fn handle(firestore: &mut MyData, message: Message, line: &mut Line) -> HttpResponse {
+    match message {
         Message::UserValidation { user } => {
+            line.notification_type = Some("user_validation".to_owned());
+            line.user_id = Some(user.id.clone());
+
             let req = GetDocumentRequest {
                 name: format!(
                     "projects/{}/databases/(default)/documents/users/{}",
@@ -56,7 +338,7 @@ async fn notifications(
                 if let Code::NotFound = error.code() {
                     return HttpResponse::BadRequest().json(USER_ERROR);
                 } else {
-                    return HttpResponse::InternalServerError().finish();
+                    return store_error(line, &error);
                 }
             }
 
@@ -66,26 +348,38 @@ async fn notifications(
             purchase,
             user,
             transaction,
+            payment_details: details,
         } => {
+            line.notification_type = Some("payment".to_owned());
+            line.user_id = Some(user.id.clone());
+            line.transaction_id = Some(transaction.id);
+
+            let currency = purchase
+                .virtual_currency
+                .sku
+                .as_ref()
+                .and_then(|sku| firestore.sku_currencies.get(sku))
+                .cloned();
+
             let req = GetDocumentRequest {
                 name: format!(
                     "projects/{}/databases/(default)/documents/users/{}",
                     firestore.project_id, user.id
                 ),
                 mask: Some(DocumentMask {
-                    field_paths: vec!["Credits".to_owned()],
+                    field_paths: vec![balance_field(&currency)],
                 }),
                 consistency_selector: None,
             };
 
             let user_doc = firestore.client.get_document(req).await;
             let mut user_doc = match user_doc {
-                Ok(user_doc) => user_doc.into_inner(),
+                Ok(user_doc) => user_doc,
                 Err(error) => {
                     if let Code::NotFound = error.code() {
                         return HttpResponse::BadRequest().json(USER_ERROR);
                     } else {
-                        return HttpResponse::InternalServerError().finish();
+                        return store_error(line, &error);
                     }
                 }
             };
@@ -100,23 +394,28 @@ async fn notifications(
             };
 
             //transaction already processed do nothing
-            if firestore.client.get_document(req).await.is_ok() {
-                return HttpResponse::Ok().finish();
+            match firestore.client.get_document(req).await {
+                Ok(_) => return HttpResponse::Ok().finish(),
+                Err(error) if error.code() == Code::NotFound => {}
+                Err(error) => return store_error(line, &error),
             }
 
-            let mut data: HashMap<String, Value> = HashMap::with_capacity(3);
+            let mut data: HashMap<String, Value> = HashMap::with_capacity(6);
+
+            let price = &purchase.virtual_currency.price;
 
             data.insert(
                 "Currency".to_owned(),
                 Value {
-                    value_type: Some(ValueType::StringValue(purchase.virtual_currency.currency)),
+                    value_type: Some(ValueType::StringValue(price.currency.clone())),
                 },
             );
 
+            //Older documents have the cost in whole units as an integer
             data.insert(
                 "Cost".to_owned(),
                 Value {
-                    value_type: Some(ValueType::IntegerValue(purchase.virtual_currency.amount)),
+                    value_type: Some(ValueType::StringValue(price.to_decimal())),
                 },
             );
 
@@ -127,6 +426,38 @@ async fn notifications(
                 },
             );
 
+            data.insert(
+                "PaymentDate".to_owned(),
+                Value {
+                    value_type: Some(ValueType::TimestampValue(payment_date(
+                        &transaction.payment_date,
+                    ))),
+                },
+            );
+
+            if let Some(country) = user.country {
+                data.insert(
+                    "Country".to_owned(),
+                    Value {
+                        value_type: Some(ValueType::StringValue(country)),
+                    },
+                );
+            }
+
+            if let Some(details) = details {
+                data.insert("PaymentDetails".to_owned(), payment_details(details));
+            }
+
+            //The refund reverses this balance even if the SKU mapping changed since
+            if let Some(currency) = currency.clone() {
+                data.insert(
+                    "VirtualCurrency".to_owned(),
+                    Value {
+                        value_type: Some(ValueType::StringValue(currency)),
+                    },
+                );
+            }
+
             let doc = Document {
                 name: format!(
                     "projects/{}/databases/(default)/documents/users/{}/transact/{}",
@@ -137,43 +468,95 @@ async fn notifications(
                 update_time: None,
             };
 
-            let req = CreateDocumentRequest {
-                parent: format!(
-                    "projects/{}/databases/(default)/documents/users/{}",
-                    firestore.project_id, user.id
-                ),
-                collection_id: "transact".to_owned(),
-                document_id: transaction.id.to_string(),
-                document: Some(doc),
-                mask: None,
-            };
-
-            if firestore.client.create_document(req).await.is_err() {
-                return HttpResponse::InternalServerError().finish();
-            }
-
             //Increment credit in user document
-            if let Some(credits) = user_doc.fields.get_mut("Credits") {
-                if let Some(credits) = credits.value_type.as_mut() {
-                    if let ValueType::IntegerValue(credits) = credits {
-                        *credits += purchase.virtual_currency.quantity
+            let balance = credit(&mut user_doc, &currency, purchase.virtual_currency.quantity);
+
+            let mut writes = vec![
+                Write {
+                    update_mask: None,
+                    update_transforms: Vec::new(),
+                    current_document: Some(Precondition {
+                        condition_type: Some(ConditionType::Exists(false)),
+                    }),
+                    operation: Some(Operation::Update(doc)),
+                },
+                Write {
+                    update_mask: Some(DocumentMask {
+                        field_paths: vec![balance_field(&currency)],
+                    }),
+                    update_transforms: Vec::new(),
+                    //Fails if a spend changed the balance since it was read
+                    current_document: Some(Precondition {
+                        condition_type: user_doc.update_time.clone().map(ConditionType::UpdateTime),
+                    }),
+                    operation: Some(Operation::Update(user_doc)),
+                },
+            ];
+
+            let mut events = vec![Event::CreditsGranted {
+                user_id: user.id.clone(),
+                transaction_id: transaction.id,
+                currency: currency.clone(),
+                quantity: purchase.virtual_currency.quantity,
+            }];
+
+            if let Some(VirtualItems {
+                items: Some(items), ..
+            }) = purchase.virtual_items
+            {
+                //One event per SKU, its id is derived from the SKU
+                let mut granted: BTreeMap<String, i64> = BTreeMap::new();
+
+                for item in items {
+                    if let Item {
+                        sku: Some(sku),
+                        amount: Some(quantity),
+                    } = item
+                    {
+                        *granted.entry(sku).or_default() += quantity;
                     }
                 }
+
+                for (sku, quantity) in granted {
+                    events.push(Event::ItemGranted {
+                        user_id: user.id.clone(),
+                        transaction_id: transaction.id,
+                        sku,
+                        quantity,
+                    });
+                }
             }
 
-            let req = UpdateDocumentRequest {
-                document: Some(user_doc),
-                update_mask: Some(DocumentMask {
-                    field_paths: vec!["Credits".to_owned()],
-                }),
-                mask: Some(DocumentMask {
-                    field_paths: vec!["Credits".to_owned()],
-                }),
-                current_document: None,
+            for event in &events {
+                writes.push(outbox::write(&firestore.project_id, event));
+            }
+
+            //Transaction, credits and events are written together or not at all
+            let req = CommitRequest {
+                database: format!("projects/{}/databases/(default)", firestore.project_id),
+                writes,
+                transaction: Vec::new(),
             };
 
-            if firestore.client.update_document(req).await.is_err() {
-                return HttpResponse::InternalServerError().finish();
+            if let Err(status) = firestore.client.commit(req).await {
+                return store_error(line, &status);
+            }
+
+            metrics::CREDITS_GRANTED
+                .with_label_values(&[metrics::currency_label(&currency)])
+                .inc_by(purchase.virtual_currency.quantity.max(0) as u64);
+
+            //Only pushed once committed, a failed commit is retried by Xsolla
+            firestore
+                .hub
+                .publish(&user.id, &balance_update(currency, balance));
+
+            for event in events {
+                if let Event::ItemGranted { sku, quantity, .. } = event {
+                    firestore
+                        .hub
+                        .publish(&user.id, &Update::ItemGranted { sku, quantity });
+                }
             }
 
             HttpResponse::Ok().finish()
@@ -183,26 +566,31 @@ async fn notifications(
             user,
             transaction,
             refund_details,
+            ..
         } => {
+            line.notification_type = Some("refund".to_owned());
+            line.user_id = Some(user.id.clone());
+            line.transaction_id = Some(transaction.id);
+
             let req = GetDocumentRequest {
                 name: format!(
                     "projects/{}/databases/(default)/documents/users/{}",
                     firestore.project_id, user.id
                 ),
                 mask: Some(DocumentMask {
-                    field_paths: vec!["Credits".to_owned()],
+                    field_paths: vec!["Credits".to_owned(), "Balances".to_owned()],
                 }),
                 consistency_selector: None,
             };
 
             let user_doc = firestore.client.get_document(req).await;
             let mut user_doc = match user_doc {
-                Ok(user_doc) => user_doc.into_inner(),
+                Ok(user_doc) => user_doc,
                 Err(error) => {
                     if let Code::NotFound = error.code() {
                         return HttpResponse::BadRequest().json(USER_ERROR);
                     } else {
-                        return HttpResponse::InternalServerError().finish();
+                        return store_error(line, &error);
                     }
                 }
             };
@@ -218,16 +606,21 @@ async fn notifications(
 
             let transact_doc = firestore.client.get_document(req).await;
             let mut transact_doc = match transact_doc {
-                Ok(transact_doc) => transact_doc.into_inner(),
+                Ok(transact_doc) => transact_doc,
                 Err(error) => {
                     if let Code::NotFound = error.code() {
                         return HttpResponse::BadRequest().json(INCORRECT_INVOICE);
                     } else {
-                        return HttpResponse::InternalServerError().finish();
+                        return store_error(line, &error);
                     }
                 }
             };
 
+            //refund already processed do nothing
+            if transact_doc.fields.contains_key("RefundDate") {
+                return HttpResponse::Ok().finish();
+            }
+
             transact_doc.fields.insert(
                 "RefundDate".to_owned(),
                 Value {
@@ -244,46 +637,324 @@ async fn notifications(
                 },
             );
 
-            let req = UpdateDocumentRequest {
-                document: Some(transact_doc),
-                update_mask: Some(DocumentMask {
-                    field_paths: vec!["RefundDate".to_owned(), "RefundCode".to_owned()],
-                }),
-                mask: Some(DocumentMask {
-                    field_paths: vec!["RefundDate".to_owned(), "RefundCode".to_owned()],
-                }),
-                current_document: None,
+            //Whatever the payment credited, not what the SKU maps to today
+            let currency = match transact_doc
+                .fields
+                .get("VirtualCurrency")
+                .and_then(|v| v.value_type.as_ref())
+            {
+                Some(ValueType::StringValue(currency)) => Some(currency.clone()),
+                _ => None,
             };
 
-            if firestore.client.update_document(req).await.is_err() {
-                return HttpResponse::InternalServerError().finish();
-            }
-
             //Decrement credit in user document
-            if let Some(credits) = user_doc.fields.get_mut("Credits") {
-                if let Some(credits) = credits.value_type.as_mut() {
-                    if let ValueType::IntegerValue(credits) = credits {
-                        *credits -= purchase.virtual_currency.quantity
-                    }
-                }
+            let balance = credit(
+                &mut user_doc,
+                &currency,
+                -purchase.virtual_currency.quantity,
+            );
+
+            let event = Event::CreditsRevoked {
+                user_id: user.id.clone(),
+                transaction_id: transaction.id,
+                currency: currency.clone(),
+                quantity: purchase.virtual_currency.quantity,
+            };
+
+            let writes = vec![
+                Write {
+                    update_mask: Some(DocumentMask {
+                        field_paths: vec!["RefundDate".to_owned(), "RefundCode".to_owned()],
+                    }),
+                    update_transforms: Vec::new(),
+                    current_document: Some(Precondition {
+                        condition_type: Some(ConditionType::Exists(true)),
+                    }),
+                    operation: Some(Operation::Update(transact_doc)),
+                },
+                Write {
+                    update_mask: Some(DocumentMask {
+                        field_paths: vec![balance_field(&currency)],
+                    }),
+                    update_transforms: Vec::new(),
+                    //Fails if a spend changed the balance since it was read
+                    current_document: Some(Precondition {
+                        condition_type: user_doc.update_time.clone().map(ConditionType::UpdateTime),
+                    }),
+                    operation: Some(Operation::Update(user_doc)),
+                },
+                outbox::write(&firestore.project_id, &event),
+            ];
+
+            let req = CommitRequest {
+                database: format!("projects/{}/databases/(default)", firestore.project_id),
+                writes,
+                transaction: Vec::new(),
+            };
+
+            if let Err(status) = firestore.client.commit(req).await {
+                return store_error(line, &status);
             }
 
-            let req = UpdateDocumentRequest {
-                document: Some(user_doc),
-                update_mask: Some(DocumentMask {
-                    field_paths: vec!["Credits".to_owned()],
-                }),
-                mask: Some(DocumentMask {
-                    field_paths: vec!["Credits".to_owned()],
-                }),
-                current_document: None,
+            metrics::CREDITS_REVOKED
+                .with_label_values(&[metrics::currency_label(&currency)])
+                .inc_by(purchase.virtual_currency.quantity.max(0) as u64);
+
+            firestore
+                .hub
+                .publish(&user.id, &balance_update(currency, balance));
+
+            HttpResponse::Ok().finish()
+        }
+        Message::Unknown {
+            notification_type,
+            raw,
+        } => {
+            line.notification_type = Some(notification_type.clone());
+
+            metrics::UNHANDLED_NOTIFICATIONS.inc();
+
+            let mut data: HashMap<String, Value> = HashMap::with_capacity(3);
+
+            data.insert(
+                "NotificationType".to_owned(),
+                Value {
+                    value_type: Some(ValueType::StringValue(notification_type)),
+                },
+            );
+
+            data.insert(
+                "Payload".to_owned(),
+                Value {
+                    value_type: Some(ValueType::StringValue(raw.to_string())),
+                },
+            );
+
+            data.insert(
+                "ReceivedDate".to_owned(),
+                Value {
+                    value_type: Some(ValueType::TimestampValue(prost_types::Timestamp::from(
+                        std::time::SystemTime::now(),
+                    ))),
+                },
+            );
+
+            let doc = Document {
+                name: String::new(),
+                fields: data,
+                create_time: None,
+                update_time: None,
             };
 
-            if firestore.client.update_document(req).await.is_err() {
-                return HttpResponse::InternalServerError().finish();
+            //Let Firestore generate the id, the same notification may be sent many times
+            let req = CreateDocumentRequest {
+                parent: format!(
+                    "projects/{}/databases/(default)/documents",
+                    firestore.project_id
+                ),
+                collection_id: "unhandled".to_owned(),
+                document_id: String::new(),
+                document: Some(doc),
+                mask: None,
+            };
+
+            if let Err(status) = firestore.client.create_document(req).await {
+                return store_error(line, &status);
+            }
+
+            if firestore.reject_unknown {
+                return HttpResponse::BadRequest().json(UNKNOWN_NOTIFICATION);
             }
 
             HttpResponse::Ok().finish()
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+    use crate::test_support::{create_user, test_data, RacingStore};
+    use actix_web::test;
+    use actix_web::test::TestRequest;
+    use actix_web::App;
+
+    const UNKNOWN_JSON: &str = r#"{"notification_type": "afs_reject", "transaction": {"id": 1}}"#;
+
+    #[actix_rt::test]
+    async fn unknown_notification_acknowledged() {
+        let data = test_data();
+
+        let app = App::new().app_data(data.clone()).service(notifications);
+        let mut app = test::init_service(app).await;
+
+        let req = TestRequest::post()
+            .uri("/webhook")
+            .header("content-type", "application/json")
+            .set_payload(UNKNOWN_JSON)
+            .to_request();
+
+        let resp = test::call_service(&mut app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = GetDocumentRequest {
+            name: "projects/test/databases/(default)/documents/unhandled/00000000000000000001"
+                .to_owned(),
+            mask: None,
+            consistency_selector: None,
+        };
+
+        let doc = data.lock().await.client.get_document(req).await.unwrap();
+
+        assert_eq!(
+            doc.fields.get("NotificationType").unwrap().value_type,
+            Some(ValueType::StringValue("afs_reject".to_owned()))
+        );
+    }
+
+    #[actix_rt::test]
+    async fn unknown_notification_rejected() {
+        let data = test_data();
+        data.lock().await.reject_unknown = true;
+
+        let app = App::new().app_data(data.clone()).service(notifications);
+        let mut app = test::init_service(app).await;
+
+        let req = TestRequest::post()
+            .uri("/webhook")
+            .header("content-type", "application/json")
+            .set_payload(UNKNOWN_JSON)
+            .to_request();
+
+        let resp = test::call_service(&mut app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn sku_balance_refunded() {
+        let data = test_data();
+
+        data.lock()
+            .await
+            .sku_currencies
+            .insert("test_package1".to_owned(), "gems".to_owned());
+
+        create_user(&data, "1234567", 5, &[]).await;
+
+        let app = App::new().app_data(data.clone()).service(notifications);
+        let mut app = test::init_service(app).await;
+
+        let notify = |payload: String| {
+            TestRequest::post()
+                .uri("/webhook")
+                .header("content-type", "application/json")
+                .set_payload(payload)
+                .to_request()
+        };
+
+        let user = || GetDocumentRequest {
+            name: "projects/test/databases/(default)/documents/users/1234567".to_owned(),
+            mask: None,
+            consistency_selector: None,
+        };
+
+        let payment = crate::simulator::payment("1234567", 1, 10);
+        test::call_service(&mut app, notify(payment)).await;
+
+        let doc = data.lock().await.client.get_document(user()).await.unwrap();
+
+        assert_eq!(crate::admin::integer(&doc, "Credits"), Some(5));
+        assert_eq!(crate::admin::balances(&doc).get("gems"), Some(&10));
+
+        //Remapped since the payment, the refund still takes the gems back
+        data.lock().await.sku_currencies.clear();
+
+        let refund = crate::simulator::refund("1234567", 1, 10);
+        test::call_service(&mut app, notify(refund)).await;
+
+        let doc = data.lock().await.client.get_document(user()).await.unwrap();
+
+        assert_eq!(crate::admin::integer(&doc, "Credits"), Some(5));
+        assert_eq!(crate::admin::balances(&doc).get("gems"), Some(&0));
+    }
+
+    #[actix_rt::test]
+    async fn balance_changed_during_payment() {
+        for (races, status, credits) in [(1, StatusCode::OK, 210), (2, StatusCode::CONFLICT, 200)] {
+            let data = test_data();
+            data.lock().await.client = Box::new(RacingStore::new(MemoryStore::new(), races));
+            create_user(&data, "1234567", 0, &[]).await;
+
+            let app = App::new().app_data(data.clone()).service(notifications);
+            let mut app = test::init_service(app).await;
+
+            let req = TestRequest::post()
+                .uri("/webhook")
+                .header("content-type", "application/json")
+                .set_payload(crate::simulator::payment("1234567", 1, 10))
+                .to_request();
+
+            let resp = test::call_service(&mut app, req).await;
+
+            assert_eq!(resp.status(), status);
+
+            let req = GetDocumentRequest {
+                name: "projects/test/databases/(default)/documents/users/1234567".to_owned(),
+                mask: None,
+                consistency_selector: None,
+            };
+
+            let doc = data.lock().await.client.get_document(req).await.unwrap();
+
+            assert_eq!(crate::admin::integer(&doc, "Credits"), Some(credits));
+        }
+    }
+
+    #[actix_rt::test]
+    async fn repeated_sku_summed() {
+        let data = test_data();
+        create_user(&data, "1234567", 0, &[]).await;
+
+        let app = App::new().app_data(data.clone()).service(notifications);
+        let mut app = test::init_service(app).await;
+
+        let mut payment: serde_json::Value =
+            serde_json::from_str(&crate::simulator::payment("1234567", 1, 10)).unwrap();
+
+        payment["purchase"]["virtual_items"] = serde_json::json!({
+            "items": [
+                {"sku": "sword", "amount": 1},
+                {"sku": "shield", "amount": 1},
+                {"sku": "sword", "amount": 2}
+            ],
+            "currency": "USD",
+            "amount": 30
+        });
+
+        let req = TestRequest::post()
+            .uri("/webhook")
+            .header("content-type", "application/json")
+            .set_payload(payment.to_string())
+            .to_request();
+
+        let resp = test::call_service(&mut app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = GetDocumentRequest {
+            name: "projects/test/databases/(default)/documents/outbox/1_item_granted_sword"
+                .to_owned(),
+            mask: None,
+            consistency_selector: None,
+        };
+
+        let doc = data.lock().await.client.get_document(req).await.unwrap();
+        let payload = crate::admin::string(&doc, "Payload").unwrap();
+        let event: serde_json::Value = serde_json::from_str(&payload).unwrap();
+
+        assert_eq!(event["quantity"], 3);
+    }
+}
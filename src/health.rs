@@ -0,0 +1,165 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use actix_web::get;
+use actix_web::{web, HttpResponse};
+
+use firestore_grpc_cloudrun::{DocumentMask, GetDocumentRequest};
+
+use futures::lock::Mutex;
+
+use serde::Serialize;
+
+use tonic::Code;
+
+use crate::config::Config;
+use crate::MyData;
+
+//A store call stuck behind the lock or the network counts as unreachable.
+const STORE_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Serialize)]
+struct Readiness {
+    status: &'static str,
+    checks: BTreeMap<&'static str, String>,
+}
+
+//Process is up, nothing else is checked.
+#[get("/healthz")]
+async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({ "status": "ok" }))
+}
+
+//A missing document still means the store answered.
+async fn check_store(data: &Mutex<MyData>) -> Result<(), String> {
+    let check = async {
+        let mut data = data.lock().await;
+
+        let req = GetDocumentRequest {
+            name: format!(
+                "projects/{}/databases/(default)/documents/health/readiness",
+                data.project_id
+            ),
+            mask: Some(DocumentMask {
+                field_paths: Vec::new(),
+            }),
+            consistency_selector: None,
+        };
+
+        match data.client.get_document(req).await {
+            Ok(_) => Ok(()),
+            Err(status) if status.code() == Code::NotFound => Ok(()),
+            Err(status) => Err(status.to_string()),
+        }
+    };
+
+    match actix_rt::time::timeout(STORE_TIMEOUT, check).await {
+        Ok(result) => result,
+        Err(_) => Err(format!("no answer within {:?}", STORE_TIMEOUT)),
+    }
+}
+
+fn check(result: Result<(), String>) -> String {
+    match result {
+        Ok(()) => "ok".to_owned(),
+        Err(error) => error,
+    }
+}
+
+fn present(present: bool) -> Result<(), String> {
+    if present {
+        Ok(())
+    } else {
+        Err("missing".to_owned())
+    }
+}
+
+#[get("/readyz")]
+async fn readyz(data: web::Data<Mutex<MyData>>, config: web::Data<Config>) -> HttpResponse {
+    let mut checks = BTreeMap::new();
+
+    checks.insert("store", check(check_store(&data).await));
+    checks.insert(
+        "webhook_secret_key",
+        check(present(config.webhook_secret_key.is_some())),
+    );
+    checks.insert(
+        "ip_white_list",
+        check(present(!config.ip_white_list.is_empty())),
+    );
+
+    if checks.values().all(|result| result == "ok") {
+        HttpResponse::Ok().json(Readiness {
+            status: "ready",
+            checks,
+        })
+    } else {
+        HttpResponse::ServiceUnavailable().json(Readiness {
+            status: "not_ready",
+            checks,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+    use crate::test_support::{test_data_with, FlakyStore};
+    use actix_web::http::StatusCode;
+    use actix_web::test;
+    use actix_web::test::TestRequest;
+    use actix_web::App;
+    use serde_json::Value;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[actix_rt::test]
+    async fn ready_until_store_fails() {
+        let failures = Arc::new(AtomicUsize::new(0));
+
+        let data = test_data_with(Box::new(FlakyStore::new(
+            MemoryStore::new(),
+            failures.clone(),
+        )));
+
+        let config = Config {
+            webhook_secret_key: Some("Ultra1Top2Secret3Key".to_owned()),
+            ip_white_list: vec!["185.30.20.0/24".parse().unwrap()],
+            ..Config::default()
+        };
+
+        let app = App::new()
+            .app_data(data)
+            .data(config)
+            .service(healthz)
+            .service(readyz);
+        let mut app = test::init_service(app).await;
+
+        let req = TestRequest::get().uri("/healthz").to_request();
+        let resp = test::call_service(&mut app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = TestRequest::get().uri("/readyz").to_request();
+        let resp = test::call_service(&mut app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        failures.store(1, Ordering::SeqCst);
+
+        let req = TestRequest::get().uri("/readyz").to_request();
+        let resp = test::call_service(&mut app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let body: Value = serde_json::from_slice(&test::read_body(resp).await).unwrap();
+
+        assert_eq!(body["status"], "not_ready");
+        assert_eq!(body["checks"]["webhook_secret_key"], "ok");
+        assert!(body["checks"]["store"]
+            .as_str()
+            .unwrap()
+            .contains("Simulated outage"));
+    }
+}
@@ -3,24 +3,21 @@ use actix_web::{dev::ServiceRequest, dev::ServiceResponse, Error, HttpResponse};
 use futures::future::{ok, Ready};
 use futures::Future;
 use ipnet::IpNet;
-use std::env;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
-fn get_white_list() -> Vec<IpNet> {
-    //185.30.20.0/24;185.30.21.0/24;...etc
-    let ips = env::var("IP_WHITE_LIST")
-        .expect("Trying to read enviroment variable IP_WHITE_LIST Error: ");
+use crate::metrics;
+use crate::telemetry::{self, Span, SpanKind};
 
-    let ips: Vec<IpNet> = ips
-        .split(';')
-        .map(|ip| ip.parse().expect("Trying to parse ip Error: "))
-        .collect();
-
-    ips
+pub struct IpWhiteList {
+    white_list: Vec<IpNet>,
 }
 
-pub struct IpWhiteList;
+impl IpWhiteList {
+    pub fn new(white_list: Vec<IpNet>) -> Self {
+        IpWhiteList { white_list }
+    }
+}
 
 impl<S, B> Transform<S> for IpWhiteList
 where
@@ -38,7 +35,7 @@ where
     fn new_transform(&self, service: S) -> Self::Future {
         ok(IpWhiteListMiddleware {
             service,
-            white_list: get_white_list(),
+            white_list: self.white_list.clone(),
         })
     }
 }
@@ -64,11 +61,21 @@ where
     }
 
     fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let mut span = Span::start(
+            "IpWhiteList",
+            SpanKind::Internal,
+            &telemetry::request_context(&req),
+        );
+
         if let Some(socket) = req.peer_addr() {
             let remote_ip = socket.ip();
 
+            span.set_attribute("net.peer.ip", remote_ip);
+
             for ip in &self.white_list {
                 if ip.contains(&remote_ip) {
+                    span.end();
+
                     let fut = self.service.call(req);
 
                     return Box::pin(async move {
@@ -79,6 +86,13 @@ where
             }
         }
 
+        metrics::REJECTED_REQUESTS
+            .with_label_values(&["ip_white_list"])
+            .inc();
+
+        span.set_error("not white listed");
+        span.end();
+
         Box::pin(ok(req.into_response(
             HttpResponse::Unauthorized().finish().into_body(),
         )))
@@ -95,10 +109,17 @@ mod tests {
     use actix_web::App;
     use std::net::SocketAddr;
 
+    fn white_list() -> IpWhiteList {
+        IpWhiteList::new(vec![
+            "185.30.20.0/24".parse().unwrap(),
+            "185.30.21.0/24".parse().unwrap(),
+        ])
+    }
+
     #[actix_rt::test]
     async fn wrong_ip() {
         let app = App::new()
-            .wrap(IpWhiteList)
+            .wrap(white_list())
             .service(handlers::notifications);
         let mut app = test::init_service(app).await;
 
@@ -117,7 +138,7 @@ mod tests {
     #[actix_rt::test]
     async fn correct_ip() {
         let app = App::new()
-            .wrap(IpWhiteList)
+            .wrap(white_list())
             .service(handlers::notifications);
         let mut app = test::init_service(app).await;
 
@@ -0,0 +1,37 @@
+use std::collections::HashMap;
+
+pub mod admin;
+pub mod archive;
+pub mod archive_middleware;
+pub mod auth_middleware;
+pub mod config;
+pub mod handlers;
+pub mod health;
+pub mod ip_white_list_middleware;
+pub mod logging;
+pub mod metrics;
+pub mod models;
+pub mod money;
+pub mod outbox;
+pub mod push;
+pub mod reconcile;
+pub mod replay_protection_middleware;
+pub mod report;
+pub mod resilience;
+pub mod shutdown;
+pub mod signature_middleware;
+pub mod simulator;
+pub mod spend;
+pub mod store;
+pub mod telemetry;
+#[cfg(test)]
+pub(crate) mod test_support;
+pub mod tls;
+
+pub struct MyData {
+    pub project_id: String,
+    pub client: Box<dyn store::Store + Send>,
+    pub reject_unknown: bool,
+    pub hub: push::Hub,
+    pub sku_currencies: HashMap<String, String>,
+}
@@ -0,0 +1,341 @@
+use actix_http::Payload;
+use actix_service::{Service, Transform};
+use actix_web::body::{Body, MessageBody, ResponseBody};
+use actix_web::{
+    dev::ServiceRequest, dev::ServiceResponse, http::header::HeaderName,
+    http::header::CONTENT_TYPE, http::HeaderValue, http::StatusCode, Error, FromRequest,
+    HttpMessage, HttpRequest,
+};
+use bytes::BytesMut;
+use chrono::{SecondsFormat, Utc};
+use futures::future::{ok, Ready};
+use futures::stream::StreamExt;
+use futures::Future;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+//Propagated from the caller when sane, generated otherwise.
+#[derive(PartialEq, Debug, Clone)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    pub(crate) fn generate() -> Self {
+        let bytes: [u8; 16] = rand::random();
+
+        RequestId(hex::encode(bytes))
+    }
+
+    fn from_header(req: &ServiceRequest) -> Option<Self> {
+        let id = req.headers().get(REQUEST_ID_HEADER)?.to_str().ok()?;
+
+        let valid = !id.is_empty()
+            && id.len() <= 128
+            && id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+
+        if valid {
+            Some(RequestId(id.to_owned()))
+        } else {
+            None
+        }
+    }
+}
+
+impl FromRequest for RequestId {
+    type Config = ();
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    //Handlers called without RequestLogger, in tests and replays, still get one
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        match req.extensions().get::<RequestId>() {
+            Some(id) => ok(id.clone()),
+            None => ok(RequestId::generate()),
+        }
+    }
+}
+
+//One JSON line on stderr, stdout is left to the CLIs.
+#[derive(PartialEq, Debug, Default, Serialize)]
+pub struct Line {
+    pub severity: &'static str,
+    pub message: &'static str,
+    pub timestamp: String,
+    pub request_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub method: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u128>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub notification_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transaction_id: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub outcome: Option<&'static str>,
+    //tonic Status of the failed Firestore call
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    //What the message is about when no other field says it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl Line {
+    pub fn new(message: &'static str, request_id: &RequestId) -> Self {
+        Line {
+            severity: "INFO",
+            message,
+            timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            request_id: request_id.0.clone(),
+            ..Line::default()
+        }
+    }
+
+    //Work outside any request, such as the outbox dispatcher, still gets an ID to search by
+    pub fn background(message: &'static str, severity: &'static str) -> Self {
+        Line {
+            severity,
+            ..Line::new(message, &RequestId::generate())
+        }
+    }
+
+    //Severity and outcome follow the response status
+    pub fn finish(&mut self, status: StatusCode) {
+        self.status = Some(status.as_u16());
+
+        if status.is_server_error() {
+            self.severity = "ERROR";
+            self.outcome = Some("failed");
+        } else if status.is_client_error() {
+            self.severity = "WARNING";
+            self.outcome = Some("rejected");
+        } else {
+            self.outcome = Some("ok");
+        }
+    }
+
+    pub fn write(&self) {
+        eprintln!(
+            "{}",
+            serde_json::to_string(self).expect("Trying to serialize log line Error: ")
+        );
+    }
+}
+
+//Callers that only keep the body of a failed request can still quote its ID.
+async fn with_request_id<B: MessageBody + 'static>(
+    mut res: ServiceResponse<B>,
+    request_id: &RequestId,
+) -> Result<ServiceResponse<Body>, Error> {
+    let status = res.status();
+
+    if !status.is_client_error() && !status.is_server_error() {
+        return Ok(res.map_body(|_, body| ResponseBody::Other(Body::Message(Box::new(body)))));
+    }
+
+    let mut body = BytesMut::new();
+    let mut stream = res.take_body();
+
+    while let Some(chunk) = stream.next().await {
+        body.extend_from_slice(&chunk?);
+    }
+
+    let mut json = match serde_json::from_slice(&body) {
+        Ok(Value::Object(json)) => json,
+        _ if body.is_empty() => Map::new(),
+        //Not JSON, left as sent
+        _ => return Ok(res.map_body(|_, _| ResponseBody::Other(Body::from(body.freeze())))),
+    };
+
+    json.insert("request_id".to_owned(), Value::String(request_id.0.clone()));
+
+    let json = serde_json::to_vec(&json).expect("Trying to serialize error body Error: ");
+
+    res.headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+
+    Ok(res.map_body(|_, _| ResponseBody::Other(Body::from(json))))
+}
+
+//Outermost middleware, one access line per request and the request ID on every response.
+pub struct RequestLogger;
+
+impl<S, B> Transform<S> for RequestLogger
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<Body>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequestLoggerMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequestLoggerMiddleware { service })
+    }
+}
+
+pub struct RequestLoggerMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for RequestLoggerMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<Body>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let start = Instant::now();
+
+        let request_id = RequestId::from_header(&req).unwrap_or_else(RequestId::generate);
+
+        let mut line = Line::new("request", &request_id);
+        line.method = Some(req.method().to_string());
+        line.path = Some(req.path().to_owned());
+
+        req.extensions_mut().insert(request_id.clone());
+
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let mut res = match fut.await {
+                Ok(res) => res,
+                Err(error) => {
+                    line.latency_ms = Some(start.elapsed().as_millis());
+                    line.finish(error.as_response_error().status_code());
+                    line.write();
+
+                    return Err(error);
+                }
+            };
+
+            if let Ok(value) = HeaderValue::from_str(&request_id.0) {
+                res.headers_mut()
+                    .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+            }
+
+            line.latency_ms = Some(start.elapsed().as_millis());
+            line.finish(res.status());
+            line.write();
+
+            with_request_id(res, &request_id).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test;
+    use actix_web::test::TestRequest;
+    use actix_web::{web, App, HttpResponse};
+
+    async fn echo(request_id: RequestId) -> HttpResponse {
+        HttpResponse::BadRequest().body(request_id.0)
+    }
+
+    #[actix_rt::test]
+    async fn request_id_echoed() {
+        let app = App::new()
+            .wrap(RequestLogger)
+            .route("/", web::post().to(echo));
+        let mut app = test::init_service(app).await;
+
+        let req = TestRequest::post()
+            .uri("/")
+            .header(REQUEST_ID_HEADER, "caller-id-1")
+            .to_request();
+
+        let resp = test::call_service(&mut app, req).await;
+
+        assert_eq!(
+            resp.headers().get(REQUEST_ID_HEADER).unwrap(),
+            "caller-id-1"
+        );
+        assert_eq!(test::read_body(resp).await, "caller-id-1");
+
+        //Not a sane ID, a new one is generated
+        let req = TestRequest::post()
+            .uri("/")
+            .header(REQUEST_ID_HEADER, "{\"inject\": 1}")
+            .to_request();
+
+        let resp = test::call_service(&mut app, req).await;
+        let id = resp.headers().get(REQUEST_ID_HEADER).unwrap().clone();
+
+        assert_eq!(id.len(), 32);
+        assert_eq!(test::read_body(resp).await, id.to_str().unwrap());
+    }
+
+    async fn fail() -> HttpResponse {
+        HttpResponse::BadRequest().json(serde_json::json!({"error": {"code": "INVALID_USER"}}))
+    }
+
+    #[actix_rt::test]
+    async fn request_id_in_error_body() {
+        let app = App::new()
+            .wrap(RequestLogger)
+            .route("/", web::post().to(fail))
+            .route("/down", web::post().to(HttpResponse::InternalServerError));
+        let mut app = test::init_service(app).await;
+
+        let req = TestRequest::post()
+            .uri("/")
+            .header(REQUEST_ID_HEADER, "caller-id-1")
+            .to_request();
+
+        let body: Value = test::read_response_json(&mut app, req).await;
+
+        assert_eq!(body["error"]["code"], "INVALID_USER");
+        assert_eq!(body["request_id"], "caller-id-1");
+
+        let req = TestRequest::post()
+            .uri("/down")
+            .header(REQUEST_ID_HEADER, "caller-id-2")
+            .to_request();
+
+        let body: Value = test::read_response_json(&mut app, req).await;
+
+        assert_eq!(body["request_id"], "caller-id-2");
+    }
+
+    #[test]
+    fn failure_line() {
+        let mut line = Line::new("notification", &RequestId("abc".to_owned()));
+        line.timestamp = "2020-01-01T00:00:00.000Z".to_owned();
+        line.notification_type = Some("payment".to_owned());
+        line.transaction_id = Some(1);
+        line.error = Some("status: Unavailable".to_owned());
+        line.finish(StatusCode::INTERNAL_SERVER_ERROR);
+
+        assert_eq!(
+            serde_json::to_string(&line).unwrap(),
+            r#"{"severity":"ERROR","message":"notification","timestamp":"2020-01-01T00:00:00.000Z","request_id":"abc","status":500,"notification_type":"payment","transaction_id":1,"outcome":"failed","error":"status: Unavailable"}"#
+        );
+    }
+}
@@ -1,49 +1,192 @@
-use std::env;
-use std::net::SocketAddr;
-use std::sync::Mutex;
+use std::process;
+use std::sync::Arc;
 
+use actix_web::middleware::Condition;
 use actix_web::{web, App, HttpServer};
 
-use firestore_grpc_cloudrun::compute_metadata;
-use firestore_grpc_cloudrun::firestore_client::FirestoreClient;
+use futures::lock::Mutex;
 
-use tonic::transport::channel::Channel;
+use actix_test::config::Config;
+use actix_test::{
+    admin, archive, archive_middleware, auth_middleware, handlers, health,
+    ip_white_list_middleware, logging, metrics, outbox, push, replay_protection_middleware,
+    resilience, shutdown, signature_middleware, spend, store, telemetry, tls, MyData,
+};
 
-mod handlers;
-mod ip_white_list_middleware;
-mod models;
-mod signature_middleware;
+#[actix_rt::main]
+async fn main() -> std::io::Result<()> {
+    //Every setting is checked here, once, instead of panicking in a worker later
+    let config = Config::load_server().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1)
+    });
 
-fn get_port() -> SocketAddr {
-    let port = env::var("PORT").expect("Trying to read enviroment variable PORT Error: ");
+    let retention = config.archive_retention;
 
-    ["0.0.0.0:", &port]
-        .concat()
-        .parse::<SocketAddr>()
-        .expect("Trying to parse SocketAddr Error: ")
-}
+    let (data, store_archive) = if config.in_memory_store {
+        let data = MyData {
+            project_id: "local".to_owned(),
+            client: Box::new(resilience::ResilientStore::new(
+                telemetry::TracedStore::new(metrics::MeteredStore::new(store::MemoryStore::new())),
+                resilience::Policy::new(&config),
+            )),
+            reject_unknown: config.reject_unknown_notifications,
+            hub: push::Hub::new(config.push_secret_key.clone()),
+            sku_currencies: config.virtual_currency_skus.clone(),
+        };
 
-struct MyData {
-    project_id: String,
-    client: FirestoreClient<Channel>,
-}
+        let store_archive = archive::StoreArchive::new(
+            "local".to_owned(),
+            Box::new(store::MemoryStore::new()),
+            retention,
+        );
 
-#[actix_rt::main]
-async fn main() -> std::io::Result<()> {
-    let project_id = compute_metadata::get_project_id().await.unwrap();
-    let client = firestore_grpc_cloudrun::get_client().await.unwrap();
+        (data, store_archive)
+    } else {
+        let project_id = store::get_project_id(&config).await.unwrap_or_else(|e| {
+            eprintln!("Trying to get the project ID Error: {}", e);
+            process::exit(1)
+        });
+
+        let client = store::get_client(&config).await.unwrap_or_else(|e| {
+            eprintln!("Trying to connect to Firestore Error: {}", e);
+            process::exit(1)
+        });
+
+        let store_archive =
+            archive::StoreArchive::new(project_id.clone(), Box::new(client.clone()), retention);
+
+        let data = MyData {
+            project_id,
+            client: Box::new(resilience::ResilientStore::new(
+                telemetry::TracedStore::new(metrics::MeteredStore::new(client)),
+                resilience::Policy::new(&config),
+            )),
+            reject_unknown: config.reject_unknown_notifications,
+            hub: push::Hub::new(config.push_secret_key.clone()),
+            sku_currencies: config.virtual_currency_skus.clone(),
+        };
+
+        (data, store_archive)
+    };
+
+    let notification_archive: Box<dyn archive::Archive + Send> = match config.archive_file.clone() {
+        Some(path) => Box::new(
+            archive::JsonlArchive::open(path, retention).unwrap_or_else(|e| {
+                eprintln!("Trying to open ARCHIVE_FILE Error: {}", e);
+                process::exit(1)
+            }),
+        ),
+        None => Box::new(store_archive),
+    };
+
+    let notification_archive = Arc::new(Mutex::new(notification_archive));
 
-    let data = web::Data::new(Mutex::new(MyData { project_id, client }));
+    let data = web::Data::new(Mutex::new(data));
+
+    let keys = Arc::new(auth_middleware::get_keys(&config));
+
+    if let Some(dispatcher) = outbox::get_dispatcher(&config) {
+        dispatcher.spawn(data.clone());
+    }
+
+    if let Some(exporter) = telemetry::get_exporter(&config) {
+        exporter.spawn();
+    }
+
+    let tls = tls::server_config(&config).unwrap_or_else(|e| {
+        eprintln!("Trying to load the TLS certificate Error: {}", e);
+        process::exit(1)
+    });
+
+    let secret_key = config
+        .webhook_secret_key
+        .clone()
+        .expect("Trying to read the webhook secret key Error: ");
+    let white_list = config.ip_white_list.clone();
+    let max_body_size = config.max_body_size;
+    //One cache for every worker
+    let replay_protection = config.replay_protection;
+    let reject_replays = replay_protection_middleware::RejectReplays::new(
+        config.replay_ttl,
+        config.replay_clock_skew,
+    );
+    let port = config.port;
+    let tls_reload_interval = config.tls_reload_interval;
+    let shutdown_timeout = config.shutdown_timeout.as_secs();
+
+    let config = web::Data::new(config);
+    let app_config = config.clone();
+    let app_data = data.clone();
 
     //https://docs.rs/crate/actix-web
-    HttpServer::new(move || {
+    //SIGTERM stops accepting connections and waits up to shutdown_timeout for running handlers
+    let server = HttpServer::new(move || {
         App::new()
-            .data(data.clone())
-            .wrap(signature_middleware::VerifySignature)
-            .wrap(ip_white_list_middleware::IpWhiteList)
-            .service(handlers::notifications)
-    })
-    .bind(get_port())?
-    .run()
-    .await
+            .app_data(app_data.clone())
+            .app_data(app_config.clone())
+            //Json would otherwise stop at its own 32 KB default, below max_body_size
+            .app_data(web::JsonConfig::default().limit(max_body_size))
+            //Wraps everything, rejections by the other middlewares are logged too
+            .wrap(logging::RequestLogger)
+            .service(metrics::export)
+            //Probes skip the Xsolla middlewares, readiness still needs the store
+            .service(health::healthz)
+            .service(health::readyz)
+            //Game clients and staff authenticate with their own tokens, not Xsolla's signature
+            .service(push::balance)
+            .service(
+                web::scope("/admin")
+                    .wrap(auth_middleware::Authenticate::new(keys.clone()))
+                    .service(admin::balance)
+                    .service(admin::transactions)
+                    .service(admin::transaction)
+                    .service(admin::adjust),
+            )
+            .service(
+                web::scope("/spend")
+                    .wrap(auth_middleware::Authenticate::new(keys.clone()))
+                    .service(spend::spend),
+            )
+            .service(
+                web::scope("")
+                    .wrap(Condition::new(replay_protection, reject_replays.clone()))
+                    .wrap(signature_middleware::VerifySignature::new(
+                        secret_key.clone(),
+                        max_body_size,
+                    ))
+                    .wrap(ip_white_list_middleware::IpWhiteList::new(
+                        white_list.clone(),
+                    ))
+                    .wrap(archive_middleware::ArchiveNotifications::new(
+                        notification_archive.clone(),
+                        max_body_size,
+                    ))
+                    .service(handlers::notifications),
+            )
+    });
+
+    let server = match tls {
+        Some((tls_config, reloader)) => {
+            reloader.spawn(tls_reload_interval);
+            server.bind_rustls(("0.0.0.0", port), tls_config)?
+        }
+        None => server.bind(("0.0.0.0", port))?,
+    };
+
+    server.shutdown_timeout(shutdown_timeout).run().await?;
+
+    //Handlers dropped at the deadline already reported themselves
+    let aborted = shutdown::report_remaining();
+
+    if aborted > 0 {
+        let mut line =
+            logging::Line::background("notifications aborted by the shutdown", "WARNING");
+        line.detail = Some(format!("{} notifications", aborted));
+        line.write();
+    }
+
+    shutdown::flush(&config, &data).await;
+
+    Ok(())
 }
@@ -0,0 +1,208 @@
+use std::time::Instant;
+
+use actix_web::get;
+use actix_web::HttpResponse;
+
+use async_trait::async_trait;
+
+use firestore_grpc_cloudrun::{
+    CommitRequest, CommitResponse, CreateDocumentRequest, Document, GetDocumentRequest,
+    ListDocumentsRequest, ListDocumentsResponse, UpdateDocumentRequest,
+};
+
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge,
+    Encoder, HistogramVec, IntCounter, IntCounterVec, IntGauge, TextEncoder,
+};
+
+use tonic::{Code, Status};
+
+use crate::store::Store;
+use crate::telemetry::SpanContext;
+
+lazy_static! {
+    //No notification_type label, anyone can send any type, it is in the logs
+    pub static ref UNHANDLED_NOTIFICATIONS: IntCounter = register_int_counter!(
+        "unhandled_notifications_total",
+        "Notifications received with an unknown notification_type"
+    )
+    .unwrap();
+    pub static ref NOTIFICATIONS: IntCounterVec = register_int_counter_vec!(
+        "notifications_total",
+        "Notifications processed by type and outcome (ok, rejected or failed)",
+        &["notification_type", "outcome"]
+    )
+    .unwrap();
+    pub static ref REJECTED_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "rejected_requests_total",
+        "Webhook requests turned away by a middleware",
+        &["middleware"]
+    )
+    .unwrap();
+    pub static ref CREDITS_GRANTED: IntCounterVec = register_int_counter_vec!(
+        "credits_granted_total",
+        "Virtual currency credited by payments, Credits is labeled credits",
+        &["currency"]
+    )
+    .unwrap();
+    pub static ref CREDITS_REVOKED: IntCounterVec = register_int_counter_vec!(
+        "credits_revoked_total",
+        "Virtual currency taken back by refunds, Credits is labeled credits",
+        &["currency"]
+    )
+    .unwrap();
+    pub static ref NOTIFICATION_DURATION: HistogramVec = register_histogram_vec!(
+        "notification_duration_seconds",
+        "Webhook handler time including the wait for the store lock",
+        &["notification_type"]
+    )
+    .unwrap();
+    pub static ref FIRESTORE_DURATION: HistogramVec = register_histogram_vec!(
+        "firestore_request_duration_seconds",
+        "Store calls by method and gRPC code",
+        &["method", "code"]
+    )
+    .unwrap();
+    pub static ref STORE_RETRIES: IntCounterVec = register_int_counter_vec!(
+        "store_retries_total",
+        "Store calls repeated after a retriable gRPC code or a deadline",
+        &["method"]
+    )
+    .unwrap();
+    pub static ref ARCHIVE_FAILURES: IntCounter = register_int_counter!(
+        "archive_failures_total",
+        "Webhook requests that could not be archived"
+    )
+    .unwrap();
+    pub static ref STORE_CIRCUIT_OPEN: IntGauge = register_int_gauge!(
+        "store_circuit_open",
+        "1 while store calls fail fast after repeated failures"
+    )
+    .unwrap();
+}
+
+pub fn currency_label(currency: &Option<String>) -> &str {
+    match currency {
+        Some(currency) => currency,
+        None => "credits",
+    }
+}
+
+#[get("/metrics")]
+async fn export() -> HttpResponse {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+
+    if encoder.encode(&prometheus::gather(), &mut buffer).is_err() {
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    HttpResponse::Ok()
+        .content_type(encoder.format_type())
+        .body(buffer)
+}
+
+//Times every call of the wrapped store.
+pub struct MeteredStore<S> {
+    store: S,
+}
+
+impl<S> MeteredStore<S> {
+    pub fn new(store: S) -> Self {
+        MeteredStore { store }
+    }
+}
+
+fn observe<T>(method: &str, start: Instant, result: &Result<T, Status>) {
+    let code = match result {
+        Ok(_) => Code::Ok,
+        Err(status) => status.code(),
+    };
+
+    FIRESTORE_DURATION
+        .with_label_values(&[method, &format!("{:?}", code)])
+        .observe(start.elapsed().as_secs_f64());
+}
+
+#[async_trait(?Send)]
+impl<S: Store> Store for MeteredStore<S> {
+    async fn get_document(&mut self, req: GetDocumentRequest) -> Result<Document, Status> {
+        let start = Instant::now();
+        let result = self.store.get_document(req).await;
+
+        observe("get_document", start, &result);
+        result
+    }
+
+    async fn list_documents(
+        &mut self,
+        req: ListDocumentsRequest,
+    ) -> Result<ListDocumentsResponse, Status> {
+        let start = Instant::now();
+        let result = self.store.list_documents(req).await;
+
+        observe("list_documents", start, &result);
+        result
+    }
+
+    async fn create_document(&mut self, req: CreateDocumentRequest) -> Result<Document, Status> {
+        let start = Instant::now();
+        let result = self.store.create_document(req).await;
+
+        observe("create_document", start, &result);
+        result
+    }
+
+    async fn update_document(&mut self, req: UpdateDocumentRequest) -> Result<Document, Status> {
+        let start = Instant::now();
+        let result = self.store.update_document(req).await;
+
+        observe("update_document", start, &result);
+        result
+    }
+
+    async fn commit(&mut self, req: CommitRequest) -> Result<CommitResponse, Status> {
+        let start = Instant::now();
+        let result = self.store.commit(req).await;
+
+        observe("commit", start, &result);
+        result
+    }
+
+    fn set_trace_parent(&mut self, parent: Option<SpanContext>) {
+        self.store.set_trace_parent(parent);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+    use actix_web::test;
+    use actix_web::test::TestRequest;
+    use actix_web::App;
+
+    #[actix_rt::test]
+    async fn store_calls_exported() {
+        let mut store = MeteredStore::new(MemoryStore::new());
+
+        let req = GetDocumentRequest {
+            name: "projects/test/databases/(default)/documents/users/missing".to_owned(),
+            mask: None,
+            consistency_selector: None,
+        };
+
+        assert!(store.get_document(req).await.is_err());
+
+        let mut app = test::init_service(App::new().service(export)).await;
+
+        let req = TestRequest::get().uri("/metrics").to_request();
+        let body = test::read_response(&mut app, req).await;
+        let body = String::from_utf8(body.to_vec()).unwrap();
+
+        assert!(body.contains(
+            r#"firestore_request_duration_seconds_count{code="NotFound",method="get_document"}"#
+        ));
+    }
+}
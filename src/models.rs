@@ -1,7 +1,13 @@
-use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
-#[derive(PartialEq, Debug, Deserialize)]
-#[serde(tag = "notification_type")]
+use serde::de::DeserializeOwned;
+use serde::de::Error as DeError;
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::money::Money;
+
+#[derive(PartialEq, Debug, Clone, Deserialize)]
+#[serde(remote = "Self", tag = "notification_type")]
 pub enum Message {
     #[serde(rename = "user_validation")]
     UserValidation { user: User },
@@ -10,7 +16,7 @@ pub enum Message {
         purchase: Purchase,
         user: User,
         transaction: Transaction,
-        //payment_details: PaymentDetails,
+        payment_details: Option<PaymentDetails>,
     },
     #[serde(rename = "refund")]
     Refund {
@@ -18,44 +24,97 @@ pub enum Message {
         user: User,
         transaction: Transaction,
         refund_details: RefundDetails,
-        //payment_details: PaymentDetails,
+        payment_details: Option<PaymentDetails>,
+    },
+    //Any notification type not listed above, raw JSON is kept as is.
+    #[serde(skip)]
+    Unknown {
+        notification_type: String,
+        raw: serde_json::Value,
     },
 }
 
-#[derive(PartialEq, Debug, Deserialize)]
-pub struct PaymentDetails {
-    #[serde(rename = "xsolla_fee")]
-    xsolla_fee: Option<Payment>,
-
-    #[serde(rename = "payout")]
-    payout: Option<Payment>,
+const KNOWN_NOTIFICATION_TYPES: [&str; 3] = ["user_validation", "payment", "refund"];
 
-    #[serde(rename = "vat")]
-    vat: Option<Payment>,
+impl<'de> Deserialize<'de> for Message {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let raw = serde_json::Value::deserialize(deserializer)?;
 
-    #[serde(rename = "payout_currency_rate")]
-    payout_currency_rate: Option<i64>,
-
-    #[serde(rename = "payment_method_fee")]
-    payment_method_fee: Option<Payment>,
+        let notification_type = match raw.get("notification_type") {
+            Some(serde_json::Value::String(notification_type)) => notification_type.clone(),
+            _ => return Err(D::Error::missing_field("notification_type")),
+        };
 
-    #[serde(rename = "payment")]
-    payment: Option<Payment>,
+        if KNOWN_NOTIFICATION_TYPES.contains(&notification_type.as_str()) {
+            //Calls the derived impl, known types must still be well formed.
+            Message::deserialize(raw).map_err(D::Error::custom)
+        } else {
+            Ok(Message::Unknown {
+                notification_type,
+                raw,
+            })
+        }
+    }
+}
 
-    #[serde(rename = "repatriation_commission")]
-    repatriation_commission: Option<Payment>,
+//Fees and payout are usually in the payout currency.
+#[derive(PartialEq, Debug, Clone)]
+pub struct PaymentDetails {
+    pub xsolla_fee: Option<Money>,
+    pub payout: Option<Money>,
+    pub vat: Option<Money>,
+    pub payout_currency_rate: Option<f64>,
+    pub payment_method_fee: Option<Money>,
+    pub payment: Option<Money>,
+    pub repatriation_commission: Option<Money>,
+    //Fields that did not parse, as sent
+    pub invalid: BTreeMap<String, serde_json::Value>,
 }
 
-#[derive(PartialEq, Debug, Deserialize)]
-pub struct Payment {
-    #[serde(rename = "currency")]
-    currency: Option<String>,
+fn lenient<T: DeserializeOwned>(
+    raw: &mut BTreeMap<String, serde_json::Value>,
+    invalid: &mut BTreeMap<String, serde_json::Value>,
+    field: &str,
+) -> Option<T> {
+    match raw.remove(field)? {
+        serde_json::Value::Null => None,
+        value => match serde_json::from_value(value.clone()) {
+            Ok(value) => Some(value),
+            Err(_) => {
+                invalid.insert(field.to_owned(), value);
+                None
+            }
+        },
+    }
+}
 
-    #[serde(rename = "amount")]
-    amount: Option<i64>,
+//Only informational, a malformed amount must not fail the payment,
+//the credited quantity is checked strictly with the purchase.
+impl<'de> Deserialize<'de> for PaymentDetails {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let mut raw = BTreeMap::<String, serde_json::Value>::deserialize(deserializer)?;
+        let mut invalid = BTreeMap::new();
+
+        Ok(PaymentDetails {
+            xsolla_fee: lenient(&mut raw, &mut invalid, "xsolla_fee"),
+            payout: lenient(&mut raw, &mut invalid, "payout"),
+            vat: lenient(&mut raw, &mut invalid, "vat"),
+            payout_currency_rate: lenient(&mut raw, &mut invalid, "payout_currency_rate"),
+            payment_method_fee: lenient(&mut raw, &mut invalid, "payment_method_fee"),
+            payment: lenient(&mut raw, &mut invalid, "payment"),
+            repatriation_commission: lenient(&mut raw, &mut invalid, "repatriation_commission"),
+            invalid,
+        })
+    }
 }
 
-#[derive(PartialEq, Debug, Deserialize)]
+#[derive(PartialEq, Debug, Clone, Deserialize)]
 pub struct Purchase {
     #[serde(rename = "virtual_currency")]
     pub virtual_currency: VirtualCurrency,
@@ -63,13 +122,11 @@ pub struct Purchase {
     //subscription: Option<Subscription>,
 
     //#[serde(rename = "checkout")]
-    //checkout: Option<Payment>,
-
-    //#[serde(rename = "virtual_items")]
-    //virtual_items: Option<VirtualItems>,
-
+    //checkout: Option<Money>,
+    #[serde(rename = "virtual_items")]
+    pub virtual_items: Option<VirtualItems>,
     //#[serde(rename = "total")]
-    //total: Option<Payment>,
+    //total: Option<Money>,
 
     //#[serde(rename = "promotions")]
     //promotions: Option<Vec<Promotion>>,
@@ -78,7 +135,8 @@ pub struct Purchase {
     //coupon: Option<Coupon>,
 }
 
-#[derive(PartialEq, Debug, Deserialize)]
+#[allow(dead_code)]
+#[derive(PartialEq, Debug, Clone, Deserialize)]
 pub struct Coupon {
     #[serde(rename = "coupon_code")]
     coupon_code: Option<String>,
@@ -87,7 +145,8 @@ pub struct Coupon {
     campaign_code: Option<String>,
 }
 
-#[derive(PartialEq, Debug, Deserialize)]
+#[allow(dead_code)]
+#[derive(PartialEq, Debug, Clone, Deserialize)]
 pub struct Promotion {
     #[serde(rename = "technical_name")]
     technical_name: Option<String>,
@@ -96,7 +155,8 @@ pub struct Promotion {
     id: Option<i64>,
 }
 
-#[derive(PartialEq, Debug, Deserialize)]
+#[allow(dead_code)]
+#[derive(PartialEq, Debug, Clone, Deserialize)]
 pub struct Subscription {
     #[serde(rename = "plan_id")]
     plan_id: Option<String>,
@@ -110,52 +170,44 @@ pub struct Subscription {
     #[serde(rename = "date_next_charge")]
     date_next_charge: Option<String>,
 
-    #[serde(rename = "currency")]
-    currency: Option<String>,
-
-    #[serde(rename = "amount")]
-    amount: Option<f64>,
+    #[serde(flatten)]
+    price: Option<Money>,
 }
 
-#[derive(PartialEq, Debug, Deserialize)]
+#[derive(PartialEq, Debug, Clone, Deserialize)]
 pub struct VirtualCurrency {
     //#[serde(rename = "name")]
     //name: Option<String>,
+    #[serde(rename = "sku")]
+    pub sku: Option<String>,
 
-    //#[serde(rename = "sku")]
-    //sku: Option<String>,
     #[serde(rename = "quantity")]
     pub quantity: i64,
 
-    #[serde(rename = "currency")]
-    pub currency: String,
-
-    #[serde(rename = "amount")]
-    pub amount: i64,
+    //"currency" and "amount"
+    #[serde(flatten)]
+    pub price: Money,
 }
 
-#[derive(PartialEq, Debug, Deserialize)]
+#[derive(PartialEq, Debug, Clone, Deserialize)]
 pub struct VirtualItems {
     #[serde(rename = "items")]
-    items: Option<Vec<Item>>,
-
-    #[serde(rename = "currency")]
-    currency: Option<String>,
+    pub items: Option<Vec<Item>>,
 
-    #[serde(rename = "amount")]
-    amount: Option<i64>,
+    #[serde(flatten)]
+    pub price: Option<Money>,
 }
 
-#[derive(PartialEq, Debug, Deserialize)]
+#[derive(PartialEq, Debug, Clone, Deserialize)]
 pub struct Item {
     #[serde(rename = "sku")]
-    sku: Option<String>,
+    pub sku: Option<String>,
 
     #[serde(rename = "amount")]
-    amount: Option<i64>,
+    pub amount: Option<i64>,
 }
 
-#[derive(PartialEq, Debug, Deserialize)]
+#[derive(PartialEq, Debug, Clone, Deserialize)]
 pub struct RefundDetails {
     #[serde(rename = "code")]
     pub code: i64,
@@ -163,10 +215,13 @@ pub struct RefundDetails {
     //reason: Option<String>,
 }
 
-#[derive(PartialEq, Debug, Deserialize)]
+#[derive(PartialEq, Debug, Clone, Deserialize)]
 pub struct Transaction {
     #[serde(rename = "id")]
     pub id: i64,
+
+    #[serde(rename = "payment_date")]
+    pub payment_date: Option<String>,
     //#[serde(rename = "external_id")]
     //external_id: Option<String>,
 
@@ -177,7 +232,7 @@ pub struct Transaction {
     //agreement: Option<i64>,
 }
 
-#[derive(PartialEq, Debug, Deserialize)]
+#[derive(PartialEq, Debug, Clone, Deserialize)]
 pub struct User {
     //#[serde(rename = "ip")]
     //ip: Option<String>,
@@ -191,9 +246,117 @@ pub struct User {
     pub id: String,
     //#[serde(rename = "name")]
     //name: Option<String>,
+    #[serde(rename = "country")]
+    pub country: Option<String>,
+}
+
+//Admin API responses, built from the stored documents.
+#[derive(PartialEq, Debug, Serialize)]
+pub struct Balance {
+    #[serde(rename = "user_id")]
+    pub user_id: String,
+
+    #[serde(rename = "credits")]
+    pub credits: i64,
 
-    //#[serde(rename = "country")]
-    //country: Option<String>,
+    //Per internal currency, e.g. {"gems": 10}
+    #[serde(rename = "balances")]
+    pub balances: BTreeMap<String, i64>,
+}
+
+#[derive(PartialEq, Debug, Serialize)]
+pub struct TransactionRecord {
+    #[serde(rename = "id")]
+    pub id: String,
+
+    #[serde(rename = "user_id")]
+    pub user_id: String,
+
+    #[serde(rename = "kind")]
+    pub kind: String,
+
+    #[serde(rename = "currency")]
+    pub currency: Option<String>,
+
+    //Decimal string, "9.99"
+    #[serde(rename = "cost")]
+    pub cost: Option<String>,
+
+    #[serde(rename = "quantity")]
+    pub quantity: Option<i64>,
+
+    //Internal currency the quantity applies to, None for Credits
+    #[serde(rename = "virtual_currency")]
+    pub virtual_currency: Option<String>,
+
+    #[serde(rename = "created_date")]
+    pub created_date: Option<String>,
+
+    #[serde(rename = "refunded")]
+    pub refunded: bool,
+
+    #[serde(rename = "refund_date")]
+    pub refund_date: Option<String>,
+
+    #[serde(rename = "refund_code")]
+    pub refund_code: Option<i64>,
+
+    #[serde(rename = "reason")]
+    pub reason: Option<String>,
+
+    #[serde(rename = "operator")]
+    pub operator: Option<String>,
+}
+
+//Manual credit change requested by support, amount may be negative.
+#[derive(PartialEq, Debug, Deserialize)]
+pub struct Adjustment {
+    #[serde(rename = "amount")]
+    pub amount: i64,
+
+    #[serde(rename = "reason")]
+    pub reason: String,
+
+    //A retried request with the same key is applied once
+    #[serde(rename = "idempotency_key")]
+    pub idempotency_key: String,
+
+    //One of the internal currencies, Credits when absent
+    #[serde(rename = "currency", default)]
+    pub currency: Option<String>,
+}
+
+//Debit requested by the game server, retried with the same idempotency key.
+#[derive(PartialEq, Debug, Deserialize)]
+pub struct Spend {
+    #[serde(rename = "user_id")]
+    pub user_id: String,
+
+    #[serde(rename = "quantity")]
+    pub quantity: i64,
+
+    #[serde(rename = "idempotency_key")]
+    pub idempotency_key: String,
+
+    #[serde(rename = "reason")]
+    pub reason: Option<String>,
+
+    //One of the internal currencies, Credits when absent
+    #[serde(rename = "currency", default)]
+    pub currency: Option<String>,
+}
+
+//Balances after the spend, like GET /admin/users/{user_id}/balance.
+#[derive(PartialEq, Debug, Serialize)]
+pub struct SpendReceipt {
+    #[serde(rename = "transaction")]
+    pub transaction: TransactionRecord,
+
+    #[serde(rename = "credits")]
+    pub credits: i64,
+
+    #[serde(rename = "balances")]
+    pub balances: BTreeMap<String, i64>,
 }
 
 #[derive(PartialEq, Debug, Serialize)]
@@ -215,6 +378,10 @@ pub struct Error<'a> {
 mod tests {
     use super::*;
 
+    fn usd(amount: &str) -> Option<Money> {
+        Some(Money::parse(amount, "USD").unwrap())
+    }
+
     #[test]
     fn user_validation_deserialize() {
         let json = r#"
@@ -232,6 +399,7 @@ mod tests {
 
         let user = User {
             id: String::from("1234567"),
+            country: Some(String::from("US")),
         };
 
         let data = Message::UserValidation { user };
@@ -340,22 +508,45 @@ mod tests {
 
         let purchase = Purchase {
             virtual_currency: VirtualCurrency {
-                currency: String::from("USD"),
+                sku: Some(String::from("test_package1")),
                 quantity: 10,
-                amount: 100,
+                price: Money::parse("100", "USD").unwrap(),
             },
+            virtual_items: Some(VirtualItems {
+                items: Some(vec![Item {
+                    sku: Some(String::from("test_item1")),
+                    amount: Some(1),
+                }]),
+                price: usd("50"),
+            }),
         };
 
         let user = User {
             id: String::from("1234567"),
+            country: Some(String::from("US")),
         };
 
-        let transaction = Transaction { id: 1 };
+        let transaction = Transaction {
+            id: 1,
+            payment_date: Some(String::from("2014-09-24T20:38:16+04:00")),
+        };
+
+        let payment_details = PaymentDetails {
+            xsolla_fee: usd("10"),
+            payout: usd("200"),
+            vat: usd("0"),
+            payout_currency_rate: Some(1.0),
+            payment_method_fee: usd("20"),
+            payment: usd("230"),
+            repatriation_commission: usd("10"),
+            invalid: BTreeMap::new(),
+        };
 
         let data = Message::Payment {
             purchase,
             user,
             transaction,
+            payment_details: Some(payment_details),
         };
 
         let msg = serde_json::from_str::<Message>(json).unwrap();
@@ -363,6 +554,29 @@ mod tests {
         assert_eq!(data, msg)
     }
 
+    #[test]
+    fn malformed_details_kept() {
+        let json = r#"
+        {
+            "payment": {"currency": "USD", "amount": 230},
+            "vat": {"currency": "USD", "amount": "n/a"},
+            "payout_currency_rate": "1,0",
+            "xsolla_fee": null
+        }
+        "#;
+
+        let details = serde_json::from_str::<PaymentDetails>(json).unwrap();
+
+        assert_eq!(details.payment, usd("230"));
+        assert_eq!(details.vat, None);
+        assert_eq!(details.payout_currency_rate, None);
+        assert_eq!(details.xsolla_fee, None);
+        assert_eq!(
+            details.invalid.keys().collect::<Vec<_>>(),
+            vec!["payout_currency_rate", "vat"]
+        );
+    }
+
     #[test]
     fn refund_deserialize() {
         let json = r#"
@@ -446,25 +660,48 @@ mod tests {
 
         let purchase = Purchase {
             virtual_currency: VirtualCurrency {
-                currency: String::from("USD"),
+                sku: None,
                 quantity: 10,
-                amount: 100,
+                price: Money::parse("100", "USD").unwrap(),
             },
+            virtual_items: Some(VirtualItems {
+                items: Some(vec![Item {
+                    sku: Some(String::from("test_item1")),
+                    amount: Some(1),
+                }]),
+                price: usd("50"),
+            }),
         };
 
         let user = User {
             id: String::from("1234567"),
+            country: Some(String::from("US")),
         };
 
-        let transaction = Transaction { id: 1 };
+        let transaction = Transaction {
+            id: 1,
+            payment_date: None,
+        };
 
         let refund_details = RefundDetails { code: 1 };
 
+        let payment_details = PaymentDetails {
+            xsolla_fee: usd("10"),
+            payout: usd("200"),
+            vat: None,
+            payout_currency_rate: None,
+            payment_method_fee: usd("20"),
+            payment: usd("230"),
+            repatriation_commission: usd("10"),
+            invalid: BTreeMap::new(),
+        };
+
         let data = Message::Refund {
             purchase,
             user,
             transaction,
             refund_details,
+            payment_details: Some(payment_details),
         };
 
         let msg = serde_json::from_str::<Message>(json).unwrap();
@@ -472,6 +709,50 @@ mod tests {
         assert_eq!(data, msg)
     }
 
+    #[test]
+    fn unknown_deserialize() {
+        let json = r#"
+        {
+            "notification_type": "afs_reject",
+            "transaction": {
+                "id": 1
+            }
+        }"#;
+
+        let msg = serde_json::from_str::<Message>(json).unwrap();
+
+        match msg {
+            Message::Unknown {
+                notification_type,
+                raw,
+            } => {
+                assert_eq!(notification_type, "afs_reject");
+                assert_eq!(raw["transaction"]["id"], 1);
+            }
+            _ => panic!("Expected unknown notification"),
+        }
+    }
+
+    #[test]
+    fn malformed_known_type_is_rejected() {
+        let json = r#"
+        {
+            "notification_type": "payment",
+            "user": {
+                "id": "1234567"
+            }
+        }"#;
+
+        assert!(serde_json::from_str::<Message>(json).is_err());
+    }
+
+    #[test]
+    fn missing_type_is_rejected() {
+        let json = r#"{"user": {"id": "1234567"}}"#;
+
+        assert!(serde_json::from_str::<Message>(json).is_err());
+    }
+
     #[test]
     fn error_serialize() {
         let json = r#"{"error":{"code":"INVALID_USER","message":"Invalid user"}}"#;
@@ -0,0 +1,231 @@
+use std::fmt;
+
+use serde::de::Error as DeError;
+use serde::{Deserialize, Deserializer};
+
+//ISO 4217 codes by number of minor units, every other active code has 2.
+const NO_MINOR_UNITS: &[&str] = &[
+    "BIF", "CLP", "DJF", "GNF", "ISK", "JPY", "KMF", "KRW", "PYG", "RWF", "UGX", "UYI", "VND",
+    "VUV", "XAF", "XOF", "XPF",
+];
+
+const THREE_MINOR_UNITS: &[&str] = &["BHD", "IQD", "JOD", "KWD", "LYD", "OMR", "TND"];
+
+const FOUR_MINOR_UNITS: &[&str] = &["CLF", "UYW"];
+
+const TWO_MINOR_UNITS: &[&str] = &[
+    "AED", "AFN", "ALL", "AMD", "ANG", "AOA", "ARS", "AUD", "AWG", "AZN", "BAM", "BBD", "BDT",
+    "BGN", "BMD", "BND", "BOB", "BOV", "BRL", "BSD", "BTN", "BWP", "BYN", "BZD", "CAD", "CDF",
+    "CHE", "CHF", "CHW", "CNY", "COP", "COU", "CRC", "CUP", "CVE", "CZK", "DKK", "DOP", "DZD",
+    "EGP", "ERN", "ETB", "EUR", "FJD", "FKP", "GBP", "GEL", "GHS", "GIP", "GMD", "GTQ", "GYD",
+    "HKD", "HNL", "HTG", "HUF", "IDR", "ILS", "INR", "IRR", "JMD", "KES", "KGS", "KHR", "KPW",
+    "KYD", "KZT", "LAK", "LBP", "LKR", "LRD", "LSL", "MAD", "MDL", "MGA", "MKD", "MMK", "MNT",
+    "MOP", "MRU", "MUR", "MVR", "MWK", "MXN", "MXV", "MYR", "MZN", "NAD", "NGN", "NIO", "NOK",
+    "NPR", "NZD", "PAB", "PEN", "PGK", "PHP", "PKR", "PLN", "QAR", "RON", "RSD", "RUB", "SAR",
+    "SBD", "SCR", "SDG", "SEK", "SGD", "SHP", "SLE", "SOS", "SRD", "SSP", "STN", "SVC", "SYP",
+    "SZL", "THB", "TJS", "TMT", "TOP", "TRY", "TTD", "TWD", "TZS", "UAH", "USD", "USN", "UYU",
+    "UZS", "VED", "VES", "WST", "XCD", "YER", "ZAR", "ZMW", "ZWL",
+];
+
+pub fn minor_units(currency: &str) -> Option<u32> {
+    if TWO_MINOR_UNITS.contains(&currency) {
+        Some(2)
+    } else if NO_MINOR_UNITS.contains(&currency) {
+        Some(0)
+    } else if THREE_MINOR_UNITS.contains(&currency) {
+        Some(3)
+    } else if FOUR_MINOR_UNITS.contains(&currency) {
+        Some(4)
+    } else {
+        None
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum MoneyError {
+    UnknownCurrency(String),
+    InvalidAmount(String),
+    //More decimals than the currency has minor units
+    TooPrecise(String, String),
+}
+
+impl fmt::Display for MoneyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MoneyError::UnknownCurrency(currency) => {
+                write!(f, "{} is not an ISO 4217 currency", currency)
+            }
+            MoneyError::InvalidAmount(amount) => write!(f, "{} is not a decimal amount", amount),
+            MoneyError::TooPrecise(amount, currency) => {
+                write!(f, "{} has too many decimals for {}", amount, currency)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MoneyError {}
+
+//Amount in minor units of the currency, 9.99 USD is 999 and 999 JPY is 999.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Money {
+    pub amount: i64,
+    pub currency: String,
+}
+
+impl Money {
+    pub fn new(amount: i64, currency: &str) -> Result<Money, MoneyError> {
+        minor_units(currency).ok_or_else(|| MoneyError::UnknownCurrency(currency.to_owned()))?;
+
+        Ok(Money {
+            amount,
+            currency: currency.to_owned(),
+        })
+    }
+
+    //Exact, "9.99" is never read as 9.98999...
+    pub fn parse(amount: &str, currency: &str) -> Result<Money, MoneyError> {
+        let units = minor_units(currency)
+            .ok_or_else(|| MoneyError::UnknownCurrency(currency.to_owned()))?;
+
+        let invalid = || MoneyError::InvalidAmount(amount.to_owned());
+
+        let (negative, digits) = match amount.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, amount),
+        };
+
+        let (whole, fraction) = match digits.find('.') {
+            Some(dot) => (&digits[..dot], &digits[dot + 1..]),
+            None => (digits, ""),
+        };
+
+        //Trailing zeros do not change the value, 100.00 JPY is fine
+        let fraction = fraction.trim_end_matches('0');
+
+        let is_digits = |s: &str| s.chars().all(|c| c.is_ascii_digit());
+
+        if whole.is_empty() || !is_digits(whole) || !is_digits(fraction) {
+            return Err(invalid());
+        }
+
+        if fraction.len() > units as usize {
+            return Err(MoneyError::TooPrecise(
+                amount.to_owned(),
+                currency.to_owned(),
+            ));
+        }
+
+        let fraction = format!("{:0<width$}", fraction, width = units as usize);
+
+        let minor = format!("{}{}", whole, fraction)
+            .parse::<i64>()
+            .map_err(|_| invalid())?;
+
+        Money::new(if negative { -minor } else { minor }, currency)
+    }
+
+    pub fn minor_units(&self) -> u32 {
+        minor_units(&self.currency).unwrap_or_default()
+    }
+
+    //Always with every minor unit, 9.90 not 9.9
+    pub fn to_decimal(&self) -> String {
+        let units = self.minor_units();
+        let sign = if self.amount < 0 { "-" } else { "" };
+        let amount = self.amount.unsigned_abs();
+
+        if units == 0 {
+            return format!("{}{}", sign, amount);
+        }
+
+        let scale = 10u64.pow(units);
+
+        format!(
+            "{}{}.{:0width$}",
+            sign,
+            amount / scale,
+            amount % scale,
+            width = units as usize
+        )
+    }
+}
+
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.to_decimal(), self.currency)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawAmount {
+    Integer(i64),
+    Decimal(f64),
+    Text(String),
+}
+
+#[derive(Deserialize)]
+struct RawMoney {
+    #[serde(rename = "currency")]
+    currency: String,
+
+    #[serde(rename = "amount")]
+    amount: RawAmount,
+}
+
+//Xsolla sends {"currency": "USD", "amount": 9.99}
+impl<'de> Deserialize<'de> for Money {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let raw = RawMoney::deserialize(deserializer)?;
+
+        //The shortest representation of a JSON number is the number as it was sent
+        let amount = match raw.amount {
+            RawAmount::Integer(amount) => amount.to_string(),
+            RawAmount::Decimal(amount) => amount.to_string(),
+            RawAmount::Text(amount) => amount,
+        };
+
+        Money::parse(&amount, &raw.currency).map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exact_decimals() {
+        assert_eq!(Money::parse("9.99", "USD").unwrap().amount, 999);
+        assert_eq!(Money::parse("1000", "JPY").unwrap().amount, 1000);
+        assert_eq!(Money::parse("1.250", "KWD").unwrap().amount, 1250);
+        assert_eq!(Money::parse("-0.5", "EUR").unwrap().to_decimal(), "-0.50");
+        assert_eq!(Money::parse("100.00", "JPY").unwrap().to_decimal(), "100");
+
+        assert_eq!(
+            Money::parse("9.99", "JPY"),
+            Err(MoneyError::TooPrecise("9.99".to_owned(), "JPY".to_owned()))
+        );
+        assert_eq!(
+            Money::parse("9.99", "usd"),
+            Err(MoneyError::UnknownCurrency("usd".to_owned()))
+        );
+        assert!(Money::parse("9,99", "USD").is_err());
+        assert!(Money::parse(".5", "USD").is_err());
+    }
+
+    #[test]
+    fn json_deserialize() {
+        let money: Money = serde_json::from_str(r#"{"currency":"USD","amount":0.1}"#).unwrap();
+
+        assert_eq!(money, Money::new(10, "USD").unwrap());
+
+        let money: Money = serde_json::from_str(r#"{"currency":"USD","amount":"19.90"}"#).unwrap();
+
+        assert_eq!(money.to_string(), "19.90 USD");
+
+        assert!(serde_json::from_str::<Money>(r#"{"currency":"XXX","amount":1}"#).is_err());
+    }
+}
@@ -0,0 +1,473 @@
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use actix_web::client::Client;
+use actix_web::http::header;
+use actix_web::web;
+
+use firestore_grpc_cloudrun::{
+    precondition::ConditionType, value::ValueType, write::Operation, CommitRequest, Document,
+    DocumentMask, ListDocumentsRequest, Precondition, Value, Write,
+};
+
+use futures::lock::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use tonic::Status;
+
+use crate::config::Config;
+use crate::logging::Line;
+use crate::MyData;
+
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Event {
+    //currency is absent for Credits
+    CreditsGranted {
+        user_id: String,
+        transaction_id: i64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        currency: Option<String>,
+        quantity: i64,
+    },
+    CreditsRevoked {
+        user_id: String,
+        transaction_id: i64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        currency: Option<String>,
+        quantity: i64,
+    },
+    ItemGranted {
+        user_id: String,
+        transaction_id: i64,
+        sku: String,
+        quantity: i64,
+    },
+    CreditsAdjusted {
+        user_id: String,
+        adjustment_id: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        currency: Option<String>,
+        quantity: i64,
+    },
+    CreditsSpent {
+        user_id: String,
+        spend_id: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        currency: Option<String>,
+        quantity: i64,
+    },
+}
+
+impl Event {
+    //Derived from the transaction so a resent notification can never publish twice
+    pub fn id(&self) -> String {
+        match self {
+            Event::CreditsGranted { transaction_id, .. } => {
+                format!("{}_credits_granted", transaction_id)
+            }
+            Event::CreditsRevoked { transaction_id, .. } => {
+                format!("{}_credits_revoked", transaction_id)
+            }
+            Event::ItemGranted {
+                transaction_id,
+                sku,
+                ..
+            } => format!("{}_item_granted_{}", transaction_id, sku),
+            //Keys are only unique per user
+            Event::CreditsAdjusted {
+                user_id,
+                adjustment_id,
+                ..
+            } => format!("{}_{}_credits_adjusted", user_id, adjustment_id),
+            Event::CreditsSpent {
+                user_id, spend_id, ..
+            } => format!("{}_{}_credits_spent", user_id, spend_id),
+        }
+    }
+}
+
+//What the endpoint receives, the id lets it drop duplicates.
+#[derive(Serialize)]
+struct Envelope<'a> {
+    id: String,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+fn timestamp_value(time: SystemTime) -> Value {
+    Value {
+        value_type: Some(ValueType::TimestampValue(prost_types::Timestamp::from(
+            time,
+        ))),
+    }
+}
+
+fn integer_value(value: i64) -> Value {
+    Value {
+        value_type: Some(ValueType::IntegerValue(value)),
+    }
+}
+
+//Write to commit along with the store update the event describes.
+pub fn write(project_id: &str, event: &Event) -> Write {
+    let id = event.id();
+
+    let payload = serde_json::to_string(&Envelope {
+        id: id.clone(),
+        event,
+    })
+    .expect("Trying to serialize event Error: ");
+
+    let now = SystemTime::now();
+
+    let mut data: HashMap<String, Value> = HashMap::with_capacity(4);
+
+    data.insert(
+        "Payload".to_owned(),
+        Value {
+            value_type: Some(ValueType::StringValue(payload)),
+        },
+    );
+    data.insert("CreatedDate".to_owned(), timestamp_value(now));
+    data.insert("NextAttempt".to_owned(), timestamp_value(now));
+    data.insert("Attempts".to_owned(), integer_value(0));
+
+    Write {
+        update_mask: None,
+        update_transforms: Vec::new(),
+        current_document: Some(Precondition {
+            condition_type: Some(ConditionType::Exists(false)),
+        }),
+        operation: Some(Operation::Update(Document {
+            name: format!(
+                "projects/{}/databases/(default)/documents/outbox/{}",
+                project_id, id
+            ),
+            fields: data,
+            create_time: None,
+            update_time: None,
+        })),
+    }
+}
+
+//Events listed at once, every page is walked on each tick.
+const PAGE_SIZE: i32 = 100;
+
+pub struct Dispatcher {
+    pub endpoint: String,
+    pub interval: Duration,
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+//Events are always written, they are only delivered once the endpoint is configured.
+pub fn get_dispatcher(config: &Config) -> Option<Dispatcher> {
+    Some(Dispatcher {
+        endpoint: config.outbox_endpoint.clone()?,
+        interval: config.outbox_poll_interval,
+        backoff: config.outbox_backoff,
+        max_backoff: Duration::from_secs(60 * 60),
+    })
+}
+
+fn to_system_time(timestamp: &prost_types::Timestamp) -> SystemTime {
+    UNIX_EPOCH + Duration::new(timestamp.seconds as u64, timestamp.nanos as u32)
+}
+
+impl Dispatcher {
+    fn backoff(&self, attempts: i64) -> Duration {
+        let exponent = attempts.clamp(0, 31) as u32;
+
+        self.backoff
+            .checked_mul(2u32.pow(exponent))
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff)
+    }
+
+    async fn post(&self, client: &Client, payload: String) -> bool {
+        let res = client
+            .post(&self.endpoint)
+            .header(header::CONTENT_TYPE, "application/json")
+            .send_body(payload)
+            .await;
+
+        match res {
+            Ok(res) => res.status().is_success(),
+            Err(_) => false,
+        }
+    }
+
+    //Delivers due events once, returns how many were delivered.
+    pub async fn dispatch(
+        &self,
+        data: &web::Data<Mutex<MyData>>,
+        client: &Client,
+    ) -> Result<usize, Status> {
+        let mut delivered = 0;
+        let mut page_token = String::new();
+
+        loop {
+            let (project_id, res) = {
+                let mut data = data.lock().await;
+
+                let req = ListDocumentsRequest {
+                    parent: format!("projects/{}/databases/(default)/documents", data.project_id),
+                    collection_id: "outbox".to_owned(),
+                    page_size: PAGE_SIZE,
+                    page_token,
+                    ..ListDocumentsRequest::default()
+                };
+
+                let res = data.client.list_documents(req).await?;
+
+                (data.project_id.clone(), res)
+            };
+
+            delivered += self
+                .deliver(data, client, &project_id, res.documents)
+                .await?;
+
+            if res.next_page_token.is_empty() {
+                break;
+            }
+
+            page_token = res.next_page_token;
+        }
+
+        Ok(delivered)
+    }
+
+    //Posts the due events of one page, delivered ones are deleted.
+    async fn deliver(
+        &self,
+        data: &web::Data<Mutex<MyData>>,
+        client: &Client,
+        project_id: &str,
+        events: Vec<Document>,
+    ) -> Result<usize, Status> {
+        let now = SystemTime::now();
+        let mut delivered = 0;
+
+        for event in events {
+            let payload = match event
+                .fields
+                .get("Payload")
+                .and_then(|v| v.value_type.as_ref())
+            {
+                Some(ValueType::StringValue(payload)) => payload.clone(),
+                _ => continue,
+            };
+
+            let attempts = match event
+                .fields
+                .get("Attempts")
+                .and_then(|v| v.value_type.as_ref())
+            {
+                Some(ValueType::IntegerValue(attempts)) => *attempts,
+                _ => 0,
+            };
+
+            if let Some(ValueType::TimestampValue(next)) = event
+                .fields
+                .get("NextAttempt")
+                .and_then(|v| v.value_type.as_ref())
+            {
+                if to_system_time(next) > now {
+                    continue;
+                }
+            }
+
+            let success = self.post(client, payload).await;
+
+            let write = if success {
+                delivered += 1;
+
+                Write {
+                    update_mask: None,
+                    update_transforms: Vec::new(),
+                    current_document: None,
+                    operation: Some(Operation::Delete(event.name)),
+                }
+            } else {
+                let mut fields: HashMap<String, Value> = HashMap::with_capacity(2);
+
+                fields.insert("Attempts".to_owned(), integer_value(attempts + 1));
+                fields.insert(
+                    "NextAttempt".to_owned(),
+                    timestamp_value(SystemTime::now() + self.backoff(attempts)),
+                );
+
+                Write {
+                    update_mask: Some(DocumentMask {
+                        field_paths: vec!["Attempts".to_owned(), "NextAttempt".to_owned()],
+                    }),
+                    update_transforms: Vec::new(),
+                    current_document: None,
+                    operation: Some(Operation::Update(Document {
+                        name: event.name,
+                        fields,
+                        create_time: None,
+                        update_time: None,
+                    })),
+                }
+            };
+
+            let req = CommitRequest {
+                database: format!("projects/{}/databases/(default)", project_id),
+                writes: vec![write],
+                transaction: Vec::new(),
+            };
+
+            data.lock().await.client.commit(req).await?;
+        }
+
+        Ok(delivered)
+    }
+
+    //Polls the outbox until the system stops.
+    pub fn spawn(self, data: web::Data<Mutex<MyData>>) {
+        actix_rt::spawn(async move {
+            let client = Client::default();
+
+            loop {
+                //Failures are retried on the next tick
+                if let Err(e) = self.dispatch(&data, &client).await {
+                    let mut line = Line::background("outbox dispatch failed", "ERROR");
+                    line.error = Some(e.to_string());
+                    line.write();
+                }
+
+                actix_rt::time::delay_for(self.interval).await;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+    use crate::test_support::test_data_with;
+    use actix_web::{test, App, HttpResponse};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn dispatcher(endpoint: String) -> Dispatcher {
+        Dispatcher {
+            endpoint,
+            interval: Duration::from_millis(10),
+            backoff: Duration::from_millis(0),
+            max_backoff: Duration::from_secs(1),
+        }
+    }
+
+    async fn data_with_events(events: &[Event]) -> web::Data<Mutex<MyData>> {
+        let mut store = MemoryStore::new();
+
+        let req = CommitRequest {
+            database: "projects/test/databases/(default)".to_owned(),
+            writes: events.iter().map(|event| write("test", event)).collect(),
+            transaction: Vec::new(),
+        };
+
+        crate::store::Store::commit(&mut store, req).await.unwrap();
+
+        test_data_with(Box::new(store))
+    }
+
+    fn event(transaction_id: i64) -> Event {
+        Event::CreditsGranted {
+            user_id: "1234567".to_owned(),
+            transaction_id,
+            currency: None,
+            quantity: 10,
+        }
+    }
+
+    #[test]
+    fn exponential_backoff() {
+        let dispatcher = Dispatcher {
+            backoff: Duration::from_secs(1),
+            ..dispatcher(String::new())
+        };
+
+        assert_eq!(dispatcher.backoff(0), Duration::from_secs(1));
+        assert_eq!(dispatcher.backoff(3), Duration::from_secs(1));
+        assert_eq!(
+            Dispatcher {
+                max_backoff: Duration::from_secs(60),
+                ..dispatcher
+            }
+            .backoff(3),
+            Duration::from_secs(8)
+        );
+    }
+
+    #[actix_rt::test]
+    async fn delivered_after_retry() {
+        let received = Arc::new(AtomicUsize::new(0));
+        let counter = received.clone();
+
+        //Stub receiver failing the first delivery
+        let srv = test::start(move || {
+            let counter = counter.clone();
+
+            App::new().route(
+                "/events",
+                web::post().to(move |body: web::Json<serde_json::Value>| {
+                    let attempt = counter.fetch_add(1, Ordering::SeqCst);
+
+                    assert_eq!(body["id"], "1_credits_granted");
+                    assert_eq!(body["type"], "CreditsGranted");
+
+                    if attempt == 0 {
+                        HttpResponse::ServiceUnavailable().finish()
+                    } else {
+                        HttpResponse::Ok().finish()
+                    }
+                }),
+            )
+        });
+
+        let data = data_with_events(&[event(1)]).await;
+        let dispatcher = dispatcher(srv.url("/events"));
+        let client = Client::default();
+
+        assert_eq!(dispatcher.dispatch(&data, &client).await.unwrap(), 0);
+        assert_eq!(dispatcher.dispatch(&data, &client).await.unwrap(), 1);
+        assert_eq!(dispatcher.dispatch(&data, &client).await.unwrap(), 0);
+
+        assert_eq!(received.load(Ordering::SeqCst), 2);
+    }
+
+    #[actix_rt::test]
+    async fn every_page_delivered() {
+        let received = Arc::new(AtomicUsize::new(0));
+        let counter = received.clone();
+
+        let srv = test::start(move || {
+            let counter = counter.clone();
+
+            App::new().route(
+                "/events",
+                web::post().to(move |_: web::Bytes| {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    HttpResponse::Ok().finish()
+                }),
+            )
+        });
+
+        let events: Vec<Event> = (1..=PAGE_SIZE as i64 + 1).map(event).collect();
+        let data = data_with_events(&events).await;
+        let dispatcher = dispatcher(srv.url("/events"));
+        let client = Client::default();
+
+        assert_eq!(
+            dispatcher.dispatch(&data, &client).await.unwrap(),
+            events.len()
+        );
+        assert_eq!(received.load(Ordering::SeqCst), events.len());
+    }
+}
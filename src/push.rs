@@ -0,0 +1,379 @@
+use std::collections::HashMap;
+
+use actix_codec::{Decoder, Encoder};
+use actix_http::ws;
+use actix_web::get;
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+
+use bytes::BytesMut;
+
+use chrono::Utc;
+
+use firestore_grpc_cloudrun::{value::ValueType, DocumentMask, GetDocumentRequest};
+
+use futures::channel::mpsc::{self, UnboundedSender};
+use futures::lock::Mutex;
+use futures::stream::StreamExt;
+
+use serde::{Deserialize, Serialize};
+
+use tonic::Code;
+
+use crate::admin::balances;
+use crate::handlers::log_store_error;
+use crate::logging::RequestId;
+use crate::models::Error as JsonError;
+use crate::models::ErrorMessage;
+use crate::signature_middleware::sign;
+use crate::MyData;
+
+//Token handed to the game client by the game backend, sha1(user_id + ":" + expires + secret)
+//expires is in unix seconds and sent next to the token.
+//The separator keeps a token of user 1234567 from passing for user 123456 expiring 7<expires>.
+pub fn token(user_id: &str, expires: i64, secret_key: &str) -> String {
+    sign(format!("{}:{}", user_id, expires).as_bytes(), secret_key)
+}
+
+//Takes as long whatever the first differing byte.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Update {
+    Balance { credits: i64 },
+    CurrencyBalance { currency: String, amount: i64 },
+    ItemGranted { sku: String, quantity: i64 },
+}
+
+//Connected clients by user ID, guarded by the MyData lock like the store.
+#[derive(Default)]
+pub struct Hub {
+    secret_key: Option<String>,
+    subscribers: HashMap<String, Vec<UnboundedSender<ws::Message>>>,
+}
+
+impl Hub {
+    pub fn new(secret_key: Option<String>) -> Self {
+        Hub {
+            secret_key,
+            subscribers: HashMap::new(),
+        }
+    }
+
+    fn authorize(&self, user_id: &str, auth: &Auth, now: i64) -> bool {
+        let secret_key = match &self.secret_key {
+            Some(secret_key) => secret_key,
+            None => return false,
+        };
+
+        if auth.expires <= now {
+            return false;
+        }
+
+        let expected = self::token(user_id, auth.expires, secret_key);
+
+        constant_time_eq(expected.as_bytes(), auth.token.as_bytes())
+    }
+
+    fn subscribe(&mut self, user_id: String, sender: UnboundedSender<ws::Message>) {
+        self.subscribers.entry(user_id).or_default().push(sender);
+    }
+
+    //Drops the closed senders of a user as soon as one disconnects
+    fn unsubscribe(&mut self, user_id: &str) {
+        if let Some(senders) = self.subscribers.get_mut(user_id) {
+            senders.retain(|sender| !sender.is_closed());
+
+            if senders.is_empty() {
+                self.subscribers.remove(user_id);
+            }
+        }
+    }
+
+    pub fn publish(&mut self, user_id: &str, update: &Update) {
+        let senders = match self.subscribers.get_mut(user_id) {
+            Some(senders) => senders,
+            None => return,
+        };
+
+        let text = serde_json::to_string(update).expect("Trying to serialize update Error: ");
+
+        //Disconnected clients are dropped here
+        senders.retain(|sender| {
+            sender
+                .unbounded_send(ws::Message::Text(text.clone()))
+                .is_ok()
+        });
+
+        if senders.is_empty() {
+            self.subscribers.remove(user_id);
+        }
+    }
+}
+
+const TOKEN_ERROR: ErrorMessage = ErrorMessage {
+    error: JsonError {
+        code: "INVALID_TOKEN",
+        message: "Invalid token",
+    },
+};
+
+#[derive(Deserialize)]
+pub struct Auth {
+    token: String,
+    expires: i64,
+}
+
+//Answers pings and closes, clients have nothing else to say.
+async fn read_frames(
+    payload: web::Payload,
+    sender: UnboundedSender<ws::Message>,
+    firestore: web::Data<Mutex<MyData>>,
+    user_id: String,
+) {
+    decode_frames(payload, &sender).await;
+
+    sender.close_channel();
+
+    firestore.lock().await.hub.unsubscribe(&user_id);
+}
+
+async fn decode_frames(mut payload: web::Payload, sender: &UnboundedSender<ws::Message>) {
+    let mut codec = ws::Codec::new();
+    let mut buf = BytesMut::new();
+
+    while let Some(Ok(chunk)) = payload.next().await {
+        buf.extend_from_slice(&chunk);
+
+        loop {
+            match codec.decode(&mut buf) {
+                Ok(Some(ws::Frame::Ping(msg))) => {
+                    let _ = sender.unbounded_send(ws::Message::Pong(msg));
+                }
+                Ok(Some(ws::Frame::Close(reason))) => {
+                    let _ = sender.unbounded_send(ws::Message::Close(reason));
+                    return;
+                }
+                Ok(Some(_)) => {}
+                Ok(None) => break,
+                Err(_) => return,
+            }
+        }
+    }
+}
+
+#[get("/balance/{user_id}")]
+async fn balance(
+    req: HttpRequest,
+    user_id: web::Path<String>,
+    auth: web::Query<Auth>,
+    payload: web::Payload,
+    firestore: web::Data<Mutex<MyData>>,
+    request_id: RequestId,
+) -> Result<HttpResponse, Error> {
+    let user_id = user_id.into_inner();
+
+    let data = firestore.clone();
+    let mut firestore = firestore.lock().await;
+
+    if !firestore
+        .hub
+        .authorize(&user_id, &auth, Utc::now().timestamp())
+    {
+        return Ok(HttpResponse::Unauthorized().json(TOKEN_ERROR));
+    }
+
+    let mut res = ws::handshake(req.head())?;
+
+    let req = GetDocumentRequest {
+        name: format!(
+            "projects/{}/databases/(default)/documents/users/{}",
+            firestore.project_id, user_id
+        ),
+        mask: Some(DocumentMask {
+            field_paths: vec!["Credits".to_owned(), "Balances".to_owned()],
+        }),
+        consistency_selector: None,
+    };
+
+    let user_doc = match firestore.client.get_document(req).await {
+        Ok(user_doc) => user_doc,
+        Err(error) => {
+            if let Code::NotFound = error.code() {
+                return Ok(HttpResponse::NotFound().finish());
+            } else {
+                return Ok(log_store_error(&request_id, &error));
+            }
+        }
+    };
+
+    let credits = match user_doc
+        .fields
+        .get("Credits")
+        .and_then(|v| v.value_type.as_ref())
+    {
+        Some(ValueType::IntegerValue(credits)) => *credits,
+        _ => 0,
+    };
+
+    let (sender, receiver) = mpsc::unbounded();
+
+    //Current balances first so the client never waits for a purchase to render
+    let mut updates = vec![Update::Balance { credits }];
+
+    for (currency, amount) in balances(&user_doc) {
+        updates.push(Update::CurrencyBalance { currency, amount });
+    }
+
+    for update in &updates {
+        let text = serde_json::to_string(update).expect("Trying to serialize update Error: ");
+        let _ = sender.unbounded_send(ws::Message::Text(text));
+    }
+
+    firestore.hub.subscribe(user_id.clone(), sender.clone());
+
+    actix_rt::spawn(read_frames(payload, sender, data, user_id));
+
+    let mut codec = ws::Codec::new();
+
+    let frames = receiver.map(move |msg| {
+        let mut buf = BytesMut::new();
+
+        codec.encode(msg, &mut buf)?;
+
+        Ok::<_, ws::ProtocolError>(buf.freeze())
+    });
+
+    Ok(res.streaming(frames))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers;
+    use crate::test_support::{self, create_user};
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+    use futures::SinkExt;
+
+    const SECRET: &str = "Push1Secret2Key3Test";
+
+    async fn test_data() -> web::Data<Mutex<MyData>> {
+        let data = test_support::test_data();
+        data.lock().await.hub = Hub::new(Some(SECRET.to_owned()));
+        create_user(&data, "1234567", 10, &[]).await;
+        data
+    }
+
+    fn update(frame: Option<Result<ws::Frame, ws::ProtocolError>>) -> Update {
+        match frame {
+            Some(Ok(ws::Frame::Text(text))) => serde_json::from_slice(&text).unwrap(),
+            frame => panic!("Expected a text frame, got {:?}", frame),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn wrong_token() {
+        let data = test_data().await;
+
+        let srv = test::start(move || App::new().app_data(data.clone()).service(balance));
+
+        let expires = Utc::now().timestamp() + 60;
+
+        let res = srv
+            .get(format!("/balance/1234567?token=0000&expires={}", expires))
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        //A leaked token stops working
+        let expired = Utc::now().timestamp() - 1;
+
+        let res = srv
+            .get(format!(
+                "/balance/1234567?token={}&expires={}",
+                token("1234567", expired, SECRET),
+                expired
+            ))
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        //Another user ID with the same digits
+        let res = srv
+            .get(format!(
+                "/balance/123456?token={}&expires=7{}",
+                token("1234567", expires, SECRET),
+                expires
+            ))
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_rt::test]
+    async fn payment_pushed() {
+        let data = test_data().await;
+        let hub = data.clone();
+
+        let mut srv = test::start(move || {
+            App::new()
+                .app_data(data.clone())
+                .service(balance)
+                .service(handlers::notifications)
+        });
+
+        let expires = Utc::now().timestamp() + 60;
+
+        let mut framed = srv
+            .ws_at(&format!(
+                "/balance/1234567?token={}&expires={}",
+                token("1234567", expires, SECRET),
+                expires
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(update(framed.next().await), Update::Balance { credits: 10 });
+
+        let payment = crate::simulator::payment("1234567", 1, 100);
+
+        let res = srv
+            .post("/webhook")
+            .header("content-type", "application/json")
+            .send_body(payment)
+            .await
+            .unwrap();
+
+        assert_eq!(res.status(), StatusCode::OK);
+
+        assert_eq!(
+            update(framed.next().await),
+            Update::Balance { credits: 110 }
+        );
+
+        framed
+            .send(ws::Message::Close(Some(ws::CloseCode::Normal.into())))
+            .await
+            .unwrap();
+
+        //Unsubscribed without waiting for the next publish
+        for _ in 0..50 {
+            if hub.lock().await.hub.subscribers.is_empty() {
+                return;
+            }
+
+            actix_rt::time::delay_for(std::time::Duration::from_millis(20)).await;
+        }
+
+        panic!("Expected the closed client to be unsubscribed");
+    }
+}
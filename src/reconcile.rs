@@ -0,0 +1,433 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Read;
+
+use actix_web::http::header;
+use actix_web::{test, web, App};
+
+use firestore_grpc_cloudrun::{Document, ListDocumentsRequest};
+
+use futures::lock::Mutex;
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use tonic::Status;
+
+use crate::admin::{cost, integer, string};
+use crate::handlers;
+use crate::money::{Money, MoneyError};
+use crate::store::Store;
+use crate::MyData;
+
+//One row of the Xsolla transactions report, other columns are ignored.
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+pub struct ExportRow {
+    #[serde(rename = "Transaction ID")]
+    pub transaction_id: i64,
+
+    #[serde(rename = "User ID")]
+    pub user_id: String,
+
+    //Kept as written, parsed with the currency
+    #[serde(rename = "Amount")]
+    pub amount: String,
+
+    #[serde(rename = "Currency")]
+    pub currency: String,
+
+    #[serde(rename = "Virtual Currency Quantity", default)]
+    pub quantity: Option<i64>,
+
+    //Picks the internal currency credited, see xsolla.virtual_currency_skus
+    #[serde(rename = "Virtual Currency SKU", default)]
+    pub sku: Option<String>,
+
+    #[serde(rename = "Status")]
+    pub status: String,
+}
+
+impl ExportRow {
+    pub fn price(&self) -> Result<Money, MoneyError> {
+        Money::parse(&self.amount, &self.currency)
+    }
+
+    pub fn refunded(&self) -> bool {
+        let status = self.status.to_lowercase();
+
+        status == "refunded" || status == "canceled" || status == "cancelled"
+    }
+}
+
+pub fn parse_export<R: Read>(reader: R) -> Result<Vec<ExportRow>, csv::Error> {
+    csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(reader)
+        .deserialize()
+        .collect()
+}
+
+#[derive(PartialEq, Debug, Clone, Serialize)]
+pub struct StoredTransaction {
+    pub transaction_id: i64,
+    pub user_id: String,
+    pub cost: Option<String>,
+    pub currency: Option<String>,
+    pub quantity: Option<i64>,
+    pub refunded: bool,
+}
+
+fn stored(user_id: &str, doc: &Document) -> Option<StoredTransaction> {
+    //Adjustments and spends never went through Xsolla
+    let transaction_id = doc.name.rsplit('/').next()?.parse().ok()?;
+
+    Some(StoredTransaction {
+        transaction_id,
+        user_id: user_id.to_owned(),
+        cost: cost(doc).map(|cost| cost.to_decimal()),
+        currency: string(doc, "Currency"),
+        quantity: integer(doc, "Quantity"),
+        refunded: doc.fields.contains_key("RefundDate"),
+    })
+}
+
+async fn list_all(
+    client: &mut (dyn Store + Send),
+    parent: String,
+    collection_id: &str,
+) -> Result<Vec<Document>, Status> {
+    let mut documents = Vec::new();
+    let mut page_token = String::new();
+
+    loop {
+        let req = ListDocumentsRequest {
+            parent: parent.clone(),
+            collection_id: collection_id.to_owned(),
+            page_size: 300,
+            page_token,
+            ..ListDocumentsRequest::default()
+        };
+
+        let res = client.list_documents(req).await?;
+
+        documents.extend(res.documents);
+
+        if res.next_page_token.is_empty() {
+            return Ok(documents);
+        }
+
+        page_token = res.next_page_token;
+    }
+}
+
+//Every users/*/transact/* document with the user it belongs to.
+pub(crate) async fn transact_documents(
+    client: &mut (dyn Store + Send),
+    project_id: &str,
+) -> Result<Vec<(String, Document)>, Status> {
+    let root = format!("projects/{}/databases/(default)/documents", project_id);
+
+    let mut documents = Vec::new();
+
+    for user in list_all(client, root, "users").await? {
+        let user_id = user.name.rsplit('/').next().unwrap_or_default().to_owned();
+
+        for doc in list_all(client, user.name.clone(), "transact").await? {
+            documents.push((user_id.clone(), doc));
+        }
+    }
+
+    Ok(documents)
+}
+
+//Every transaction created from a notification.
+pub async fn load_store(
+    client: &mut (dyn Store + Send),
+    project_id: &str,
+) -> Result<Vec<StoredTransaction>, Status> {
+    let documents = transact_documents(client, project_id).await?;
+
+    Ok(documents
+        .iter()
+        .filter_map(|(user_id, doc)| stored(user_id, doc))
+        .collect())
+}
+
+#[derive(PartialEq, Debug, Serialize)]
+pub struct Mismatch {
+    pub transaction_id: i64,
+    pub field: &'static str,
+    pub export: String,
+    pub store: String,
+}
+
+#[derive(PartialEq, Debug, Default, Serialize)]
+pub struct Report {
+    pub missing: Vec<ExportRow>,
+    //Rows repeating a transaction ID already seen in the export
+    pub duplicates: Vec<ExportRow>,
+    pub extra: Vec<StoredTransaction>,
+    pub mismatched: Vec<Mismatch>,
+}
+
+impl Report {
+    pub fn is_clean(&self) -> bool {
+        self.missing.is_empty()
+            && self.duplicates.is_empty()
+            && self.extra.is_empty()
+            && self.mismatched.is_empty()
+    }
+}
+
+fn show<T: ToString>(value: &Option<T>) -> String {
+    match value {
+        Some(value) => value.to_string(),
+        None => "none".to_owned(),
+    }
+}
+
+pub fn compare(export: &[ExportRow], store: &[StoredTransaction]) -> Report {
+    let mut stored: BTreeMap<i64, &StoredTransaction> = store
+        .iter()
+        .map(|transaction| (transaction.transaction_id, transaction))
+        .collect();
+
+    let mut seen = BTreeSet::new();
+    let mut report = Report::default();
+
+    for row in export {
+        if !seen.insert(row.transaction_id) {
+            report.duplicates.push(row.clone());
+            continue;
+        }
+
+        let transaction = match stored.remove(&row.transaction_id) {
+            Some(transaction) => transaction,
+            None => {
+                report.missing.push(row.clone());
+                continue;
+            }
+        };
+
+        let mut mismatch = |field, export: String, store: String| {
+            if export != store {
+                report.mismatched.push(Mismatch {
+                    transaction_id: row.transaction_id,
+                    field,
+                    export,
+                    store,
+                });
+            }
+        };
+
+        mismatch("user_id", row.user_id.clone(), transaction.user_id.clone());
+        //Compared with every minor unit, 9.9 and 9.90 are the same
+        let amount = match row.price() {
+            Ok(price) => price.to_decimal(),
+            Err(_) => row.amount.clone(),
+        };
+
+        mismatch("amount", amount, show(&transaction.cost));
+        mismatch(
+            "currency",
+            row.currency.clone(),
+            show(&transaction.currency),
+        );
+
+        if row.quantity.is_some() {
+            mismatch("quantity", show(&row.quantity), show(&transaction.quantity));
+        }
+
+        mismatch(
+            "refunded",
+            row.refunded().to_string(),
+            transaction.refunded.to_string(),
+        );
+    }
+
+    report.extra = stored.into_values().cloned().collect();
+
+    report
+}
+
+//Notifications Xsolla should have sent for a missing row, payment then refund.
+pub fn notifications(row: &ExportRow) -> Result<Vec<String>, String> {
+    let quantity = row
+        .quantity
+        .ok_or_else(|| format!("{} has no virtual currency quantity", row.transaction_id))?;
+
+    let price = row
+        .price()
+        .map_err(|e| format!("{} {}", row.transaction_id, e))?;
+
+    //A JSON number like Xsolla sends, the decimal is its shortest representation
+    let amount: serde_json::Value =
+        serde_json::from_str(&price.to_decimal()).map_err(|e| e.to_string())?;
+
+    let purchase = json!({
+        "virtual_currency": {
+            "sku": row.sku,
+            "quantity": quantity,
+            "currency": price.currency,
+            "amount": amount
+        }
+    });
+
+    let transaction = json!({ "id": row.transaction_id });
+    let user = json!({ "id": row.user_id });
+
+    let mut notifications = vec![json!({
+        "notification_type": "payment",
+        "purchase": purchase,
+        "user": user,
+        "transaction": transaction
+    })
+    .to_string()];
+
+    if row.refunded() {
+        notifications.push(
+            json!({
+                "notification_type": "refund",
+                "purchase": purchase,
+                "user": user,
+                "transaction": transaction,
+                "refund_details": { "code": 0 }
+            })
+            .to_string(),
+        );
+    }
+
+    Ok(notifications)
+}
+
+//Processes the notifications through the handler, returns the response statuses.
+pub async fn replay(data: web::Data<Mutex<MyData>>, row: &ExportRow) -> Result<Vec<u16>, String> {
+    //Without the SKU the quantity would be credited as Credits
+    if row.sku.is_none() && !data.lock().await.sku_currencies.is_empty() {
+        return Err(format!(
+            "{} has no virtual currency SKU, SKUs are mapped to currencies",
+            row.transaction_id
+        ));
+    }
+
+    let app = App::new().app_data(data).service(handlers::notifications);
+    let mut app = test::init_service(app).await;
+
+    let mut statuses = Vec::new();
+
+    for payload in notifications(row)? {
+        let req = test::TestRequest::post()
+            .uri("/webhook")
+            .header(header::CONTENT_TYPE, "application/json")
+            .set_payload(payload)
+            .to_request();
+
+        statuses.push(test::call_service(&mut app, req).await.status().as_u16());
+    }
+
+    Ok(statuses)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator;
+    use crate::test_support::{create_user, test_data};
+
+    const EXPORT: &str = "\
+Transaction ID,Project ID,User ID,Amount,Currency,Virtual Currency Quantity,Virtual Currency SKU,Status
+1,1000,1234567,100,USD,10,test_package1,done
+2,1000,1234567,200,USD,20,test_package1,refunded
+3,1000,1234567,50,USD,5,gems_pack,done
+1,1000,1234567,100,USD,10,test_package1,done
+";
+
+    #[test]
+    fn export_parsed() {
+        let rows = parse_export(EXPORT.as_bytes()).unwrap();
+
+        assert_eq!(rows.len(), 4);
+        assert_eq!(rows[1].quantity, Some(20));
+        assert!(rows[1].refunded());
+    }
+
+    #[actix_rt::test]
+    async fn missing_extra_and_mismatched() {
+        let data = test_data();
+        create_user(&data, "1234567", 0, &[]).await;
+
+        let app = App::new()
+            .app_data(data.clone())
+            .service(handlers::notifications);
+        let mut app = test::init_service(app).await;
+
+        //1 matches, 2 was never refunded, 4 is not in the export
+        for payload in &[
+            simulator::payment("1234567", 1, 10),
+            simulator::payment("1234567", 2, 20),
+            simulator::payment("1234567", 4, 40),
+        ] {
+            let req = test::TestRequest::post()
+                .uri("/webhook")
+                .header(header::CONTENT_TYPE, "application/json")
+                .set_payload(payload.clone())
+                .to_request();
+
+            test::call_service(&mut app, req).await;
+        }
+
+        let export = parse_export(EXPORT.as_bytes()).unwrap();
+
+        let stored = {
+            let mut data = data.lock().await;
+            load_store(data.client.as_mut(), "test").await.unwrap()
+        };
+
+        let report = compare(&export, &stored);
+
+        assert_eq!(report.missing.len(), 1);
+        assert_eq!(report.missing[0].transaction_id, 3);
+        assert_eq!(report.duplicates.len(), 1);
+        assert_eq!(report.duplicates[0].transaction_id, 1);
+        assert_eq!(report.extra.len(), 1);
+        assert_eq!(report.extra[0].transaction_id, 4);
+        assert_eq!(
+            report.mismatched,
+            vec![Mismatch {
+                transaction_id: 2,
+                field: "refunded",
+                export: "true".to_owned(),
+                store: "false".to_owned(),
+            }]
+        );
+
+        let without_sku = ExportRow {
+            sku: None,
+            ..report.missing[0].clone()
+        };
+
+        assert!(replay(data.clone(), &without_sku).await.is_err());
+
+        assert_eq!(
+            replay(data.clone(), &report.missing[0]).await.unwrap(),
+            vec![200]
+        );
+
+        let (stored, user_doc) = {
+            let mut data = data.lock().await;
+
+            let req = firestore_grpc_cloudrun::GetDocumentRequest {
+                name: "projects/test/databases/(default)/documents/users/1234567".to_owned(),
+                mask: None,
+                consistency_selector: None,
+            };
+
+            (
+                load_store(data.client.as_mut(), "test").await.unwrap(),
+                data.client.get_document(req).await.unwrap(),
+            )
+        };
+
+        assert!(compare(&export, &stored).missing.is_empty());
+        assert_eq!(crate::admin::balances(&user_doc).get("gems"), Some(&5));
+    }
+}
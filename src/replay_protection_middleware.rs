@@ -0,0 +1,384 @@
+use actix_http::h1;
+use actix_service::{Service, Transform};
+use actix_web::http::StatusCode;
+use actix_web::{dev::ServiceRequest, dev::ServiceResponse, Error, HttpMessage, HttpResponse};
+use bytes::BytesMut;
+use chrono::{DateTime, Utc};
+use futures::future::{ok, Future, Ready};
+use futures::stream::StreamExt;
+use sha1::{Digest, Sha1};
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use crate::logging::{Line, RequestId};
+use crate::metrics;
+use crate::models::Error as JsonError;
+use crate::models::ErrorMessage;
+
+//Keeps memory bounded whatever the rate, the oldest bodies are forgotten first.
+const MAX_SEEN_BODIES: usize = 100_000;
+
+const DUPLICATE_ERROR: ErrorMessage = ErrorMessage {
+    error: JsonError {
+        code: "INVALID_PARAMETER",
+        message: "Notification already received",
+    },
+};
+
+const STALE_ERROR: ErrorMessage = ErrorMessage {
+    error: JsonError {
+        code: "INVALID_PARAMETER",
+        message: "payment_date outside the allowed window",
+    },
+};
+
+//What is known of a body already received.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Seen {
+    First,
+    //Still being processed
+    InFlight,
+    //Processed with this success status
+    Done(u16),
+}
+
+//sha1 of the bodies received during the last ttl, in arrival order.
+pub struct SeenBodies {
+    ttl: Duration,
+    order: VecDeque<(Instant, [u8; 20])>,
+    seen: HashMap<[u8; 20], Option<u16>>,
+}
+
+impl SeenBodies {
+    pub fn new(ttl: Duration) -> Self {
+        SeenBodies {
+            ttl,
+            order: VecDeque::new(),
+            seen: HashMap::new(),
+        }
+    }
+
+    fn prune(&mut self, now: Instant) {
+        while let Some((received, hash)) = self.order.front().copied() {
+            if now.duration_since(received) < self.ttl && self.order.len() < MAX_SEEN_BODIES {
+                break;
+            }
+
+            self.order.pop_front();
+            self.seen.remove(&hash);
+        }
+    }
+
+    //Remembered as in flight when first seen
+    pub fn insert(&mut self, hash: [u8; 20], now: Instant) -> Seen {
+        self.prune(now);
+
+        match self.seen.get(&hash) {
+            Some(Some(status)) => return Seen::Done(*status),
+            Some(None) => return Seen::InFlight,
+            None => {}
+        }
+
+        self.seen.insert(hash, None);
+        self.order.push_back((now, hash));
+
+        Seen::First
+    }
+
+    //A resent body gets the same answer without being processed again
+    pub fn finish(&mut self, hash: &[u8; 20], status: u16) {
+        if let Some(done) = self.seen.get_mut(hash) {
+            *done = Some(status);
+        }
+    }
+
+    //Xsolla resends after anything but a 2xx, the resent body must go through
+    pub fn forget(&mut self, hash: &[u8; 20]) {
+        if self.seen.remove(hash).is_some() {
+            self.order.retain(|(_, seen)| seen != hash);
+        }
+    }
+}
+
+//Only payment_date tells when a notification was sent,
+//refunds carry the date of the payment they cancel.
+fn payment_date(json: &serde_json::Value) -> Option<DateTime<Utc>> {
+    if json.get("notification_type")?.as_str()? != "payment" {
+        return None;
+    }
+
+    let date = json.get("transaction")?.get("payment_date")?.as_str()?;
+
+    DateTime::parse_from_rfc3339(date)
+        .ok()
+        .map(|date| date.with_timezone(&Utc))
+}
+
+fn outside_window(date: DateTime<Utc>, clock_skew: Duration) -> bool {
+    let skew = (Utc::now() - date).num_seconds().unsigned_abs();
+
+    skew > clock_skew.as_secs()
+}
+
+//Must be inside VerifySignature, only signed bodies are remembered.
+#[derive(Clone)]
+pub struct RejectReplays {
+    seen: Arc<Mutex<SeenBodies>>,
+    clock_skew: Duration,
+}
+
+impl RejectReplays {
+    pub fn new(ttl: Duration, clock_skew: Duration) -> Self {
+        RejectReplays {
+            seen: Arc::new(Mutex::new(SeenBodies::new(ttl))),
+            clock_skew,
+        }
+    }
+}
+
+impl<S: 'static, B> Transform<S> for RejectReplays
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RejectReplaysMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RejectReplaysMiddleware {
+            service: Rc::new(RefCell::new(service)),
+            seen: self.seen.clone(),
+            clock_skew: self.clock_skew,
+        })
+    }
+}
+
+pub struct RejectReplaysMiddleware<S> {
+    service: Rc<RefCell<S>>,
+    seen: Arc<Mutex<SeenBodies>>,
+    clock_skew: Duration,
+}
+
+fn reject<B>(
+    req: ServiceRequest,
+    json: Option<serde_json::Value>,
+    error: &'static str,
+    resp: HttpResponse,
+) -> ServiceResponse<B> {
+    let request_id = req.extensions().get::<RequestId>().cloned();
+    let request_id = request_id.unwrap_or_else(RequestId::generate);
+
+    //A resent body that was already processed gets its answer again
+    let answered = resp.status().is_success();
+    let message = if answered {
+        "replay answered"
+    } else {
+        "replay rejected"
+    };
+
+    let mut line = Line::new(message, &request_id);
+    line.notification_type = json
+        .as_ref()
+        .and_then(|json| json.get("notification_type")?.as_str())
+        .map(str::to_owned);
+    line.user_id = json
+        .as_ref()
+        .and_then(|json| json.get("user")?.get("id")?.as_str())
+        .map(str::to_owned);
+    line.transaction_id = json
+        .as_ref()
+        .and_then(|json| json.get("transaction")?.get("id")?.as_i64());
+    line.error = Some(error.to_owned()).filter(|_| !answered);
+    line.finish(resp.status());
+    line.write();
+
+    if !answered {
+        metrics::REJECTED_REQUESTS
+            .with_label_values(&["reject_replays"])
+            .inc();
+    }
+
+    req.into_response(resp.into_body())
+}
+
+impl<S, B> Service for RejectReplaysMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: ServiceRequest) -> Self::Future {
+        let mut svc = self.service.clone();
+        let seen = self.seen.clone();
+        let clock_skew = self.clock_skew;
+
+        Box::pin(async move {
+            let mut hasher = Sha1::new();
+            let mut body = BytesMut::new();
+            let mut stream = req.take_payload();
+
+            //Already limited by VerifySignature
+            while let Some(chunk) = stream.next().await {
+                let chunk = chunk?;
+                hasher.input(&chunk);
+                body.extend_from_slice(&chunk);
+            }
+
+            let body = body.freeze();
+            let json = serde_json::from_slice::<serde_json::Value>(&body).ok();
+
+            //Put the body back for the handler
+            let (_, mut payload) = h1::Payload::create(true);
+            payload.unread_data(body);
+            req.set_payload(payload.into());
+
+            let mut hash = [0; 20];
+            hash.copy_from_slice(&hasher.result());
+
+            if let Some(date) = json.as_ref().and_then(payment_date) {
+                if outside_window(date, clock_skew) {
+                    let resp = HttpResponse::BadRequest().json(STALE_ERROR);
+
+                    return Ok(reject(req, json, "payment_date outside window", resp));
+                }
+            }
+
+            let first = seen
+                .lock()
+                .expect("Trying to lock seen bodies Error: ")
+                .insert(hash, Instant::now());
+
+            match first {
+                Seen::First => {}
+                Seen::InFlight => {
+                    let resp = HttpResponse::BadRequest().json(DUPLICATE_ERROR);
+
+                    return Ok(reject(req, json, "duplicate body", resp));
+                }
+                Seen::Done(status) => {
+                    let status = StatusCode::from_u16(status).unwrap_or(StatusCode::OK);
+                    let resp = HttpResponse::build(status).finish();
+
+                    return Ok(reject(req, json, "duplicate body", resp));
+                }
+            }
+
+            let res = svc.call(req).await;
+
+            let mut seen = seen.lock().expect("Trying to lock seen bodies Error: ");
+
+            match &res {
+                Ok(res) if res.status().is_success() => seen.finish(&hash, res.status().as_u16()),
+                _ => seen.forget(&hash),
+            }
+
+            drop(seen);
+
+            res
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers;
+    use crate::store::MemoryStore;
+    use crate::test_support::{test_data_with, FlakyStore};
+    use actix_web::http::{header, StatusCode};
+    use actix_web::test::{self, TestRequest};
+    use actix_web::App;
+    use serde_json::Value;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn webhook(payload: &str) -> actix_http::Request {
+        TestRequest::post()
+            .uri("/webhook")
+            .header(header::CONTENT_TYPE, "application/json")
+            .set_payload(payload.to_owned())
+            .to_request()
+    }
+
+    #[actix_rt::test]
+    async fn replays_rejected() {
+        let failures = Arc::new(AtomicUsize::new(0));
+
+        let data = test_data_with(Box::new(FlakyStore::new(
+            MemoryStore::new(),
+            failures.clone(),
+        )));
+
+        let app = App::new()
+            .app_data(data)
+            .wrap(RejectReplays::new(
+                Duration::from_secs(60),
+                Duration::from_secs(3600),
+            ))
+            .service(handlers::notifications);
+        let mut app = test::init_service(app).await;
+
+        let validation = crate::simulator::user_validation("1234567");
+
+        //Xsolla resends after a 5xx, that is not a replay
+        failures.store(1, Ordering::SeqCst);
+
+        let resp = test::call_service(&mut app, webhook(&validation)).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let resp = test::call_service(&mut app, webhook(&validation)).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let body: Value = serde_json::from_slice(&test::read_body(resp).await).unwrap();
+        assert_eq!(body["error"]["message"], "Invalid user");
+
+        //Nor after a 4xx
+        let resp = test::call_service(&mut app, webhook(&validation)).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let body: Value = serde_json::from_slice(&test::read_body(resp).await).unwrap();
+        assert_eq!(body["error"]["message"], "Invalid user");
+
+        //A processed body is answered as before without being stored again
+        let unknown = r#"{"notification_type": "afs_reject", "transaction": {"id": 1}}"#;
+
+        let resp = test::call_service(&mut app, webhook(unknown)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        failures.store(1, Ordering::SeqCst);
+
+        let resp = test::call_service(&mut app, webhook(unknown)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(failures.load(Ordering::SeqCst), 1);
+
+        //The simulator payment is dated 2014
+        let payment = crate::simulator::payment("1234567", 1, 10);
+
+        let resp = test::call_service(&mut app, webhook(&payment)).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let body: Value = serde_json::from_slice(&test::read_body(resp).await).unwrap();
+        assert_eq!(
+            body["error"]["message"],
+            "payment_date outside the allowed window"
+        );
+    }
+}
@@ -0,0 +1,286 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+
+use chrono::NaiveDate;
+
+use firestore_grpc_cloudrun::{value::ValueType, Document, Value};
+
+use serde::Serialize;
+
+use tonic::Status;
+
+use crate::admin::{cost, string, timestamp, to_date};
+use crate::money::Money;
+use crate::reconcile::transact_documents;
+use crate::store::Store;
+
+//One line of the revenue report, every amount is a decimal in its currency.
+#[derive(PartialEq, Debug, Clone, Serialize)]
+pub struct Row {
+    pub date: String,
+    pub country: Option<String>,
+    pub currency: String,
+    pub payments: u64,
+    pub gross: String,
+    pub vat: String,
+    pub fees: String,
+    pub payout: String,
+    pub refunds: u64,
+    pub refunded: String,
+}
+
+//Sums in minor units, never rounded.
+#[derive(Default)]
+struct Totals {
+    payments: u64,
+    gross: i64,
+    vat: i64,
+    fees: i64,
+    payout: i64,
+    refunds: u64,
+    refunded: i64,
+}
+
+fn details(doc: &Document) -> Option<&HashMap<String, Value>> {
+    match doc
+        .fields
+        .get("PaymentDetails")
+        .and_then(|v| v.value_type.as_ref())
+    {
+        Some(ValueType::MapValue(map)) => Some(&map.fields),
+        _ => None,
+    }
+}
+
+fn money(details: Option<&HashMap<String, Value>>, field: &str) -> Option<Money> {
+    let fields = match details?.get(field).and_then(|v| v.value_type.as_ref()) {
+        Some(ValueType::MapValue(map)) => &map.fields,
+        _ => return None,
+    };
+
+    let amount = match fields.get("Amount").and_then(|v| v.value_type.as_ref()) {
+        Some(ValueType::StringValue(amount)) => amount,
+        _ => return None,
+    };
+
+    match fields.get("Currency").and_then(|v| v.value_type.as_ref()) {
+        Some(ValueType::StringValue(currency)) => Money::parse(amount, currency).ok(),
+        _ => None,
+    }
+}
+
+type Rows = BTreeMap<(NaiveDate, Option<String>, String), Totals>;
+
+fn totals<'a>(
+    rows: &'a mut Rows,
+    date: NaiveDate,
+    country: &Option<String>,
+    currency: String,
+) -> &'a mut Totals {
+    rows.entry((date, country.clone(), currency)).or_default()
+}
+
+//Payments count on their payment date, refunds on their refund date, both inclusive.
+pub fn build(documents: &[(String, Document)], from: NaiveDate, to: NaiveDate) -> Vec<Row> {
+    let mut rows = Rows::new();
+
+    let in_period = |date: NaiveDate| from <= date && date <= to;
+
+    for (_, doc) in documents {
+        //Adjustments and spends never went through Xsolla
+        let id = doc.name.rsplit('/').next().unwrap_or_default();
+
+        if id.parse::<i64>().is_err() {
+            continue;
+        }
+
+        let details = details(doc);
+        let country = string(doc, "Country");
+
+        //Documents stored before payment details were kept only have the cost
+        let gross = money(details, "Payment").or_else(|| cost(doc));
+
+        let paid = timestamp(doc, "PaymentDate").or_else(|| doc.create_time.as_ref().map(to_date));
+
+        if let Some(paid) = paid.map(|date| date.naive_utc().date()) {
+            if in_period(paid) {
+                if let Some(gross) = gross.clone() {
+                    let totals = totals(&mut rows, paid, &country, gross.currency);
+                    totals.payments += 1;
+                    totals.gross += gross.amount;
+                }
+
+                if let Some(vat) = money(details, "Vat") {
+                    totals(&mut rows, paid, &country, vat.currency).vat += vat.amount;
+                }
+
+                for fee in &["XsollaFee", "PaymentMethodFee", "RepatriationCommission"] {
+                    if let Some(fee) = money(details, fee) {
+                        totals(&mut rows, paid, &country, fee.currency).fees += fee.amount;
+                    }
+                }
+
+                if let Some(payout) = money(details, "Payout") {
+                    totals(&mut rows, paid, &country, payout.currency).payout += payout.amount;
+                }
+            }
+        }
+
+        let refunded = timestamp(doc, "RefundDate").map(|date| date.naive_utc().date());
+
+        if let (Some(refunded), Some(gross)) = (refunded, gross) {
+            if in_period(refunded) {
+                let totals = totals(&mut rows, refunded, &country, gross.currency);
+                totals.refunds += 1;
+                totals.refunded += gross.amount;
+            }
+        }
+    }
+
+    rows.into_iter()
+        .map(|((date, country, currency), totals)| {
+            let decimal = |amount| {
+                Money {
+                    amount,
+                    currency: currency.clone(),
+                }
+                .to_decimal()
+            };
+
+            Row {
+                date: date.to_string(),
+                gross: decimal(totals.gross),
+                vat: decimal(totals.vat),
+                fees: decimal(totals.fees),
+                payout: decimal(totals.payout),
+                refunded: decimal(totals.refunded),
+                payments: totals.payments,
+                refunds: totals.refunds,
+                country,
+                currency,
+            }
+        })
+        .collect()
+}
+
+pub async fn load_report(
+    client: &mut (dyn Store + Send),
+    project_id: &str,
+    from: NaiveDate,
+    to: NaiveDate,
+) -> Result<Vec<Row>, Status> {
+    let documents = transact_documents(client, project_id).await?;
+
+    Ok(build(&documents, from, to))
+}
+
+const HEADERS: [&str; 10] = [
+    "date", "country", "currency", "payments", "gross", "vat", "fees", "payout", "refunds",
+    "refunded",
+];
+
+//Headers are written even for a period without transactions.
+pub fn write_csv<W: Write>(rows: &[Row], writer: W) -> Result<(), csv::Error> {
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(writer);
+
+    writer.write_record(HEADERS)?;
+
+    for row in rows {
+        writer.serialize(row)?;
+    }
+
+    writer.flush()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers;
+    use crate::simulator;
+    use crate::test_support::{create_user, test_data};
+    use actix_web::http::header;
+    use actix_web::{test, App};
+    use chrono::Utc;
+
+    fn row(date: &str, payments: u64, gross: &str, refunds: u64, refunded: &str) -> Row {
+        Row {
+            date: date.to_owned(),
+            country: Some("US".to_owned()),
+            currency: "USD".to_owned(),
+            payments,
+            gross: gross.to_owned(),
+            vat: "0.00".to_owned(),
+            fees: "0.00".to_owned(),
+            payout: gross.to_owned(),
+            refunds,
+            refunded: refunded.to_owned(),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn payments_and_refunds_by_day() {
+        let data = test_data();
+        create_user(&data, "1234567", 0, &[]).await;
+
+        let app = App::new()
+            .app_data(data.clone())
+            .service(handlers::notifications);
+        let mut app = test::init_service(app).await;
+
+        //Both paid 2014-09-24, the second refunded today
+        for payload in &[
+            simulator::payment("1234567", 1, 10),
+            simulator::payment("1234567", 2, 20),
+            simulator::refund("1234567", 2, 20),
+        ] {
+            let req = test::TestRequest::post()
+                .uri("/webhook")
+                .header(header::CONTENT_TYPE, "application/json")
+                .set_payload(payload.clone())
+                .to_request();
+
+            test::call_service(&mut app, req).await;
+        }
+
+        let mut data = data.lock().await;
+
+        let september = load_report(
+            data.client.as_mut(),
+            "test",
+            NaiveDate::from_ymd(2014, 9, 1),
+            NaiveDate::from_ymd(2014, 9, 30),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(september, vec![row("2014-09-24", 2, "300.00", 0, "0.00")]);
+
+        let today = Utc::now().naive_utc().date();
+
+        let refunds = load_report(data.client.as_mut(), "test", today, today)
+            .await
+            .unwrap();
+
+        let mut refund = row(&today.to_string(), 0, "0.00", 1, "200.00");
+        refund.payout = "0.00".to_owned();
+
+        assert_eq!(refunds, vec![refund]);
+    }
+
+    #[test]
+    fn csv_written() {
+        let mut csv = Vec::new();
+
+        write_csv(&[row("2014-09-24", 2, "300.50", 0, "0.00")], &mut csv).unwrap();
+
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "date,country,currency,payments,gross,vat,fees,payout,refunds,refunded\n\
+             2014-09-24,US,USD,2,300.50,0.00,0.00,300.50,0,0.00\n"
+        );
+    }
+}
@@ -0,0 +1,297 @@
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+
+use firestore_grpc_cloudrun::{
+    CommitRequest, CommitResponse, CreateDocumentRequest, Document, GetDocumentRequest,
+    ListDocumentsRequest, ListDocumentsResponse, UpdateDocumentRequest,
+};
+
+use tonic::{Code, Status};
+
+use crate::config::Config;
+use crate::metrics;
+use crate::store::Store;
+use crate::telemetry::SpanContext;
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Policy {
+    pub timeout: Duration,
+    pub retries: u32,
+    pub backoff: Duration,
+    //Failed calls in a row before the store is considered down
+    pub breaker_threshold: u32,
+    pub breaker_cooldown: Duration,
+}
+
+impl Policy {
+    pub fn new(config: &Config) -> Self {
+        Policy {
+            timeout: config.store_timeout,
+            retries: config.store_retries,
+            backoff: config.store_retry_backoff,
+            breaker_threshold: config.store_breaker_threshold,
+            breaker_cooldown: config.store_breaker_cooldown,
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+enum Breaker {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    //One trial call decides whether it closes or opens again
+    HalfOpen,
+}
+
+//The store may answer later, the call can be repeated as is.
+fn retriable(code: Code) -> bool {
+    matches!(
+        code,
+        Code::Unavailable | Code::Aborted | Code::DeadlineExceeded
+    )
+}
+
+//Deadline per attempt, retries with exponential backoff and a circuit breaker,
+//so a stuck or down store never holds the global lock for long.
+pub struct ResilientStore<S> {
+    store: S,
+    policy: Policy,
+    breaker: Breaker,
+}
+
+impl<S> ResilientStore<S> {
+    pub fn new(store: S, policy: Policy) -> Self {
+        ResilientStore {
+            store,
+            policy,
+            breaker: Breaker::Closed { failures: 0 },
+        }
+    }
+
+    //How many retries the call gets, fails fast while the circuit is open
+    fn admit(&mut self) -> Result<u32, Status> {
+        match self.breaker {
+            Breaker::Closed { .. } => Ok(self.policy.retries),
+            Breaker::Open { until } if Instant::now() < until => {
+                Err(Status::unavailable("Store circuit open"))
+            }
+            Breaker::Open { .. } | Breaker::HalfOpen => {
+                self.breaker = Breaker::HalfOpen;
+                Ok(0)
+            }
+        }
+    }
+
+    //Any answer other than a retriable error means the store is up
+    fn record<T>(&mut self, result: &Result<T, Status>) {
+        let failed = matches!(result, Err(status) if retriable(status.code()));
+
+        self.breaker = match (self.breaker, failed) {
+            (_, false) => Breaker::Closed { failures: 0 },
+            (Breaker::Closed { failures }, true)
+                if failures + 1 < self.policy.breaker_threshold =>
+            {
+                Breaker::Closed {
+                    failures: failures + 1,
+                }
+            }
+            (_, true) => Breaker::Open {
+                until: Instant::now() + self.policy.breaker_cooldown,
+            },
+        };
+
+        metrics::STORE_CIRCUIT_OPEN.set(matches!(self.breaker, Breaker::Open { .. }) as i64);
+    }
+
+    fn backoff(&self, attempt: u32) -> Duration {
+        self.policy
+            .backoff
+            .checked_mul(2u32.saturating_pow(attempt))
+            .unwrap_or(self.policy.backoff)
+    }
+}
+
+//The same loop for every method, the request is cloned for each attempt.
+macro_rules! resilient {
+    ($self:ident, $method:ident, $req:ident) => {{
+        let retries = $self.admit()?;
+        let mut attempt = 0;
+
+        loop {
+            let call = $self.store.$method($req.clone());
+
+            let result = match actix_rt::time::timeout($self.policy.timeout, call).await {
+                Ok(result) => result,
+                Err(_) => Err(Status::deadline_exceeded("Store call timed out")),
+            };
+
+            match &result {
+                Err(status) if retriable(status.code()) && attempt < retries => {
+                    metrics::STORE_RETRIES
+                        .with_label_values(&[stringify!($method)])
+                        .inc();
+
+                    actix_rt::time::delay_for($self.backoff(attempt)).await;
+                    attempt += 1;
+                }
+                _ => {
+                    $self.record(&result);
+                    return result;
+                }
+            }
+        }
+    }};
+}
+
+#[async_trait(?Send)]
+impl<S: Store> Store for ResilientStore<S> {
+    async fn get_document(&mut self, req: GetDocumentRequest) -> Result<Document, Status> {
+        resilient!(self, get_document, req)
+    }
+
+    async fn list_documents(
+        &mut self,
+        req: ListDocumentsRequest,
+    ) -> Result<ListDocumentsResponse, Status> {
+        resilient!(self, list_documents, req)
+    }
+
+    async fn create_document(&mut self, req: CreateDocumentRequest) -> Result<Document, Status> {
+        resilient!(self, create_document, req)
+    }
+
+    async fn update_document(&mut self, req: UpdateDocumentRequest) -> Result<Document, Status> {
+        resilient!(self, update_document, req)
+    }
+
+    async fn commit(&mut self, req: CommitRequest) -> Result<CommitResponse, Status> {
+        resilient!(self, commit, req)
+    }
+
+    fn set_trace_parent(&mut self, parent: Option<SpanContext>) {
+        self.store.set_trace_parent(parent);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers;
+    use crate::store::MemoryStore;
+    use crate::test_support::{test_data_with, FlakyStore};
+    use actix_web::http::{header, StatusCode};
+    use actix_web::test::{self, TestRequest};
+    use actix_web::App;
+    use serde_json::Value;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn policy() -> Policy {
+        Policy {
+            timeout: Duration::from_millis(50),
+            retries: 2,
+            backoff: Duration::from_millis(1),
+            breaker_threshold: 2,
+            breaker_cooldown: Duration::from_millis(50),
+        }
+    }
+
+    fn get() -> GetDocumentRequest {
+        GetDocumentRequest {
+            name: "projects/test/databases/(default)/documents/users/1".to_owned(),
+            mask: None,
+            consistency_selector: None,
+        }
+    }
+
+    #[actix_rt::test]
+    async fn retries_then_opens_circuit() {
+        let failures = Arc::new(AtomicUsize::new(2));
+        let mut store = ResilientStore::new(
+            FlakyStore::new(MemoryStore::new(), failures.clone()),
+            policy(),
+        );
+
+        //Two outages retried away, the store answers NotFound
+        let status = store.get_document(get()).await.unwrap_err();
+
+        assert_eq!(status.code(), Code::NotFound);
+        assert_eq!(failures.load(Ordering::SeqCst), 0);
+
+        //Two calls failing every attempt open the circuit
+        failures.store(6, Ordering::SeqCst);
+
+        for _ in 0..2 {
+            let status = store.get_document(get()).await.unwrap_err();
+            assert_eq!(status.message(), "Simulated outage");
+        }
+
+        assert_eq!(failures.load(Ordering::SeqCst), 0);
+
+        //Fails fast without reaching the store
+        failures.store(1, Ordering::SeqCst);
+
+        let status = store.get_document(get()).await.unwrap_err();
+
+        assert_eq!(status.message(), "Store circuit open");
+        assert_eq!(failures.load(Ordering::SeqCst), 1);
+
+        //After the cooldown a single trial call, failing, opens it again
+        actix_rt::time::delay_for(Duration::from_millis(60)).await;
+
+        let status = store.get_document(get()).await.unwrap_err();
+
+        assert_eq!(status.message(), "Simulated outage");
+        assert_eq!(
+            store.get_document(get()).await.unwrap_err().message(),
+            "Store circuit open"
+        );
+
+        //The next trial succeeds and closes it
+        actix_rt::time::delay_for(Duration::from_millis(60)).await;
+
+        assert_eq!(
+            store.get_document(get()).await.unwrap_err().code(),
+            Code::NotFound
+        );
+        assert_eq!(store.breaker, Breaker::Closed { failures: 0 });
+    }
+
+    #[actix_rt::test]
+    async fn open_circuit_answers_503() {
+        let failures = Arc::new(AtomicUsize::new(usize::MAX));
+
+        let data = test_data_with(Box::new(ResilientStore::new(
+            FlakyStore::new(MemoryStore::new(), failures),
+            Policy {
+                breaker_threshold: 1,
+                breaker_cooldown: Duration::from_secs(60),
+                ..policy()
+            },
+        )));
+
+        let app = App::new().app_data(data).service(handlers::notifications);
+        let mut app = test::init_service(app).await;
+
+        for message in &["Simulated outage", "Store circuit open"] {
+            let req = TestRequest::post()
+                .uri("/webhook")
+                .header(header::CONTENT_TYPE, "application/json")
+                .set_payload(crate::simulator::user_validation("1234567"))
+                .to_request();
+            let resp = test::call_service(&mut app, req).await;
+
+            assert_eq!(
+                resp.status(),
+                StatusCode::SERVICE_UNAVAILABLE,
+                "{}",
+                message
+            );
+
+            let body: Value = serde_json::from_slice(&test::read_body(resp).await).unwrap();
+
+            assert_eq!(body["error"]["code"], "SERVICE_UNAVAILABLE");
+        }
+    }
+}
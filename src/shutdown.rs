@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::client::Client;
+use actix_web::{http::StatusCode, web};
+
+use futures::lock::Mutex as AsyncMutex;
+
+use lazy_static::lazy_static;
+
+use crate::config::Config;
+use crate::logging::{Line, RequestId};
+use crate::metrics;
+use crate::outbox;
+use crate::telemetry;
+use crate::MyData;
+
+//Left after the drain deadline for the outbox and spans.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(2);
+
+//A notification between being received and answered.
+#[derive(PartialEq, Debug, Clone)]
+pub struct InFlight {
+    pub request_id: RequestId,
+    pub notification_type: &'static str,
+    pub user_id: Option<String>,
+    pub transaction_id: Option<i64>,
+    pub started: Instant,
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    static ref IN_FLIGHT: Mutex<HashMap<u64, InFlight>> = Mutex::new(HashMap::new());
+}
+
+fn in_flight() -> std::sync::MutexGuard<'static, HashMap<u64, InFlight>> {
+    IN_FLIGHT
+        .lock()
+        .expect("Trying to lock in-flight notifications Error: ")
+}
+
+//Reports the notification as aborted when dropped before finish,
+//which is what happens to a handler still running at the shutdown deadline.
+pub struct Guard {
+    id: u64,
+}
+
+pub fn track(notification: InFlight) -> Guard {
+    let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+
+    in_flight().insert(id, notification);
+
+    Guard { id }
+}
+
+impl Guard {
+    pub fn finish(self) {
+        in_flight().remove(&self.id);
+        std::mem::forget(self);
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        let notification = in_flight().remove(&self.id);
+
+        if let Some(notification) = notification {
+            report_aborted(&notification);
+        }
+    }
+}
+
+fn report_aborted(notification: &InFlight) {
+    let mut line = Line::new("notification aborted", &notification.request_id);
+    line.notification_type = Some(notification.notification_type.to_owned());
+    line.user_id = notification.user_id.clone();
+    line.transaction_id = notification.transaction_id;
+    line.latency_ms = Some(notification.started.elapsed().as_millis());
+    line.finish(StatusCode::SERVICE_UNAVAILABLE);
+    line.outcome = Some("aborted");
+    line.write();
+
+    metrics::NOTIFICATIONS
+        .with_label_values(&[notification.notification_type, "aborted"])
+        .inc();
+}
+
+//After the server stopped: whatever is still tracked never got an answer.
+//Xsolla resends those, the store writes are idempotent by transaction ID.
+pub fn report_remaining() -> usize {
+    let remaining: Vec<InFlight> = in_flight().drain().map(|(_, n)| n).collect();
+
+    for notification in &remaining {
+        report_aborted(notification);
+    }
+
+    remaining.len()
+}
+
+//Last delivery of due outbox events and finished spans before the process exits.
+pub async fn flush(config: &Config, data: &web::Data<AsyncMutex<MyData>>) {
+    let client = Client::default();
+
+    if let Some(dispatcher) = outbox::get_dispatcher(config) {
+        let dispatch = dispatcher.dispatch(data, &client);
+
+        if let Ok(Err(status)) = actix_rt::time::timeout(FLUSH_TIMEOUT, dispatch).await {
+            let mut line = Line::background("outbox flush failed", "ERROR");
+            line.error = Some(status.to_string());
+            line.write();
+        }
+    }
+
+    if let Some(exporter) = telemetry::get_exporter(config) {
+        let _ = actix_rt::time::timeout(FLUSH_TIMEOUT, exporter.export(&client)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers;
+    use crate::test_support::test_data;
+    use actix_web::http::header;
+    use actix_web::{test, App};
+
+    fn aborted() -> u64 {
+        metrics::NOTIFICATIONS
+            .with_label_values(&["refund", "aborted"])
+            .get()
+    }
+
+    #[actix_rt::test]
+    async fn dropped_handler_reported() {
+        let data = test_data();
+
+        let app = App::new()
+            .app_data(data.clone())
+            .service(handlers::notifications);
+        let mut app = test::init_service(app).await;
+
+        let before = aborted();
+
+        //The handler waits for the lock held here until the deadline drops it
+        let lock = data.lock().await;
+
+        let req = test::TestRequest::post()
+            .uri("/webhook")
+            .header(header::CONTENT_TYPE, "application/json")
+            .set_payload(crate::simulator::refund("1234567", 1, 10))
+            .to_request();
+
+        let call = test::call_service(&mut app, req);
+
+        assert!(actix_rt::time::timeout(Duration::from_millis(50), call)
+            .await
+            .is_err());
+
+        drop(lock);
+
+        assert_eq!(aborted(), before + 1);
+
+        //Answered notifications are not reported
+        let req = test::TestRequest::post()
+            .uri("/webhook")
+            .header(header::CONTENT_TYPE, "application/json")
+            .set_payload(crate::simulator::refund("1234567", 1, 10))
+            .to_request();
+
+        test::call_service(&mut app, req).await;
+
+        assert_eq!(aborted(), before + 1);
+    }
+}
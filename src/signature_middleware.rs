@@ -1,29 +1,61 @@
+use actix_http::h1;
 use actix_service::{Service, Transform};
 use actix_web::{
     dev::ServiceRequest, dev::ServiceResponse, http::header, http::header::HeaderValue, Error,
     HttpMessage, HttpResponse,
 };
+use bytes::BytesMut;
 use futures::future::{ok, Future, Ready};
 use futures::stream::StreamExt;
+use serde::{Deserialize, Serialize};
 use sha1::{Digest, Sha1};
 use std::cell::RefCell;
-use std::env;
 use std::pin::Pin;
 use std::rc::Rc;
 use std::task::{Context, Poll};
 
-fn get_secret_key() -> String {
-    let secret = env::var("WEBHOOK_SECRET_KEY")
-        .expect("Trying to read enviroment variable WEBHOOK_SECRET_KEY Error: ");
+//Hex signature expected as "Authorization: Bearer <sign>", sha1(body + secret)
+pub fn sign(payload: &[u8], secret_key: &str) -> String {
+    let mut hasher = Sha1::new();
 
-    if secret.len() != 20 {
-        panic!("WEBHOOK_SECRET_KEY must be 20 characters long");
+    hasher.input(payload);
+    hasher.input(secret_key.as_bytes());
+
+    hex::encode(hasher.result())
+}
+
+//Outcome of the check, left in the request extensions for the archive.
+#[derive(PartialEq, Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SignatureVerdict {
+    Valid,
+    Invalid,
+    Missing,
+}
+
+impl SignatureVerdict {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SignatureVerdict::Valid => "valid",
+            SignatureVerdict::Invalid => "invalid",
+            SignatureVerdict::Missing => "missing",
+        }
     }
+}
 
-    secret
+pub struct VerifySignature {
+    secret_key: String,
+    max_body_size: usize,
 }
 
-pub struct VerifySignature;
+impl VerifySignature {
+    pub fn new(secret_key: String, max_body_size: usize) -> Self {
+        VerifySignature {
+            secret_key,
+            max_body_size,
+        }
+    }
+}
 
 impl<S: 'static, B> Transform<S> for VerifySignature
 where
@@ -41,7 +73,8 @@ where
     fn new_transform(&self, service: S) -> Self::Future {
         ok(VerifySignatureMiddleware {
             service: Rc::new(RefCell::new(service)),
-            secret_key: get_secret_key(),
+            secret_key: self.secret_key.clone(),
+            max_body_size: self.max_body_size,
         })
     }
 }
@@ -66,8 +99,10 @@ fn extract_signature(header_value: &HeaderValue) -> Option<[u8; 20]> {
     None
 }
 
+use crate::metrics;
 use crate::models::Error as JsonError;
 use crate::models::ErrorMessage;
+use crate::telemetry::{self, Span, SpanKind};
 
 const SIGNATURE_ERROR: ErrorMessage = ErrorMessage {
     error: JsonError {
@@ -76,9 +111,40 @@ const SIGNATURE_ERROR: ErrorMessage = ErrorMessage {
     },
 };
 
+const CONTENT_TYPE_ERROR: ErrorMessage = ErrorMessage {
+    error: JsonError {
+        code: "INVALID_PARAMETER",
+        message: "Content-Type must be application/json",
+    },
+};
+
+const BODY_SIZE_ERROR: ErrorMessage = ErrorMessage {
+    error: JsonError {
+        code: "INVALID_PARAMETER",
+        message: "Request body too large",
+    },
+};
+
+//Turned away before the signature is checked, the archive records no verdict.
+fn reject<B>(
+    req: ServiceRequest,
+    mut span: Span,
+    error: &str,
+    resp: HttpResponse,
+) -> ServiceResponse<B> {
+    span.set_error(error);
+    span.end();
+    metrics::REJECTED_REQUESTS
+        .with_label_values(&["verify_signature"])
+        .inc();
+
+    req.into_response(resp.into_body())
+}
+
 pub struct VerifySignatureMiddleware<S> {
     service: Rc<RefCell<S>>, //Rc & RefCell why???
     secret_key: String,
+    max_body_size: usize,
 }
 
 impl<S, B> Service for VerifySignatureMiddleware<S>
@@ -97,10 +163,45 @@ where
     }
 
     fn call(&mut self, mut req: ServiceRequest) -> Self::Future {
+        //Covers reading and hashing the body, not the handler
+        let mut span = Span::start(
+            "VerifySignature",
+            SpanKind::Internal,
+            &telemetry::request_context(&req),
+        );
+
+        //Xsolla only sends JSON, anything else is not worth hashing
+        if !req.content_type().eq_ignore_ascii_case("application/json") {
+            let resp = HttpResponse::UnsupportedMediaType().json(CONTENT_TYPE_ERROR);
+
+            return Box::pin(ok(reject(req, span, "unsupported content type", resp)));
+        }
+
+        let max_body_size = self.max_body_size;
+
+        let content_length = req
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|length| length.to_str().ok())
+            .and_then(|length| length.parse::<usize>().ok());
+
+        if content_length.is_some_and(|length| length > max_body_size) {
+            let resp = HttpResponse::PayloadTooLarge().json(BODY_SIZE_ERROR);
+
+            return Box::pin(ok(reject(req, span, "body too large", resp)));
+        }
+
         let header_value = req.headers().get(header::AUTHORIZATION);
         let header_value = match header_value {
             Some(bearer) => bearer,
             None => {
+                req.extensions_mut().insert(SignatureVerdict::Missing);
+                span.set_error("missing signature");
+                span.end();
+                metrics::REJECTED_REQUESTS
+                    .with_label_values(&["verify_signature"])
+                    .inc();
+
                 return Box::pin(ok(req.into_response(
                     HttpResponse::Unauthorized()
                         .json(SIGNATURE_ERROR)
@@ -113,6 +214,13 @@ where
         let signature = match signature {
             Some(sig) => sig,
             None => {
+                req.extensions_mut().insert(SignatureVerdict::Invalid);
+                span.set_error("invalid signature");
+                span.end();
+                metrics::REJECTED_REQUESTS
+                    .with_label_values(&["verify_signature"])
+                    .inc();
+
                 return Box::pin(ok(req.into_response(
                     HttpResponse::Unauthorized()
                         .json(SIGNATURE_ERROR)
@@ -126,18 +234,49 @@ where
 
         Box::pin(async move {
             let mut hasher = Sha1::new();
+            let mut body = BytesMut::new();
 
             let mut stream = req.take_payload();
 
             while let Some(chunk) = stream.next().await {
-                hasher.input(chunk?);
+                let chunk = match chunk {
+                    Ok(chunk) => chunk,
+                    Err(error) => {
+                        span.set_error(&error);
+                        span.end();
+
+                        return Err(error.into());
+                    }
+                };
+
+                //Chunked bodies have no Content-Length to check up front
+                if body.len() + chunk.len() > max_body_size {
+                    let resp = HttpResponse::PayloadTooLarge().json(BODY_SIZE_ERROR);
+
+                    return Ok(reject(req, span, "body too large", resp));
+                }
+
+                hasher.input(&chunk);
+                body.extend_from_slice(&chunk);
             }
 
             hasher.input(secret.as_bytes());
 
+            //Put the body back for the handler
+            let (_, mut payload) = h1::Payload::create(true);
+            payload.unread_data(body.freeze());
+            req.set_payload(payload.into());
+
             let hash = hasher.result();
 
             if signature != hash.as_slice() {
+                req.extensions_mut().insert(SignatureVerdict::Invalid);
+                span.set_error("invalid signature");
+                span.end();
+                metrics::REJECTED_REQUESTS
+                    .with_label_values(&["verify_signature"])
+                    .inc();
+
                 return Ok(req.into_response(
                     HttpResponse::Unauthorized()
                         .json(SIGNATURE_ERROR)
@@ -145,7 +284,10 @@ where
                 ));
             }
 
-            Ok(svc.call(req).await?)
+            req.extensions_mut().insert(SignatureVerdict::Valid);
+            span.end();
+
+            svc.call(req).await
         })
     }
 }
@@ -154,16 +296,30 @@ where
 mod tests {
     use super::*;
     use crate::handlers;
+    use crate::test_support::test_data;
     use actix_web::http::header;
     use actix_web::http::StatusCode;
     use actix_web::test;
     use actix_web::test::TestRequest;
     use actix_web::App;
 
+    const MAX_BODY_SIZE: usize = 64;
+
+    #[test]
+    fn sign_payload() {
+        assert_eq!(
+            sign(b"examplepayload", "Ultra1Top2Secret3Key"),
+            "bd31a2212735b01bc15e8350a6d27003a2b63d27"
+        );
+    }
+
     #[actix_rt::test]
     async fn wrong_signature() {
         let app = App::new()
-            .wrap(VerifySignature)
+            .wrap(VerifySignature::new(
+                "Ultra1Top2Secret3Key".to_owned(),
+                MAX_BODY_SIZE,
+            ))
             .service(handlers::notifications);
         let mut app = test::init_service(app).await;
 
@@ -171,6 +327,7 @@ mod tests {
 
         let req = TestRequest::post()
             .uri("/webhook")
+            .header(header::CONTENT_TYPE, "application/json")
             .header(
                 header::AUTHORIZATION,
                 "Bearer bd31a2212735b01bc15e8350a6d27003a2b63d26", //hash of "examplepayloadUltra1Top2Secret3Key" with last hex changed
@@ -186,7 +343,10 @@ mod tests {
     #[actix_rt::test]
     async fn correct_signature() {
         let app = App::new()
-            .wrap(VerifySignature)
+            .wrap(VerifySignature::new(
+                "Ultra1Top2Secret3Key".to_owned(),
+                MAX_BODY_SIZE,
+            ))
             .service(handlers::notifications);
         let mut app = test::init_service(app).await;
 
@@ -194,6 +354,7 @@ mod tests {
 
         let req = TestRequest::post()
             .uri("/webhook")
+            .header(header::CONTENT_TYPE, "application/json")
             .header(
                 header::AUTHORIZATION,
                 "Bearer bd31a2212735b01bc15e8350a6d27003a2b63d27", //hash of "examplepayloadUltra1Top2Secret3Key"
@@ -205,4 +366,82 @@ mod tests {
 
         assert_ne!(resp.status(), StatusCode::UNAUTHORIZED);
     }
+
+    #[actix_rt::test]
+    async fn payload_forwarded() {
+        let data = test_data();
+
+        let app = App::new()
+            .app_data(data)
+            .wrap(VerifySignature::new(
+                "Ultra1Top2Secret3Key".to_owned(),
+                MAX_BODY_SIZE,
+            ))
+            .service(handlers::notifications);
+        let mut app = test::init_service(app).await;
+
+        let data = r#"{"notification_type":"user_validation","user":{"id":"1"}}"#;
+
+        let req = TestRequest::post()
+            .uri("/webhook")
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(
+                header::AUTHORIZATION,
+                format!("Bearer {}", sign(data.as_bytes(), "Ultra1Top2Secret3Key")),
+            )
+            .set_payload(data)
+            .to_request();
+
+        let resp = test::call_service(&mut app, req).await;
+
+        //The handler could read the body, the user does not exist
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn rejected_before_hashing() {
+        let app = App::new()
+            .wrap(VerifySignature::new(
+                "Ultra1Top2Secret3Key".to_owned(),
+                MAX_BODY_SIZE,
+            ))
+            .service(handlers::notifications);
+        let mut app = test::init_service(app).await;
+
+        let data = "examplepayload";
+
+        let req = TestRequest::post()
+            .uri("/webhook")
+            .header(header::CONTENT_TYPE, "text/plain")
+            .header(
+                header::AUTHORIZATION,
+                format!("Bearer {}", sign(data.as_bytes(), "Ultra1Top2Secret3Key")),
+            )
+            .set_payload(data)
+            .to_request();
+
+        let resp = test::call_service(&mut app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+        let data = "x".repeat(MAX_BODY_SIZE + 1);
+
+        let req = TestRequest::post()
+            .uri("/webhook")
+            .header(header::CONTENT_TYPE, "application/json; charset=utf-8")
+            .header(
+                header::AUTHORIZATION,
+                format!("Bearer {}", sign(data.as_bytes(), "Ultra1Top2Secret3Key")),
+            )
+            .set_payload(data)
+            .to_request();
+
+        let resp = test::call_service(&mut app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let body: serde_json::Value = serde_json::from_slice(&test::read_body(resp).await).unwrap();
+
+        assert_eq!(body["error"]["code"], "INVALID_PARAMETER");
+    }
 }
@@ -0,0 +1,445 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_http::Request;
+use actix_service::Service;
+use actix_web::dev::{MessageBody, ServiceResponse};
+use actix_web::http::header;
+use actix_web::{test, web, App, Error, HttpResponse};
+
+use firestore_grpc_cloudrun::{
+    value::ValueType, CreateDocumentRequest, Document, GetDocumentRequest, Value,
+};
+
+use futures::future::{ok, Either};
+use futures::lock::Mutex;
+
+use serde_json::json;
+
+use crate::config::Config;
+use crate::handlers;
+use crate::push::Hub;
+use crate::signature_middleware::{sign, VerifySignature};
+use crate::store::{MemoryStore, Store};
+use crate::MyData;
+
+//Payloads follow the examples of the Xsolla webhook documentation.
+
+pub fn user_validation(user_id: &str) -> String {
+    json!({
+        "notification_type": "user_validation",
+        "user": {
+            "ip": "127.0.0.1",
+            "phone": "18777976552",
+            "email": "email@example.com",
+            "id": user_id,
+            "name": "Xsolla User",
+            "country": "US"
+        }
+    })
+    .to_string()
+}
+
+fn purchase(quantity: i64) -> serde_json::Value {
+    json!({
+        "virtual_currency": {
+            "name": "Coins",
+            "sku": "test_package1",
+            "quantity": quantity,
+            "currency": "USD",
+            "amount": quantity * 10
+        },
+        "total": {
+            "currency": "USD",
+            "amount": quantity * 10
+        }
+    })
+}
+
+fn user(user_id: &str) -> serde_json::Value {
+    json!({
+        "ip": "127.0.0.1",
+        "email": "email@example.com",
+        "id": user_id,
+        "name": "Xsolla User",
+        "country": "US"
+    })
+}
+
+fn payment_details(amount: i64) -> serde_json::Value {
+    json!({
+        "payment": {
+            "currency": "USD",
+            "amount": amount
+        },
+        "vat": {
+            "currency": "USD",
+            "amount": 0
+        },
+        "payout_currency_rate": 1,
+        "payout": {
+            "currency": "USD",
+            "amount": amount
+        },
+        "xsolla_fee": {
+            "currency": "USD",
+            "amount": 0
+        },
+        "payment_method_fee": {
+            "currency": "USD",
+            "amount": 0
+        }
+    })
+}
+
+pub fn payment(user_id: &str, transaction_id: i64, quantity: i64) -> String {
+    json!({
+        "notification_type": "payment",
+        "purchase": purchase(quantity),
+        "user": user(user_id),
+        "transaction": {
+            "id": transaction_id,
+            "external_id": transaction_id.to_string(),
+            "payment_date": "2014-09-24T20:38:16+04:00",
+            "payment_method": 1,
+            "dry_run": 1,
+            "agreement": 1
+        },
+        "payment_details": payment_details(quantity * 10)
+    })
+    .to_string()
+}
+
+pub fn refund(user_id: &str, transaction_id: i64, quantity: i64) -> String {
+    json!({
+        "notification_type": "refund",
+        "purchase": purchase(quantity),
+        "user": user(user_id),
+        "transaction": {
+            "id": transaction_id,
+            "external_id": transaction_id.to_string(),
+            "dry_run": 1,
+            "agreement": 1
+        },
+        "refund_details": {
+            "code": 1,
+            "reason": "Fraud"
+        },
+        "payment_details": payment_details(quantity * 10)
+    })
+    .to_string()
+}
+
+//One of create_subscription, update_subscription or cancel_subscription.
+pub fn subscription(notification_type: &str, user_id: &str, subscription_id: i64) -> String {
+    json!({
+        "notification_type": notification_type,
+        "user": {
+            "id": user_id,
+            "name": "Xsolla User"
+        },
+        "subscription": {
+            "plan_id": "b5dac9c8",
+            "subscription_id": subscription_id,
+            "product_id": "Demo Product",
+            "date_create": "2014-09-22T19:25:25+04:00",
+            "date_next_charge": "2014-10-22T19:25:25+04:00",
+            "currency": "USD",
+            "amount": 9.99
+        }
+    })
+    .to_string()
+}
+
+//Xsolla resends on 5xx or no response: 2 attempts 5 minutes apart,
+//then 7 attempts 15 minutes apart, then 10 attempts an hour apart.
+pub fn xsolla_retry_delays() -> Vec<Duration> {
+    let mut delays = vec![Duration::from_secs(5 * 60); 2];
+    delays.extend(vec![Duration::from_secs(15 * 60); 7]);
+    delays.extend(vec![Duration::from_secs(60 * 60); 10]);
+
+    delays
+}
+
+pub struct Delivery {
+    pub attempts: usize,
+    pub status: u16,
+    pub body: String,
+}
+
+//Delivers a signed notification, retrying like Xsolla with delays multiplied by time_scale.
+pub async fn deliver<S, B>(app: &mut S, payload: &str, secret: &str, time_scale: f64) -> Delivery
+where
+    S: Service<Request = Request, Response = ServiceResponse<B>, Error = Error>,
+    B: MessageBody,
+{
+    let signature = sign(payload.as_bytes(), secret);
+
+    let mut delays = xsolla_retry_delays().into_iter();
+    let mut attempts = 0;
+
+    loop {
+        attempts += 1;
+
+        let req = test::TestRequest::post()
+            .uri("/webhook")
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::AUTHORIZATION, format!("Bearer {}", signature))
+            .set_payload(payload.to_owned())
+            .to_request();
+
+        let res = test::call_service(app, req).await;
+        let status = res.status().as_u16();
+        let body = test::read_body(res).await;
+
+        let delay = match delays.next() {
+            Some(delay) if status >= 500 => delay,
+            _ => {
+                return Delivery {
+                    attempts,
+                    status,
+                    body: String::from_utf8_lossy(&body).into_owned(),
+                }
+            }
+        };
+
+        actix_rt::time::delay_for(delay.mul_f64(time_scale)).await;
+    }
+}
+
+pub struct Step {
+    pub name: &'static str,
+    pub payload: String,
+    pub outage: usize, //attempts answered as during a store outage
+    pub expected_status: u16,
+    pub expected_credits: Option<i64>,
+}
+
+//Purchase lifecycle of a single user, from validation to refund.
+pub fn scenario(user_id: &str, unknown_user_id: &str) -> Vec<Step> {
+    vec![
+        Step {
+            name: "user_validation unknown user",
+            payload: user_validation(unknown_user_id),
+            outage: 0,
+            expected_status: 400,
+            expected_credits: None,
+        },
+        Step {
+            name: "user_validation",
+            payload: user_validation(user_id),
+            outage: 0,
+            expected_status: 200,
+            expected_credits: Some(0),
+        },
+        Step {
+            name: "payment during store outage",
+            payload: payment(user_id, 1, 10),
+            outage: 1,
+            expected_status: 200,
+            expected_credits: Some(10),
+        },
+        Step {
+            name: "payment resent",
+            payload: payment(user_id, 1, 10),
+            outage: 0,
+            expected_status: 200,
+            expected_credits: Some(10),
+        },
+        Step {
+            name: "second payment",
+            payload: payment(user_id, 2, 5),
+            outage: 0,
+            expected_status: 200,
+            expected_credits: Some(15),
+        },
+        Step {
+            name: "refund",
+            payload: refund(user_id, 1, 10),
+            outage: 0,
+            expected_status: 200,
+            expected_credits: Some(5),
+        },
+        Step {
+            name: "refund unknown transaction",
+            payload: refund(user_id, 3, 10),
+            outage: 0,
+            expected_status: 400,
+            expected_credits: Some(5),
+        },
+        Step {
+            name: "create_subscription",
+            payload: subscription("create_subscription", user_id, 10),
+            outage: 0,
+            expected_status: 200,
+            expected_credits: Some(5),
+        },
+        Step {
+            name: "cancel_subscription",
+            payload: subscription("cancel_subscription", user_id, 10),
+            outage: 0,
+            expected_status: 200,
+            expected_credits: Some(5),
+        },
+    ]
+}
+
+pub struct Outcome {
+    pub name: &'static str,
+    pub attempts: usize,
+    pub status: u16,
+    pub credits: Option<i64>,
+    pub passed: bool,
+}
+
+async fn credits(data: &web::Data<Mutex<MyData>>, user_id: &str) -> Option<i64> {
+    let mut data = data.lock().await;
+
+    let req = GetDocumentRequest {
+        name: format!(
+            "projects/{}/databases/(default)/documents/users/{}",
+            data.project_id, user_id
+        ),
+        mask: None,
+        consistency_selector: None,
+    };
+
+    let doc = data.client.get_document(req).await.ok()?;
+
+    match doc.fields.get("Credits")?.value_type {
+        Some(ValueType::IntegerValue(credits)) => Some(credits),
+        _ => None,
+    }
+}
+
+//Runs the scenario against an in-process server backed by a memory store.
+pub async fn run(time_scale: f64, secret: &str) -> Vec<Outcome> {
+    let user_id = "1234567";
+    let project_id = "simulator";
+
+    let mut store = MemoryStore::new();
+
+    let mut fields = HashMap::new();
+    fields.insert(
+        "Credits".to_owned(),
+        Value {
+            value_type: Some(ValueType::IntegerValue(0)),
+        },
+    );
+
+    let req = CreateDocumentRequest {
+        parent: format!("projects/{}/databases/(default)/documents", project_id),
+        collection_id: "users".to_owned(),
+        document_id: user_id.to_owned(),
+        document: Some(Document {
+            fields,
+            ..Document::default()
+        }),
+        mask: None,
+    };
+
+    store
+        .create_document(req)
+        .await
+        .expect("Trying to create simulator user Error: ");
+
+    let failures = Arc::new(AtomicUsize::new(0));
+    let outage = failures.clone();
+
+    let data = web::Data::new(Mutex::new(MyData {
+        project_id: project_id.to_owned(),
+        client: Box::new(store),
+        reject_unknown: false,
+        hub: Hub::default(),
+        sku_currencies: HashMap::new(),
+    }));
+
+    let app = App::new()
+        .app_data(data.clone())
+        //What Xsolla gets while the store is down
+        .wrap_fn(move |req, srv| {
+            let failures = outage.load(Ordering::SeqCst);
+
+            if failures > 0 {
+                outage.store(failures - 1, Ordering::SeqCst);
+
+                let resp = HttpResponse::ServiceUnavailable().finish();
+
+                return Either::Left(ok(req.into_response(resp.into_body())));
+            }
+
+            Either::Right(srv.call(req))
+        })
+        .wrap(VerifySignature::new(
+            secret.to_owned(),
+            Config::default().max_body_size,
+        ))
+        .service(handlers::notifications);
+    let mut app = test::init_service(app).await;
+
+    let mut outcomes = Vec::new();
+
+    for step in scenario(user_id, "7654321") {
+        failures.store(step.outage, Ordering::SeqCst);
+
+        let delivery = deliver(&mut app, &step.payload, secret, time_scale).await;
+
+        let credits = credits(&data, user_id).await;
+
+        let passed = delivery.status == step.expected_status
+            && (step.expected_credits.is_none() || credits == step.expected_credits);
+
+        outcomes.push(Outcome {
+            name: step.name,
+            attempts: delivery.attempts,
+            status: delivery.status,
+            credits,
+            passed,
+        });
+    }
+
+    outcomes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Message;
+
+    #[test]
+    fn payloads_deserialize() {
+        let payloads = vec![
+            user_validation("1"),
+            payment("1", 1, 10),
+            refund("1", 1, 10),
+            subscription("create_subscription", "1", 10),
+        ];
+
+        for payload in payloads {
+            serde_json::from_str::<Message>(&payload).unwrap();
+        }
+    }
+
+    #[actix_rt::test]
+    async fn scenario_passes() {
+        let outcomes = run(0.0, "Simulator1Secret2Key").await;
+
+        for outcome in &outcomes {
+            assert!(outcome.passed, "{} failed", outcome.name);
+        }
+
+        //The outage made Xsolla resend once
+        assert_eq!(outcomes[2].attempts, 2);
+    }
+
+    #[test]
+    fn retry_schedule() {
+        let delays = xsolla_retry_delays();
+
+        assert_eq!(delays.len(), 19);
+        assert_eq!(delays[0], Duration::from_secs(300));
+        assert_eq!(delays[18], Duration::from_secs(3600));
+    }
+}
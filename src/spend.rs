@@ -0,0 +1,401 @@
+use std::collections::HashMap;
+
+use actix_web::post;
+use actix_web::{web, HttpResponse, Responder};
+
+use firestore_grpc_cloudrun::{
+    precondition::ConditionType, value::ValueType, write::Operation, CommitRequest, Document,
+    DocumentMask, GetDocumentRequest, Precondition, Value, Write,
+};
+
+use futures::lock::Mutex;
+
+use tonic::Code;
+
+use crate::admin::{
+    applied, balances, integer, known_currency, record, string_value, user_name, valid_key,
+};
+use crate::auth_middleware::{Identity, Role, ROLE_ERROR};
+use crate::handlers::{balance_field, balance_update, credit, log_store_error};
+use crate::logging::RequestId;
+use crate::models::Error;
+use crate::models::ErrorMessage;
+use crate::models::{Spend, SpendReceipt};
+use crate::outbox::{self, Event};
+use crate::MyData;
+
+const USER_ERROR: ErrorMessage = ErrorMessage {
+    error: Error {
+        code: "INVALID_USER",
+        message: "Invalid user",
+    },
+};
+
+const SPEND_ERROR: ErrorMessage = ErrorMessage {
+    error: Error {
+        code: "INVALID_PARAMETER",
+        message: "Quantity must be positive and the idempotency key 1 to 128 of [A-Za-z0-9_-]",
+    },
+};
+
+const BALANCE_ERROR: ErrorMessage = ErrorMessage {
+    error: Error {
+        code: "INSUFFICIENT_CREDITS",
+        message: "Not enough credits",
+    },
+};
+
+const CURRENCY_ERROR: ErrorMessage = ErrorMessage {
+    error: Error {
+        code: "INVALID_PARAMETER",
+        message: "Unknown currency",
+    },
+};
+
+const KEY_REUSED_ERROR: ErrorMessage = ErrorMessage {
+    error: Error {
+        code: "IDEMPOTENCY_KEY_REUSED",
+        message: "Idempotency key already used for a different spend",
+    },
+};
+
+const CONFLICT_ERROR: ErrorMessage = ErrorMessage {
+    error: Error {
+        code: "BALANCE_CHANGED",
+        message: "The balance changed during the spend, retry with the same key",
+    },
+};
+
+//Spends share the transact collection, Xsolla transaction IDs are numeric.
+const SPEND_PREFIX: &str = "spend_";
+
+#[post("")]
+async fn spend(
+    firestore: web::Data<Mutex<MyData>>,
+    spend: web::Json<Spend>,
+    identity: Identity,
+    request_id: RequestId,
+) -> impl Responder {
+    if !identity.role.allows(&[Role::GameServer]) {
+        return HttpResponse::Forbidden().json(ROLE_ERROR);
+    }
+
+    let spend = spend.into_inner();
+
+    if spend.quantity <= 0 || !valid_key(&spend.idempotency_key) {
+        return HttpResponse::BadRequest().json(SPEND_ERROR);
+    }
+
+    let mut firestore = firestore.lock().await;
+
+    let user = user_name(&firestore.project_id, &spend.user_id);
+    let spend_id = format!("{}{}", SPEND_PREFIX, spend.idempotency_key);
+    let name = format!("{}/transact/{}", user, spend_id);
+
+    let currency = spend.currency.clone();
+
+    if !known_currency(&firestore, &currency) {
+        return HttpResponse::BadRequest().json(CURRENCY_ERROR);
+    }
+
+    //Commits failed on a precondition, retried once when the balance changes
+    let mut conflicts = 0;
+
+    loop {
+        let req = GetDocumentRequest {
+            name: user.clone(),
+            mask: Some(DocumentMask {
+                field_paths: vec!["Credits".to_owned(), "Balances".to_owned()],
+            }),
+            consistency_selector: None,
+        };
+
+        let mut user_doc = match firestore.client.get_document(req).await {
+            Ok(user_doc) => user_doc,
+            Err(error) => {
+                if let Code::NotFound = error.code() {
+                    return HttpResponse::BadRequest().json(USER_ERROR);
+                } else {
+                    return log_store_error(&request_id, &error);
+                }
+            }
+        };
+
+        let available = match &currency {
+            Some(currency) => balances(&user_doc).get(currency).copied(),
+            None => integer(&user_doc, "Credits"),
+        };
+
+        //spend already processed answer like the first time
+        match applied(&mut firestore, &name, -spend.quantity, &currency).await {
+            Ok(Some(doc)) => {
+                return HttpResponse::Ok().json(SpendReceipt {
+                    transaction: record(&spend.user_id, &doc),
+                    credits: integer(&user_doc, "Credits").unwrap_or_default(),
+                    balances: balances(&user_doc),
+                });
+            }
+            Ok(None) => {}
+            Err(error) if error.code() == Code::AlreadyExists => {
+                return HttpResponse::Conflict().json(KEY_REUSED_ERROR);
+            }
+            Err(error) => return log_store_error(&request_id, &error),
+        }
+
+        //The spend document is still missing, the user document failed both commits
+        if conflicts > 1 {
+            return HttpResponse::Conflict().json(CONFLICT_ERROR);
+        }
+
+        if available.unwrap_or_default() < spend.quantity {
+            return HttpResponse::BadRequest().json(BALANCE_ERROR);
+        }
+
+        let new_balance = credit(&mut user_doc, &currency, -spend.quantity);
+
+        let mut data: HashMap<String, Value> = HashMap::with_capacity(4);
+
+        data.insert("Type".to_owned(), string_value("spend".to_owned()));
+        data.insert(
+            "Quantity".to_owned(),
+            Value {
+                value_type: Some(ValueType::IntegerValue(-spend.quantity)),
+            },
+        );
+        data.insert(
+            "Operator".to_owned(),
+            string_value(identity.subject.clone()),
+        );
+
+        if let Some(reason) = spend.reason.clone() {
+            data.insert("Reason".to_owned(), string_value(reason));
+        }
+
+        if let Some(currency) = currency.clone() {
+            data.insert("VirtualCurrency".to_owned(), string_value(currency));
+        }
+
+        let mut written = Document {
+            name: name.clone(),
+            fields: data,
+            create_time: None,
+            update_time: None,
+        };
+
+        let event = Event::CreditsSpent {
+            user_id: spend.user_id.clone(),
+            spend_id: spend_id.clone(),
+            currency: currency.clone(),
+            quantity: spend.quantity,
+        };
+
+        let writes = vec![
+            Write {
+                update_mask: None,
+                update_transforms: Vec::new(),
+                current_document: Some(Precondition {
+                    condition_type: Some(ConditionType::Exists(false)),
+                }),
+                operation: Some(Operation::Update(written.clone())),
+            },
+            Write {
+                update_mask: Some(DocumentMask {
+                    field_paths: vec![balance_field(&currency)],
+                }),
+                update_transforms: Vec::new(),
+                //Fails if a webhook changed the balance since it was read
+                current_document: Some(Precondition {
+                    condition_type: user_doc.update_time.clone().map(ConditionType::UpdateTime),
+                }),
+                operation: Some(Operation::Update(user_doc.clone())),
+            },
+            outbox::write(&firestore.project_id, &event),
+        ];
+
+        let req = CommitRequest {
+            database: format!("projects/{}/databases/(default)", firestore.project_id),
+            writes,
+            transaction: Vec::new(),
+        };
+
+        match firestore.client.commit(req).await {
+            Ok(res) => written.create_time = res.commit_time,
+            //A webhook changed the balance or the same spend went through since the read,
+            //both are told apart by reading again
+            Err(error) if error.code() == Code::FailedPrecondition => {
+                conflicts += 1;
+                continue;
+            }
+            Err(error) => return log_store_error(&request_id, &error),
+        }
+
+        firestore
+            .hub
+            .publish(&spend.user_id, &balance_update(currency, new_balance));
+
+        //user_doc holds the balances just written
+        return HttpResponse::Ok().json(SpendReceipt {
+            transaction: record(&spend.user_id, &written),
+            credits: integer(&user_doc, "Credits").unwrap_or_default(),
+            balances: balances(&user_doc),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth_middleware::{Authenticate, Keys};
+    use crate::store::MemoryStore;
+    use crate::test_support::{self, create_user, test_data_with, RacingStore};
+    use actix_web::http::{header, StatusCode};
+    use actix_web::test;
+    use actix_web::test::TestRequest;
+    use actix_web::App;
+    use std::sync::Arc;
+
+    //The second user spends with the same keys
+    async fn with_users(data: web::Data<Mutex<MyData>>) -> web::Data<Mutex<MyData>> {
+        for user_id in &["1234567", "7654321"] {
+            create_user(&data, user_id, 100, &[("gems", 10)]).await;
+        }
+
+        data
+    }
+
+    async fn test_data() -> web::Data<Mutex<MyData>> {
+        with_users(test_support::test_data()).await
+    }
+
+    fn request(token: &str, quantity: i64, key: &str) -> actix_http::Request {
+        user_request(token, "1234567", quantity, key)
+    }
+
+    fn user_request(token: &str, user_id: &str, quantity: i64, key: &str) -> actix_http::Request {
+        TestRequest::post()
+            .uri("/spend")
+            .header(header::AUTHORIZATION, format!("Bearer {}", token))
+            .set_json(&serde_json::json!({
+                "user_id": user_id,
+                "quantity": quantity,
+                "idempotency_key": key,
+                "reason": "sword"
+            }))
+            .to_request()
+    }
+
+    fn currency_request(quantity: i64, key: &str, currency: &str) -> actix_http::Request {
+        TestRequest::post()
+            .uri("/spend")
+            .header(header::AUTHORIZATION, "Bearer game-key")
+            .set_json(&serde_json::json!({
+                "user_id": "1234567",
+                "quantity": quantity,
+                "idempotency_key": key,
+                "currency": currency
+            }))
+            .to_request()
+    }
+
+    #[actix_rt::test]
+    async fn spend_is_idempotent() {
+        let keys = Keys::default()
+            .with_api_key("game-key", "game-server-1", Role::GameServer)
+            .with_api_key("support-key", "support@example.com", Role::Support);
+
+        let app = App::new().app_data(test_data().await).service(
+            web::scope("/spend")
+                .wrap(Authenticate::new(Arc::new(keys)))
+                .service(spend),
+        );
+        let mut app = test::init_service(app).await;
+
+        let resp: serde_json::Value =
+            test::read_response_json(&mut app, request("game-key", 30, "order-1")).await;
+
+        assert_eq!(resp["credits"], 70);
+        assert_eq!(resp["transaction"]["kind"], "spend");
+        assert_eq!(resp["transaction"]["quantity"], -30);
+
+        //Retried after a timeout, nothing is debited twice
+        let resp: serde_json::Value =
+            test::read_response_json(&mut app, request("game-key", 30, "order-1")).await;
+
+        assert_eq!(resp["credits"], 70);
+
+        let resp: serde_json::Value =
+            test::read_response_json(&mut app, user_request("game-key", "7654321", 30, "order-1"))
+                .await;
+
+        assert_eq!(resp["credits"], 70);
+
+        let resp = test::call_service(&mut app, request("game-key", 40, "order-1")).await;
+
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+        let resp = test::call_service(&mut app, request("game-key", 100, "order-2")).await;
+
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let resp = test::call_service(&mut app, request("game-key", 10, "order/3")).await;
+
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let resp = test::call_service(&mut app, request("support-key", 10, "order-4")).await;
+
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let resp: serde_json::Value =
+            test::read_response_json(&mut app, currency_request(4, "order-5", "gems")).await;
+
+        assert_eq!(resp["credits"], 70);
+        assert_eq!(resp["balances"]["gems"], 6);
+        assert_eq!(resp["transaction"]["virtual_currency"], "gems");
+
+        //Same quantity as order-1 but gems
+        let resp = test::call_service(&mut app, currency_request(30, "order-1", "gems")).await;
+
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+        let resp = test::call_service(&mut app, currency_request(7, "order-6", "gems")).await;
+
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let resp = test::call_service(&mut app, currency_request(1, "order-7", "gold")).await;
+
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+    #[actix_rt::test]
+    async fn balance_changed_during_spend() {
+        let keys = Keys::default().with_api_key("game-key", "game-server-1", Role::GameServer);
+        let keys = Arc::new(keys);
+
+        for (races, status, credits) in [(1, StatusCode::OK, 170), (2, StatusCode::CONFLICT, 200)] {
+            let data = with_users(test_data_with(Box::new(RacingStore::new(
+                MemoryStore::new(),
+                races,
+            ))))
+            .await;
+
+            let app = App::new().app_data(data.clone()).service(
+                web::scope("/spend")
+                    .wrap(Authenticate::new(keys.clone()))
+                    .service(spend),
+            );
+            let mut app = test::init_service(app).await;
+
+            let resp = test::call_service(&mut app, request("game-key", 30, "order-1")).await;
+
+            assert_eq!(resp.status(), status);
+
+            let req = GetDocumentRequest {
+                name: "projects/test/databases/(default)/documents/users/1234567".to_owned(),
+                mask: None,
+                consistency_selector: None,
+            };
+            let user_doc = data.lock().await.client.get_document(req).await.unwrap();
+
+            assert_eq!(integer(&user_doc, "Credits"), Some(credits));
+        }
+    }
+}
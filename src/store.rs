@@ -0,0 +1,504 @@
+use std::collections::BTreeMap;
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+
+use firestore_grpc_cloudrun::firestore_client::FirestoreClient;
+use firestore_grpc_cloudrun::{compute_metadata, BoxError};
+use firestore_grpc_cloudrun::{
+    precondition::ConditionType, write::Operation, CommitRequest, CommitResponse,
+    CreateDocumentRequest, Document, DocumentMask, GetDocumentRequest, ListDocumentsRequest,
+    ListDocumentsResponse, Precondition, UpdateDocumentRequest, Write, WriteResult,
+};
+
+use tonic::metadata::MetadataValue;
+use tonic::transport::channel::Channel;
+use tonic::{Request, Status};
+
+use crate::config::Config;
+use crate::telemetry::SpanContext;
+
+//Admin access to the emulator, it has no other credentials
+const EMULATOR_TOKEN: &str = "Bearer owner";
+
+//The emulator accepts any project, the same one as the memory store is used by default
+const EMULATOR_PROJECT_ID: &str = "local";
+
+pub async fn get_project_id(config: &Config) -> Result<String, BoxError> {
+    if let Some(project_id) = &config.project_id {
+        return Ok(project_id.clone());
+    }
+
+    if config.firestore_emulator_host.is_some() {
+        return Ok(EMULATOR_PROJECT_ID.to_owned());
+    }
+
+    compute_metadata::get_project_id().await
+}
+
+//Plaintext to the emulator when FIRESTORE_EMULATOR_HOST is set, TLS with a metadata server token otherwise.
+pub async fn get_client(config: &Config) -> Result<FirestoreClient<Channel>, BoxError> {
+    let host = match &config.firestore_emulator_host {
+        Some(host) => host,
+        None => return firestore_grpc_cloudrun::get_client().await,
+    };
+
+    let channel = Channel::from_shared(format!("http://{}", host))?
+        .connect()
+        .await?;
+
+    let token = MetadataValue::from_str(EMULATOR_TOKEN)?;
+
+    Ok(FirestoreClient::with_interceptor(
+        channel,
+        move |mut req: Request<()>| {
+            req.metadata_mut().insert("authorization", token.clone());
+            Ok(req)
+        },
+    ))
+}
+
+//Subset of the Firestore API used by the handlers.
+//Implemented by the real client and by an in-memory store for local runs and tests.
+#[async_trait(?Send)]
+pub trait Store {
+    async fn get_document(&mut self, req: GetDocumentRequest) -> Result<Document, Status>;
+
+    async fn list_documents(
+        &mut self,
+        req: ListDocumentsRequest,
+    ) -> Result<ListDocumentsResponse, Status>;
+
+    async fn create_document(&mut self, req: CreateDocumentRequest) -> Result<Document, Status>;
+
+    async fn update_document(&mut self, req: UpdateDocumentRequest) -> Result<Document, Status>;
+
+    //Applies all writes atomically, or none of them.
+    async fn commit(&mut self, req: CommitRequest) -> Result<CommitResponse, Status>;
+
+    //Span the next calls belong to, set by whoever holds the store.
+    fn set_trace_parent(&mut self, _parent: Option<SpanContext>) {}
+}
+
+#[async_trait(?Send)]
+impl Store for FirestoreClient<Channel> {
+    async fn get_document(&mut self, req: GetDocumentRequest) -> Result<Document, Status> {
+        let res = FirestoreClient::get_document(self, req).await?;
+
+        Ok(res.into_inner())
+    }
+
+    async fn list_documents(
+        &mut self,
+        req: ListDocumentsRequest,
+    ) -> Result<ListDocumentsResponse, Status> {
+        let res = FirestoreClient::list_documents(self, req).await?;
+
+        Ok(res.into_inner())
+    }
+
+    async fn create_document(&mut self, req: CreateDocumentRequest) -> Result<Document, Status> {
+        let res = FirestoreClient::create_document(self, req).await?;
+
+        Ok(res.into_inner())
+    }
+
+    async fn update_document(&mut self, req: UpdateDocumentRequest) -> Result<Document, Status> {
+        let res = FirestoreClient::update_document(self, req).await?;
+
+        Ok(res.into_inner())
+    }
+
+    async fn commit(&mut self, req: CommitRequest) -> Result<CommitResponse, Status> {
+        let res = FirestoreClient::commit(self, req).await?;
+
+        Ok(res.into_inner())
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryStore {
+    documents: BTreeMap<String, Document>,
+    next_id: u64,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+fn apply_mask(mut doc: Document, mask: &Option<DocumentMask>) -> Document {
+    if let Some(mask) = mask {
+        doc.fields
+            .retain(|field, _| mask.field_paths.iter().any(|path| path == field));
+    }
+
+    doc
+}
+
+fn check_precondition(
+    current: Option<&Document>,
+    precondition: &Option<Precondition>,
+) -> Result<(), Status> {
+    let condition = match precondition {
+        Some(Precondition {
+            condition_type: Some(condition),
+        }) => condition,
+        _ => return Ok(()),
+    };
+
+    let met = match condition {
+        ConditionType::Exists(exists) => current.is_some() == *exists,
+        ConditionType::UpdateTime(time) => {
+            current.and_then(|doc| doc.update_time.as_ref()) == Some(time)
+        }
+    };
+
+    if met {
+        Ok(())
+    } else {
+        Err(Status::failed_precondition("Precondition not met"))
+    }
+}
+
+#[async_trait(?Send)]
+impl Store for MemoryStore {
+    async fn get_document(&mut self, req: GetDocumentRequest) -> Result<Document, Status> {
+        match self.documents.get(&req.name) {
+            Some(doc) => Ok(apply_mask(doc.clone(), &req.mask)),
+            None => Err(Status::not_found(req.name)),
+        }
+    }
+
+    async fn create_document(&mut self, req: CreateDocumentRequest) -> Result<Document, Status> {
+        let document_id = if req.document_id.is_empty() {
+            self.next_id += 1;
+            format!("{:020}", self.next_id)
+        } else {
+            req.document_id
+        };
+
+        let name = format!("{}/{}/{}", req.parent, req.collection_id, document_id);
+
+        if self.documents.contains_key(&name) {
+            return Err(Status::already_exists(name));
+        }
+
+        let now = prost_types::Timestamp::from(SystemTime::now());
+
+        let mut doc = req.document.unwrap_or_default();
+        doc.name = name.clone();
+        doc.create_time = Some(now.clone());
+        doc.update_time = Some(now);
+
+        self.documents.insert(name, doc.clone());
+
+        Ok(apply_mask(doc, &req.mask))
+    }
+
+    async fn list_documents(
+        &mut self,
+        req: ListDocumentsRequest,
+    ) -> Result<ListDocumentsResponse, Status> {
+        let prefix = format!("{}/{}/", req.parent, req.collection_id);
+
+        //The token is the name of the last document of the previous page
+        let mut documents: Vec<Document> = self
+            .documents
+            .range(prefix.clone()..)
+            .take_while(|(name, _)| name.starts_with(&prefix))
+            .filter(|(name, _)| !name[prefix.len()..].contains('/'))
+            .filter(|(name, _)| req.page_token.is_empty() || **name > req.page_token)
+            .map(|(_, doc)| apply_mask(doc.clone(), &req.mask))
+            .collect();
+
+        let page_size = req.page_size as usize;
+        let mut next_page_token = String::new();
+
+        if page_size > 0 && documents.len() > page_size {
+            documents.truncate(page_size);
+            next_page_token = documents[page_size - 1].name.clone();
+        }
+
+        Ok(ListDocumentsResponse {
+            documents,
+            next_page_token,
+        })
+    }
+
+    async fn update_document(&mut self, req: UpdateDocumentRequest) -> Result<Document, Status> {
+        let update = match req.document {
+            Some(doc) => doc,
+            None => return Err(Status::invalid_argument("Missing document")),
+        };
+
+        let now = prost_types::Timestamp::from(SystemTime::now());
+
+        let doc = apply_update(
+            &mut self.documents,
+            update,
+            req.update_mask,
+            &req.current_document,
+            now,
+        )?;
+
+        Ok(apply_mask(doc, &req.mask))
+    }
+
+    async fn commit(&mut self, req: CommitRequest) -> Result<CommitResponse, Status> {
+        let now = prost_types::Timestamp::from(SystemTime::now());
+
+        //Writes go to a copy, swapped in only if all of them succeed
+        let mut documents = self.documents.clone();
+        let mut write_results = Vec::with_capacity(req.writes.len());
+
+        for Write {
+            update_mask,
+            update_transforms,
+            current_document,
+            operation,
+        } in req.writes
+        {
+            if !update_transforms.is_empty() {
+                return Err(Status::unimplemented("Field transforms"));
+            }
+
+            match operation {
+                Some(Operation::Update(update)) => {
+                    apply_update(
+                        &mut documents,
+                        update,
+                        update_mask,
+                        &current_document,
+                        now.clone(),
+                    )?;
+
+                    write_results.push(WriteResult {
+                        update_time: Some(now.clone()),
+                        transform_results: Vec::new(),
+                    });
+                }
+                Some(Operation::Delete(name)) => {
+                    check_precondition(documents.get(&name), &current_document)?;
+
+                    documents.remove(&name);
+
+                    write_results.push(WriteResult::default());
+                }
+                _ => return Err(Status::unimplemented("Write operation")),
+            }
+        }
+
+        self.documents = documents;
+
+        Ok(CommitResponse {
+            write_results,
+            commit_time: Some(now),
+        })
+    }
+}
+
+fn apply_update(
+    documents: &mut BTreeMap<String, Document>,
+    update: Document,
+    update_mask: Option<DocumentMask>,
+    precondition: &Option<Precondition>,
+    now: prost_types::Timestamp,
+) -> Result<Document, Status> {
+    let current = documents.get(&update.name);
+
+    check_precondition(current, precondition)?;
+
+    let mut doc = match current {
+        Some(doc) => doc.clone(),
+        None => Document {
+            name: update.name.clone(),
+            create_time: Some(now.clone()),
+            ..Document::default()
+        },
+    };
+
+    match update_mask {
+        Some(update_mask) => {
+            for field in update_mask.field_paths {
+                match update.fields.get(&field) {
+                    Some(value) => doc.fields.insert(field, value.clone()),
+                    None => doc.fields.remove(&field),
+                };
+            }
+        }
+        None => doc.fields = update.fields,
+    }
+
+    doc.update_time = Some(now);
+
+    documents.insert(doc.name.clone(), doc.clone());
+
+    Ok(doc)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use firestore_grpc_cloudrun::{value::ValueType, Value};
+    use std::collections::HashMap;
+    use tonic::Code;
+
+    fn integer(value: i64) -> Value {
+        Value {
+            value_type: Some(ValueType::IntegerValue(value)),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn create_then_get() {
+        let mut store = MemoryStore::new();
+
+        let mut fields = HashMap::new();
+        fields.insert("Credits".to_owned(), integer(10));
+
+        let req = CreateDocumentRequest {
+            parent: "projects/test/databases/(default)/documents".to_owned(),
+            collection_id: "users".to_owned(),
+            document_id: "1234567".to_owned(),
+            document: Some(Document {
+                fields,
+                ..Document::default()
+            }),
+            mask: None,
+        };
+
+        store.create_document(req.clone()).await.unwrap();
+
+        let error = store.create_document(req).await.unwrap_err();
+        assert_eq!(error.code(), Code::AlreadyExists);
+
+        let req = GetDocumentRequest {
+            name: "projects/test/databases/(default)/documents/users/1234567".to_owned(),
+            mask: None,
+            consistency_selector: None,
+        };
+
+        let doc = store.get_document(req).await.unwrap();
+
+        assert_eq!(doc.fields.get("Credits"), Some(&integer(10)));
+    }
+
+    #[actix_rt::test]
+    async fn update_with_mask() {
+        let mut store = MemoryStore::new();
+
+        let mut fields = HashMap::new();
+        fields.insert("Credits".to_owned(), integer(10));
+        fields.insert("Other".to_owned(), integer(1));
+
+        let req = CreateDocumentRequest {
+            parent: "projects/test/databases/(default)/documents".to_owned(),
+            collection_id: "users".to_owned(),
+            document_id: "1234567".to_owned(),
+            document: Some(Document {
+                fields,
+                ..Document::default()
+            }),
+            mask: None,
+        };
+
+        let created = store.create_document(req).await.unwrap();
+
+        let mut fields = HashMap::new();
+        fields.insert("Credits".to_owned(), integer(20));
+
+        let req = UpdateDocumentRequest {
+            document: Some(Document {
+                name: created.name.clone(),
+                fields,
+                ..Document::default()
+            }),
+            update_mask: Some(DocumentMask {
+                field_paths: vec!["Credits".to_owned()],
+            }),
+            mask: None,
+            current_document: Some(Precondition {
+                condition_type: Some(ConditionType::UpdateTime(created.update_time.unwrap())),
+            }),
+        };
+
+        let doc = store.update_document(req.clone()).await.unwrap();
+
+        assert_eq!(doc.fields.get("Credits"), Some(&integer(20)));
+        assert_eq!(doc.fields.get("Other"), Some(&integer(1)));
+
+        //Stale update time
+        let error = store.update_document(req).await.unwrap_err();
+        assert_eq!(error.code(), Code::FailedPrecondition);
+    }
+
+    #[actix_rt::test]
+    async fn commit_is_atomic() {
+        let mut store = MemoryStore::new();
+
+        let write = |name: &str, exists: bool| Write {
+            update_mask: None,
+            update_transforms: Vec::new(),
+            current_document: Some(Precondition {
+                condition_type: Some(ConditionType::Exists(exists)),
+            }),
+            operation: Some(Operation::Update(Document {
+                name: name.to_owned(),
+                ..Document::default()
+            })),
+        };
+
+        let req = CommitRequest {
+            database: "projects/test/databases/(default)".to_owned(),
+            writes: vec![write("root/outbox/1", false), write("root/users/1", true)],
+            transaction: Vec::new(),
+        };
+
+        //users/1 does not exist, nothing is written
+        let error = store.commit(req).await.unwrap_err();
+        assert_eq!(error.code(), Code::FailedPrecondition);
+
+        let req = ListDocumentsRequest {
+            parent: "root".to_owned(),
+            collection_id: "outbox".to_owned(),
+            ..ListDocumentsRequest::default()
+        };
+
+        let res = store.list_documents(req.clone()).await.unwrap();
+        assert!(res.documents.is_empty());
+
+        let commit = CommitRequest {
+            database: "projects/test/databases/(default)".to_owned(),
+            writes: vec![
+                write("root/outbox/1", false),
+                write("root/outbox/1/sub/1", false),
+            ],
+            transaction: Vec::new(),
+        };
+
+        store.commit(commit).await.unwrap();
+
+        //Only direct children are listed
+        let res = store.list_documents(req).await.unwrap();
+        assert_eq!(res.documents.len(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn emulator_without_metadata_server() {
+        let mut config = Config {
+            firestore_emulator_host: Some("127.0.0.1:1".to_owned()),
+            ..Config::default()
+        };
+
+        assert_eq!(get_project_id(&config).await.unwrap(), "local");
+
+        //Nothing listens there, the error is returned rather than trying GCP
+        assert!(get_client(&config).await.is_err());
+
+        config.project_id = Some("demo-test".to_owned());
+
+        assert_eq!(get_project_id(&config).await.unwrap(), "demo-test");
+    }
+}
@@ -0,0 +1,623 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use actix_http::Payload;
+use actix_web::client::Client;
+use actix_web::{dev::ServiceRequest, http::header, Error, FromRequest, HttpMessage, HttpRequest};
+
+use async_trait::async_trait;
+
+use firestore_grpc_cloudrun::{
+    CommitRequest, CommitResponse, CreateDocumentRequest, Document, GetDocumentRequest,
+    ListDocumentsRequest, ListDocumentsResponse, UpdateDocumentRequest,
+};
+
+use futures::future::{ok, Ready};
+
+use lazy_static::lazy_static;
+
+use serde::Serialize;
+
+use tonic::Status;
+
+use crate::config::Config;
+use crate::store::Store;
+
+pub const TRACEPARENT_HEADER: &str = "traceparent";
+
+//Same default as the OpenTelemetry batch processor, spans past it are dropped.
+const MAX_QUEUED_SPANS: usize = 2048;
+
+static RECORDING: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref FINISHED: Mutex<Vec<SpanData>> = Mutex::new(Vec::new());
+}
+
+//Spans are only kept once an exporter runs.
+pub fn enable() {
+    RECORDING.store(true, Ordering::SeqCst);
+}
+
+//W3C trace context, https://www.w3.org/TR/trace-context/
+#[derive(PartialEq, Debug, Clone)]
+pub struct SpanContext {
+    pub trace_id: String,
+    pub span_id: String,
+    pub sampled: bool,
+}
+
+fn is_hex(field: &str, len: usize) -> bool {
+    field.len() == len
+        && field
+            .chars()
+            .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+}
+
+//All zeros is not a valid ID
+fn is_id(id: &str, len: usize) -> bool {
+    is_hex(id, len) && id.chars().any(|c| c != '0')
+}
+
+impl SpanContext {
+    fn new_trace() -> Self {
+        let trace_id: [u8; 16] = rand::random();
+
+        SpanContext {
+            trace_id: hex::encode(trace_id),
+            span_id: new_span_id(),
+            sampled: true,
+        }
+    }
+
+    //"00-<trace id>-<parent id>-<flags>", later versions may append fields
+    pub fn parse(traceparent: &str) -> Option<Self> {
+        let parts: Vec<&str> = traceparent.trim().split('-').collect();
+
+        let (version, trace_id, span_id, flags) = match parts.as_slice() {
+            [version, trace_id, span_id, flags] => (*version, *trace_id, *span_id, *flags),
+            [version, trace_id, span_id, flags, ..] if *version != "00" => {
+                (*version, *trace_id, *span_id, *flags)
+            }
+            _ => return None,
+        };
+
+        if !is_hex(version, 2) || version == "ff" || !is_hex(flags, 2) {
+            return None;
+        }
+
+        if !is_id(trace_id, 32) || !is_id(span_id, 16) {
+            return None;
+        }
+
+        let flags = u8::from_str_radix(flags, 16).ok()?;
+
+        Some(SpanContext {
+            trace_id: trace_id.to_owned(),
+            span_id: span_id.to_owned(),
+            sampled: flags & 1 == 1,
+        })
+    }
+
+    pub fn traceparent(&self) -> String {
+        format!(
+            "00-{}-{}-{:02x}",
+            self.trace_id, self.span_id, self.sampled as u8
+        )
+    }
+
+    fn from_header(headers: &header::HeaderMap) -> Option<Self> {
+        Self::parse(headers.get(TRACEPARENT_HEADER)?.to_str().ok()?)
+    }
+}
+
+fn new_span_id() -> String {
+    let span_id: [u8; 8] = rand::random();
+
+    hex::encode(span_id)
+}
+
+//The caller's context, or a new trace, shared by every span of the request.
+pub fn request_context(req: &ServiceRequest) -> SpanContext {
+    if let Some(context) = req.extensions().get::<SpanContext>() {
+        return context.clone();
+    }
+
+    let context = SpanContext::from_header(req.headers()).unwrap_or_else(SpanContext::new_trace);
+
+    req.extensions_mut().insert(context.clone());
+
+    context
+}
+
+impl FromRequest for SpanContext {
+    type Config = ();
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        if let Some(context) = req.extensions().get::<SpanContext>() {
+            return ok(context.clone());
+        }
+
+        ok(SpanContext::from_header(req.headers()).unwrap_or_else(SpanContext::new_trace))
+    }
+}
+
+//OTLP SpanKind
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum SpanKind {
+    Internal = 1,
+    Server = 2,
+    Client = 3,
+}
+
+pub struct Span {
+    context: SpanContext,
+    parent_span_id: String,
+    name: String,
+    kind: SpanKind,
+    start: SystemTime,
+    attributes: Vec<(&'static str, String)>,
+    error: Option<String>,
+}
+
+impl Span {
+    pub fn start(name: &str, kind: SpanKind, parent: &SpanContext) -> Self {
+        Span {
+            context: SpanContext {
+                span_id: new_span_id(),
+                ..parent.clone()
+            },
+            parent_span_id: parent.span_id.clone(),
+            name: name.to_owned(),
+            kind,
+            start: SystemTime::now(),
+            attributes: Vec::new(),
+            error: None,
+        }
+    }
+
+    //Parent of the spans started under this one
+    pub fn context(&self) -> SpanContext {
+        self.context.clone()
+    }
+
+    pub fn set_attribute(&mut self, key: &'static str, value: impl ToString) {
+        self.attributes.push((key, value.to_string()));
+    }
+
+    pub fn set_error(&mut self, message: impl ToString) {
+        self.error = Some(message.to_string());
+    }
+
+    pub fn end(self) {
+        if !self.context.sampled || !RECORDING.load(Ordering::SeqCst) {
+            return;
+        }
+
+        let span = SpanData {
+            trace_id: self.context.trace_id,
+            span_id: self.context.span_id,
+            parent_span_id: self.parent_span_id,
+            name: self.name,
+            kind: self.kind as i32,
+            start_time_unix_nano: unix_nanos(self.start),
+            end_time_unix_nano: unix_nanos(SystemTime::now()),
+            attributes: self
+                .attributes
+                .into_iter()
+                .map(|(key, value)| attribute(key, value))
+                .collect(),
+            status: match self.error {
+                Some(message) => SpanStatus { code: 2, message },
+                None => SpanStatus {
+                    code: 0,
+                    message: String::new(),
+                },
+            },
+        };
+
+        let mut finished = FINISHED.lock().expect("Trying to lock spans Error: ");
+
+        if finished.len() < MAX_QUEUED_SPANS {
+            finished.push(span);
+        }
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+//OTLP/HTTP JSON, ids are hex and 64 bit integers are strings.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SpanData {
+    trace_id: String,
+    span_id: String,
+    parent_span_id: String,
+    name: String,
+    kind: i32,
+    start_time_unix_nano: String,
+    end_time_unix_nano: String,
+    attributes: Vec<KeyValue>,
+    status: SpanStatus,
+}
+
+#[derive(Serialize)]
+struct SpanStatus {
+    code: i32,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    message: String,
+}
+
+#[derive(Serialize)]
+struct KeyValue {
+    key: &'static str,
+    value: AnyValue,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AnyValue {
+    string_value: String,
+}
+
+fn attribute(key: &'static str, value: String) -> KeyValue {
+    KeyValue {
+        key,
+        value: AnyValue {
+            string_value: value,
+        },
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ExportRequest {
+    resource_spans: Vec<ResourceSpans>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ResourceSpans {
+    resource: Resource,
+    scope_spans: Vec<ScopeSpans>,
+}
+
+#[derive(Serialize)]
+struct Resource {
+    attributes: Vec<KeyValue>,
+}
+
+#[derive(Serialize)]
+struct ScopeSpans {
+    scope: Scope,
+    spans: Vec<SpanData>,
+}
+
+#[derive(Serialize)]
+struct Scope {
+    name: &'static str,
+}
+
+pub struct Exporter {
+    pub endpoint: String,
+    pub service_name: String,
+    pub interval: Duration,
+}
+
+//Spans are only recorded and sent once the OTLP endpoint is configured.
+pub fn get_exporter(config: &Config) -> Option<Exporter> {
+    let endpoint = config.otlp_endpoint.as_ref()?;
+
+    Some(Exporter {
+        endpoint: format!("{}/v1/traces", endpoint.trim_end_matches('/')),
+        service_name: config.service_name.clone(),
+        interval: config.otlp_schedule_delay,
+    })
+}
+
+impl Exporter {
+    //Sends every finished span once, a failed batch is dropped rather than piling up.
+    pub async fn export(&self, client: &Client) -> bool {
+        let spans: Vec<SpanData> = {
+            let mut finished = FINISHED.lock().expect("Trying to lock spans Error: ");
+
+            finished.drain(..).collect()
+        };
+
+        if spans.is_empty() {
+            return true;
+        }
+
+        let req = ExportRequest {
+            resource_spans: vec![ResourceSpans {
+                resource: Resource {
+                    attributes: vec![attribute("service.name", self.service_name.clone())],
+                },
+                scope_spans: vec![ScopeSpans {
+                    scope: Scope {
+                        name: env!("CARGO_PKG_NAME"),
+                    },
+                    spans,
+                }],
+            }],
+        };
+
+        let res = client
+            .post(&self.endpoint)
+            .header(header::CONTENT_TYPE, "application/json")
+            .send_json(&req)
+            .await;
+
+        match res {
+            Ok(res) => res.status().is_success(),
+            Err(_) => false,
+        }
+    }
+
+    pub fn spawn(self) {
+        enable();
+
+        actix_rt::spawn(async move {
+            let client = Client::default();
+
+            loop {
+                actix_rt::time::delay_for(self.interval).await;
+
+                self.export(&client).await;
+            }
+        });
+    }
+}
+
+//One client span per call of the wrapped store, under the span set by the lock holder.
+pub struct TracedStore<S> {
+    store: S,
+    parent: Option<SpanContext>,
+}
+
+impl<S> TracedStore<S> {
+    pub fn new(store: S) -> Self {
+        TracedStore {
+            store,
+            parent: None,
+        }
+    }
+
+    fn start(&self, method: &str) -> Option<Span> {
+        let mut span = Span::start(
+            &format!("google.firestore.v1.Firestore/{}", method),
+            SpanKind::Client,
+            self.parent.as_ref()?,
+        );
+
+        span.set_attribute("rpc.system", "grpc");
+        span.set_attribute("rpc.service", "google.firestore.v1.Firestore");
+        span.set_attribute("rpc.method", method);
+
+        Some(span)
+    }
+}
+
+fn finish<T>(span: Option<Span>, result: &Result<T, Status>) {
+    if let Some(mut span) = span {
+        match result {
+            Ok(_) => span.set_attribute("rpc.grpc.status_code", 0),
+            Err(status) => {
+                span.set_attribute("rpc.grpc.status_code", status.code() as i32);
+                span.set_error(status.message());
+            }
+        }
+
+        span.end();
+    }
+}
+
+#[async_trait(?Send)]
+impl<S: Store> Store for TracedStore<S> {
+    async fn get_document(&mut self, req: GetDocumentRequest) -> Result<Document, Status> {
+        let span = self.start("GetDocument");
+        let result = self.store.get_document(req).await;
+
+        finish(span, &result);
+        result
+    }
+
+    async fn list_documents(
+        &mut self,
+        req: ListDocumentsRequest,
+    ) -> Result<ListDocumentsResponse, Status> {
+        let span = self.start("ListDocuments");
+        let result = self.store.list_documents(req).await;
+
+        finish(span, &result);
+        result
+    }
+
+    async fn create_document(&mut self, req: CreateDocumentRequest) -> Result<Document, Status> {
+        let span = self.start("CreateDocument");
+        let result = self.store.create_document(req).await;
+
+        finish(span, &result);
+        result
+    }
+
+    async fn update_document(&mut self, req: UpdateDocumentRequest) -> Result<Document, Status> {
+        let span = self.start("UpdateDocument");
+        let result = self.store.update_document(req).await;
+
+        finish(span, &result);
+        result
+    }
+
+    async fn commit(&mut self, req: CommitRequest) -> Result<CommitResponse, Status> {
+        let span = self.start("Commit");
+        let result = self.store.commit(req).await;
+
+        finish(span, &result);
+        result
+    }
+
+    fn set_trace_parent(&mut self, parent: Option<SpanContext>) {
+        self.store.set_trace_parent(parent.clone());
+        self.parent = parent;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers;
+    use crate::ip_white_list_middleware::IpWhiteList;
+    use crate::signature_middleware::{sign, VerifySignature};
+    use crate::store::MemoryStore;
+    use crate::test_support::test_data_with;
+    use actix_web::test::TestRequest;
+    use actix_web::{test, web, App, HttpResponse};
+    use serde_json::Value;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    #[test]
+    fn traceparent_parsed() {
+        let context =
+            SpanContext::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").unwrap();
+
+        assert_eq!(context.trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(context.span_id, "00f067aa0ba902b7");
+        assert!(context.sampled);
+        assert_eq!(
+            context.traceparent(),
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+        );
+
+        assert!(
+            !SpanContext::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00")
+                .unwrap()
+                .sampled
+        );
+
+        //Future versions may carry more fields
+        assert!(SpanContext::parse(
+            "01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra"
+        )
+        .is_some());
+
+        for invalid in &[
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+        ] {
+            assert_eq!(SpanContext::parse(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn spans_exported_to_collector() {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let spans = received.clone();
+
+        //Stub OTLP/HTTP collector
+        let srv = test::start(move || {
+            let spans = spans.clone();
+
+            App::new().route(
+                "/v1/traces",
+                web::post().to(move |body: web::Bytes| {
+                    let body: Value = serde_json::from_slice(&body).unwrap();
+
+                    for span in body["resourceSpans"][0]["scopeSpans"][0]["spans"]
+                        .as_array()
+                        .unwrap()
+                    {
+                        spans.lock().unwrap().push(span.clone());
+                    }
+
+                    HttpResponse::Ok().finish()
+                }),
+            )
+        });
+
+        enable();
+
+        let data = test_data_with(Box::new(TracedStore::new(MemoryStore::new())));
+
+        let app = App::new()
+            .app_data(data)
+            .wrap(VerifySignature::new(
+                "Ultra1Top2Secret3Key".to_owned(),
+                64 * 1024,
+            ))
+            .wrap(IpWhiteList::new(vec!["185.30.20.0/24".parse().unwrap()]))
+            .service(handlers::notifications);
+        let mut app = test::init_service(app).await;
+
+        let payload = r#"{"notification_type":"user_validation","user":{"id":"1"}}"#;
+
+        let req = TestRequest::post()
+            .uri("/webhook")
+            .peer_addr("185.30.20.1:443".parse::<SocketAddr>().unwrap())
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(
+                header::AUTHORIZATION,
+                format!(
+                    "Bearer {}",
+                    sign(payload.as_bytes(), "Ultra1Top2Secret3Key")
+                ),
+            )
+            .header(
+                TRACEPARENT_HEADER,
+                "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            )
+            .set_payload(payload)
+            .to_request();
+
+        test::call_service(&mut app, req).await;
+
+        let exporter = Exporter {
+            endpoint: srv.url("/v1/traces"),
+            service_name: "test".to_owned(),
+            interval: Duration::from_millis(10),
+        };
+
+        assert!(exporter.export(&Client::default()).await);
+
+        //Other tests may have recorded spans of their own meanwhile
+        let spans: Vec<Value> = received
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|span| span["traceId"] == "0af7651916cd43dd8448eb211c80319c")
+            .cloned()
+            .collect();
+
+        let span = |name: &str| {
+            spans
+                .iter()
+                .find(|span| span["name"] == name)
+                .unwrap_or_else(|| panic!("{} not exported", name))
+        };
+
+        for name in &["IpWhiteList", "VerifySignature", "notifications"] {
+            assert_eq!(span(name)["parentSpanId"], "b7ad6b7169203331");
+        }
+
+        let get = span("google.firestore.v1.Firestore/GetDocument");
+
+        assert_eq!(get["parentSpanId"], span("notifications")["spanId"]);
+        assert_eq!(get["kind"], 3);
+        //The user does not exist
+        assert_eq!(get["status"]["code"], 2);
+        assert!(spans.iter().any(|span| span["name"] == "lock"));
+    }
+}
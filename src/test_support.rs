@@ -0,0 +1,209 @@
+//Test doubles shared by the module tests, never built into the binaries.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use actix_web::web;
+
+use async_trait::async_trait;
+
+use firestore_grpc_cloudrun::{
+    value::ValueType, CommitRequest, CommitResponse, CreateDocumentRequest, Document, DocumentMask,
+    GetDocumentRequest, ListDocumentsRequest, ListDocumentsResponse, MapValue,
+    UpdateDocumentRequest, Value,
+};
+
+use futures::lock::Mutex;
+
+use tonic::Status;
+
+use crate::push::Hub;
+use crate::store::{MemoryStore, Store};
+use crate::MyData;
+
+//MyData of the module tests over any store, fields are changed through the lock.
+//The gems_pack SKU credits gems, every other SKU Credits.
+pub fn test_data_with(client: Box<dyn Store + Send>) -> web::Data<Mutex<MyData>> {
+    let mut sku_currencies = HashMap::new();
+    sku_currencies.insert("gems_pack".to_owned(), "gems".to_owned());
+
+    web::Data::new(Mutex::new(MyData {
+        project_id: "test".to_owned(),
+        client,
+        reject_unknown: false,
+        hub: Hub::default(),
+        sku_currencies,
+    }))
+}
+
+pub fn test_data() -> web::Data<Mutex<MyData>> {
+    test_data_with(Box::new(MemoryStore::new()))
+}
+
+fn integer_value(value: i64) -> Value {
+    Value {
+        value_type: Some(ValueType::IntegerValue(value)),
+    }
+}
+
+//users/{user_id} with its Credits and the balances of other currencies.
+pub async fn create_user(
+    data: &web::Data<Mutex<MyData>>,
+    user_id: &str,
+    credits: i64,
+    balances: &[(&str, i64)],
+) {
+    let mut fields = HashMap::new();
+    fields.insert("Credits".to_owned(), integer_value(credits));
+
+    if !balances.is_empty() {
+        let balances = balances
+            .iter()
+            .map(|(currency, amount)| ((*currency).to_owned(), integer_value(*amount)))
+            .collect();
+
+        fields.insert(
+            "Balances".to_owned(),
+            Value {
+                value_type: Some(ValueType::MapValue(MapValue { fields: balances })),
+            },
+        );
+    }
+
+    let req = CreateDocumentRequest {
+        parent: "projects/test/databases/(default)/documents".to_owned(),
+        collection_id: "users".to_owned(),
+        document_id: user_id.to_owned(),
+        document: Some(Document {
+            name: String::new(),
+            fields,
+            create_time: None,
+            update_time: None,
+        }),
+        mask: None,
+    };
+
+    data.lock()
+        .await
+        .client
+        .create_document(req)
+        .await
+        .expect("Trying to create test user Error: ");
+}
+
+//Memory store that can simulate an outage for the next calls.
+pub struct FlakyStore {
+    store: MemoryStore,
+    failures: Arc<AtomicUsize>,
+}
+
+impl FlakyStore {
+    pub fn new(store: MemoryStore, failures: Arc<AtomicUsize>) -> Self {
+        Self { store, failures }
+    }
+
+    fn check(&self) -> Result<(), Status> {
+        let failures = self.failures.load(Ordering::SeqCst);
+
+        if failures > 0 {
+            self.failures.store(failures - 1, Ordering::SeqCst);
+            return Err(Status::unavailable("Simulated outage"));
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl Store for FlakyStore {
+    async fn get_document(&mut self, req: GetDocumentRequest) -> Result<Document, Status> {
+        self.check()?;
+        self.store.get_document(req).await
+    }
+
+    async fn list_documents(
+        &mut self,
+        req: ListDocumentsRequest,
+    ) -> Result<ListDocumentsResponse, Status> {
+        self.check()?;
+        self.store.list_documents(req).await
+    }
+
+    async fn create_document(&mut self, req: CreateDocumentRequest) -> Result<Document, Status> {
+        self.check()?;
+        self.store.create_document(req).await
+    }
+
+    async fn update_document(&mut self, req: UpdateDocumentRequest) -> Result<Document, Status> {
+        self.check()?;
+        self.store.update_document(req).await
+    }
+
+    async fn commit(&mut self, req: CommitRequest) -> Result<CommitResponse, Status> {
+        self.check()?;
+        self.store.commit(req).await
+    }
+}
+
+//Sets the credits of user 1234567 to 200 before each of the first commits,
+//like another instance changing the balance between a read and a commit.
+pub struct RacingStore {
+    store: MemoryStore,
+    races: usize,
+}
+
+impl RacingStore {
+    pub fn new(store: MemoryStore, races: usize) -> Self {
+        Self { store, races }
+    }
+}
+
+#[async_trait(?Send)]
+impl Store for RacingStore {
+    async fn get_document(&mut self, req: GetDocumentRequest) -> Result<Document, Status> {
+        self.store.get_document(req).await
+    }
+
+    async fn list_documents(
+        &mut self,
+        req: ListDocumentsRequest,
+    ) -> Result<ListDocumentsResponse, Status> {
+        self.store.list_documents(req).await
+    }
+
+    async fn create_document(&mut self, req: CreateDocumentRequest) -> Result<Document, Status> {
+        self.store.create_document(req).await
+    }
+
+    async fn update_document(&mut self, req: UpdateDocumentRequest) -> Result<Document, Status> {
+        self.store.update_document(req).await
+    }
+
+    async fn commit(&mut self, req: CommitRequest) -> Result<CommitResponse, Status> {
+        if self.races > 0 {
+            self.races -= 1;
+
+            let mut fields = HashMap::new();
+            fields.insert("Credits".to_owned(), integer_value(200));
+
+            let update = UpdateDocumentRequest {
+                document: Some(Document {
+                    name: "projects/test/databases/(default)/documents/users/1234567".to_owned(),
+                    fields,
+                    create_time: None,
+                    update_time: None,
+                }),
+                update_mask: Some(DocumentMask {
+                    field_paths: vec!["Credits".to_owned()],
+                }),
+                mask: None,
+                current_document: None,
+            };
+
+            self.store.update_document(update).await?;
+        }
+
+        self.store.commit(req).await
+    }
+}